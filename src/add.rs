@@ -0,0 +1,212 @@
+use super::hash_object;
+use super::ignore_util::IgnoreRules;
+use super::index_util;
+use super::index_util::IndexFile;
+use super::object_util;
+use super::object_util::Object;
+use std::fs;
+use std::path::Path;
+
+// https://git-scm.com/docs/git-add
+
+/// Adds file contents to the index
+///
+/// Directories are added recursively, skipping ignored files.
+/// Tracked files that were deleted from the working tree are removed from the index.
+/// Naming an ignored file directly is an error unless `force` is set.
+pub fn add(paths: &[String], force: bool) -> Result<(), String> {
+    let rules = IgnoreRules::load();
+    let (index, mut items) = index_util::lock_index()?;
+
+    let mut files = Vec::new();
+    let mut removed = Vec::new();
+    let mut ignored = Vec::new();
+
+    for path in paths.iter() {
+        let path = normalize_path(path);
+        let prefix = if path.is_empty() {
+            String::new()
+        } else {
+            format!("{}/", path)
+        };
+        let tracked_under =
+            |item: &IndexFile| item.filename == path || item.filename.starts_with(&prefix);
+
+        if !path.is_empty() && !Path::new(&path).exists() {
+            if !items.iter().any(&tracked_under) {
+                return Err(format!(
+                    "fatal: pathspec '{}' did not match any files",
+                    path
+                ));
+            }
+        } else if path.is_empty() || is_real_dir(&path) {
+            if !force && !path.is_empty() && rules.is_ignored(&path, true) {
+                ignored.push(path.clone());
+                continue;
+            }
+            collect_files(&path, &rules, force, &mut files);
+        } else if !force
+            && rules.is_ignored(&path, false)
+            && !items.iter().any(|i| i.filename == path)
+        {
+            ignored.push(path.clone());
+            continue;
+        } else {
+            files.push(path.clone());
+        }
+
+        // Tracked files that no longer exist, or became directories, are staged as deleted
+        for item in items.iter().filter(|i| tracked_under(i)) {
+            if fs::symlink_metadata(&item.filename)
+                .map(|m| m.is_dir())
+                .unwrap_or(true)
+            {
+                removed.push(item.filename.clone());
+            }
+        }
+    }
+
+    if !ignored.is_empty() {
+        return Err(format!(
+            "The following paths are ignored by one of your .gitignore files:\n{}\nhint: Use -f if you really want to add them.",
+            ignored.join("\n")
+        ));
+    }
+
+    items.retain(|i| !removed.contains(&i.filename));
+    for file in files.iter() {
+        let (object_hash, mode) = write_blob(file);
+        let item = IndexFile::new(file, mode, &object_hash, 0);
+
+        // Adding a file resolves any conflict, so every stage is replaced
+        items.retain(|i| i.filename != *file);
+        items.push(index_util::refresh_stat(&item));
+    }
+
+    items.sort_by(|a, b| (&a.filename, a.stage).cmp(&(&b.filename, b.stage)));
+    index_util::write_index(index, items)
+}
+
+/// Parse arguments for `add`
+///
+/// Returns the paths to add and whether ignored files are allowed
+pub fn parse_args(args: &[String]) -> Result<(Vec<String>, bool), String> {
+    let mut force = false;
+    let mut paths = Vec::new();
+
+    for arg in args.iter() {
+        match arg.as_str() {
+            "-f" | "--force" => force = true,
+            _ if arg.starts_with('-') => {
+                return Err(format!("{} is not recognized as a valid option", arg))
+            }
+            _ => paths.push(arg.to_owned()),
+        }
+    }
+
+    if paths.is_empty() {
+        return Err("Nothing specified, nothing added.".to_string());
+    }
+    Ok((paths, force))
+}
+
+/// Recursively collects the files in a directory that aren't ignored
+///
+/// The `gitrs` directory and any `.git` directory are always skipped
+fn collect_files(dir: &str, rules: &IgnoreRules, force: bool, files: &mut Vec<String>) {
+    let read_path = if dir.is_empty() { "." } else { dir };
+    let entries = match fs::read_dir(read_path) {
+        Ok(entries) => entries,
+        Err(_) => return,
+    };
+
+    for entry in entries.flatten() {
+        let name = entry.file_name().to_string_lossy().to_string();
+        let path = if dir.is_empty() {
+            name.clone()
+        } else {
+            format!("{}/{}", dir, name)
+        };
+        // Symlinks to directories are not followed
+        let is_dir = entry.file_type().map(|t| t.is_dir()).unwrap_or(false);
+
+        if name == ".git" || (dir.is_empty() && name == "gitrs") {
+            continue;
+        }
+        if !force && rules.is_ignored(&path, is_dir) {
+            continue;
+        }
+
+        if is_dir {
+            collect_files(&path, rules, force, files);
+        } else {
+            files.push(path);
+        }
+    }
+}
+
+/// Removes a leading `./` and trailing `/`, with `.` becoming the top level
+fn normalize_path(path: &str) -> String {
+    let path = path.trim_start_matches("./").trim_end_matches('/');
+    if path == "." {
+        String::new()
+    } else {
+        path.to_string()
+    }
+}
+
+/// Writes the blob for a file and returns its hash and mode
+///
+/// A symlink is stored as a blob holding the path it points to, like git does
+fn write_blob(path: &str) -> (String, u32) {
+    #[cfg(unix)]
+    {
+        use std::os::unix::ffi::OsStrExt;
+        if let Ok(target) = fs::read_link(path) {
+            let hash = object_util::write_object(Object::Blob, target.as_os_str().as_bytes());
+            return (hash, 0o120000);
+        }
+    }
+    (hash_object::write_hash_object(path), file_mode(path))
+}
+
+/// Returns true for a directory, but not a symlink to one
+fn is_real_dir(path: &str) -> bool {
+    fs::symlink_metadata(path)
+        .map(|m| m.is_dir())
+        .unwrap_or(false)
+}
+
+/// Returns `0o100755` for executable files and `0o100644` for everything else
+fn file_mode(path: &str) -> u32 {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        if let Ok(metadata) = fs::metadata(path) {
+            if metadata.permissions().mode() & 0o111 != 0 {
+                return 0o100755;
+            }
+        }
+    }
+    let _ = path;
+    0o100644
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_normalize_path() {
+        assert_eq!("", normalize_path("."));
+        assert_eq!("", normalize_path("./"));
+        assert_eq!("src", normalize_path("./src/"));
+        assert_eq!("src/main.rs", normalize_path("src/main.rs"));
+    }
+
+    #[test]
+    fn test_parse_args_force() {
+        let args = vec!["-f".to_string(), "build".to_string()];
+        assert_eq!(Ok((vec!["build".to_string()], true)), parse_args(&args));
+    }
+}
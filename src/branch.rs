@@ -0,0 +1,340 @@
+use super::commit_util;
+use super::object_util::Object;
+use super::ref_util;
+use super::rev_parse;
+use std::env;
+
+// https://git-scm.com/docs/git-branch
+
+/// What `branch` should do
+#[derive(Debug, PartialEq)]
+pub enum BranchAction {
+    /// Lists the branches, with the tip of each one if `verbose`
+    List { verbose: bool },
+    /// Creates a branch at `start`
+    Create {
+        name: String,
+        start: String,
+        force: bool,
+    },
+    /// Renames `old`, or the current branch if not given, to `new`
+    Rename {
+        old: Option<String>,
+        new: String,
+        force: bool,
+    },
+    /// Deletes the branches, `force` skips checking they are merged
+    Delete { names: Vec<String>, force: bool },
+}
+
+/// Lists, creates, renames or deletes branches
+///
+/// Returns the output to show
+pub fn branch(action: &BranchAction) -> Result<String, String> {
+    match action {
+        BranchAction::List { verbose } => list_branches(*verbose),
+        BranchAction::Create { name, start, force } => {
+            create_branch(name, start, *force).map(|_| String::new())
+        }
+        BranchAction::Rename { old, new, force } => {
+            rename_branch(old.as_deref(), new, *force).map(|_| String::new())
+        }
+        BranchAction::Delete { names, force } => delete_branches(names, *force),
+    }
+}
+
+/// Parse arguments for `branch`
+pub fn parse_args(args: &[String]) -> Result<BranchAction, String> {
+    let mut verbose = false;
+    let mut delete = false;
+    let mut rename = false;
+    let mut force = false;
+    let mut names = Vec::new();
+
+    for arg in args.iter() {
+        match arg.as_str() {
+            "-v" | "--verbose" => verbose = true,
+            "-l" | "--list" => (),
+            "-d" | "--delete" => delete = true,
+            "-D" => {
+                delete = true;
+                force = true;
+            }
+            "-m" | "--move" => rename = true,
+            "-M" => {
+                rename = true;
+                force = true;
+            }
+            "-f" | "--force" => force = true,
+            _ if arg.starts_with('-') => {
+                return Err(format!("{} is not recognized as a valid option", arg))
+            }
+            _ => names.push(arg.to_owned()),
+        }
+    }
+
+    if delete && rename {
+        return Err("fatal: -d and -m cannot be used together".to_string());
+    }
+    if delete {
+        if names.is_empty() {
+            return Err("fatal: branch name required".to_string());
+        }
+        return Ok(BranchAction::Delete { names, force });
+    }
+    if rename {
+        return match names.len() {
+            1 => Ok(BranchAction::Rename {
+                old: None,
+                new: names.remove(0),
+                force,
+            }),
+            2 => Ok(BranchAction::Rename {
+                old: Some(names.remove(0)),
+                new: names.remove(0),
+                force,
+            }),
+            0 => Err("fatal: branch name required".to_string()),
+            _ => Err("fatal: too many arguments for a rename operation".to_string()),
+        };
+    }
+    if names.is_empty() {
+        return Ok(BranchAction::List { verbose });
+    }
+    if names.len() > 2 {
+        return Err("fatal: too many arguments".to_string());
+    }
+
+    let start = names.get(1).cloned().unwrap_or_else(|| "HEAD".to_string());
+    Ok(BranchAction::Create {
+        name: names.remove(0),
+        start,
+        force,
+    })
+}
+
+/// Returns the name of the branch HEAD points at, or `None` if HEAD is detached
+fn current_branch() -> Result<Option<String>, String> {
+    let head = ref_util::resolve_symbolic("HEAD")?;
+    Ok(head.strip_prefix("refs/heads/").map(|b| b.to_string()))
+}
+
+/// Lists the branches, marking the current branch with `*`
+///
+/// A detached HEAD is listed first as `(HEAD detached at <abbrev>)`
+fn list_branches(verbose: bool) -> Result<String, String> {
+    let current = current_branch()?;
+    let mut rows = Vec::new();
+
+    if current.is_none() {
+        if let Some(head) = ref_util::resolve_ref("HEAD")? {
+            rows.push((
+                true,
+                format!("(HEAD detached at {})", &head[..7]),
+                head.clone(),
+            ));
+        }
+    }
+    for (name, hash) in ref_util::list_refs("refs/heads/") {
+        let name = name.trim_start_matches("refs/heads/").to_string();
+        rows.push((current.as_ref() == Some(&name), name, hash));
+    }
+
+    let width = rows
+        .iter()
+        .map(|(_, name, _)| name.len())
+        .max()
+        .unwrap_or(0);
+    let mut output = String::new();
+    for (is_current, name, hash) in rows {
+        let marker = if is_current { '*' } else { ' ' };
+        if verbose {
+            let subject = commit_util::read_commit(&hash)?.subject();
+            output += &format!(
+                "{} {:<width$} {} {}\n",
+                marker,
+                name,
+                &hash[..7],
+                subject,
+                width = width
+            );
+        } else {
+            output += &format!("{} {}\n", marker, name);
+        }
+    }
+    Ok(output)
+}
+
+/// Creates `refs/heads/<name>` pointing at the commit `start` resolves to
+fn create_branch(name: &str, start: &str, force: bool) -> Result<(), String> {
+    let ref_name = format!("refs/heads/{}", name);
+    if !ref_util::is_valid_ref_name(&ref_name) {
+        return Err(format!("fatal: '{}' is not a valid branch name", name));
+    }
+
+    let old = ref_util::resolve_ref(&ref_name)?;
+    if old.is_some() {
+        if !force {
+            return Err(format!("fatal: a branch named '{}' already exists", name));
+        }
+        if current_branch()?.as_deref() == Some(name) {
+            return Err("fatal: cannot force update the current branch".to_string());
+        }
+    }
+
+    let commit = rev_parse::rev_parse(start)?;
+    let commit = rev_parse::peel(&commit, Some(Object::Commit))
+        .map_err(|_| format!("fatal: not a valid branch point: '{}'", start))?;
+
+    let reason = match old {
+        Some(_) => format!("branch: Reset to {}", start),
+        None => format!("branch: Created from {}", start),
+    };
+    let expected = old.unwrap_or_else(|| ref_util::NULL_HASH.to_string());
+    ref_util::update_ref(&ref_name, &commit, Some(&expected), &reason)
+}
+
+/// Renames a branch and its reflog, moving HEAD along if it is the current branch
+fn rename_branch(old: Option<&str>, new: &str, force: bool) -> Result<(), String> {
+    let current = current_branch()?;
+    let old = match old.map(|o| o.to_string()).or_else(|| current.clone()) {
+        Some(old) => old,
+        None => return Err("fatal: cannot rename the current branch while not on any".to_string()),
+    };
+    let (old_ref, new_ref) = (format!("refs/heads/{}", old), format!("refs/heads/{}", new));
+
+    if !ref_util::is_valid_ref_name(&new_ref) {
+        return Err(format!("fatal: '{}' is not a valid branch name", new));
+    }
+    if ref_util::read_ref(&new_ref).is_some() && !force {
+        return Err(format!("fatal: a branch named '{}' already exists", new));
+    }
+
+    let is_current = current.as_deref() == Some(old.as_str());
+    match ref_util::read_ref(&old_ref) {
+        Some(_) => ref_util::rename_ref(
+            &old_ref,
+            &new_ref,
+            force,
+            &format!("Branch: renamed {} to {}", old_ref, new_ref),
+        )?,
+        // An unborn current branch only exists in HEAD
+        None if is_current => (),
+        None => {
+            return Err(format!(
+                "error: refname {} not found\nfatal: Branch rename failed",
+                old_ref
+            ))
+        }
+    }
+
+    if is_current {
+        ref_util::write_symbolic_ref("HEAD", &new_ref)?;
+    }
+    Ok(())
+}
+
+/// Deletes each branch, continuing past branches that can't be deleted
+///
+/// Unless `force` is set, a branch is only deleted if it is merged into HEAD.
+/// Returns an error listing the branches that couldn't be deleted if there were any
+fn delete_branches(names: &[String], force: bool) -> Result<String, String> {
+    let current = current_branch()?;
+    let head = ref_util::resolve_ref("HEAD")?;
+    let mut output = String::new();
+    let mut errors = Vec::new();
+
+    for name in names.iter() {
+        let ref_name = format!("refs/heads/{}", name);
+        let hash = match ref_util::resolve_ref(&ref_name)? {
+            Some(hash) => hash,
+            None => {
+                errors.push(format!("error: branch '{}' not found.", name));
+                continue;
+            }
+        };
+
+        if current.as_ref() == Some(name) {
+            let dir = env::current_dir().map_err(|e| e.to_string())?;
+            errors.push(format!(
+                "error: Cannot delete branch '{}' checked out at '{}'",
+                name,
+                dir.display()
+            ));
+            continue;
+        }
+
+        let merged = match &head {
+            Some(head) => commit_util::is_ancestor(&hash, head)?,
+            None => false,
+        };
+        if !force && !merged {
+            errors.push(format!(
+                "error: The branch '{}' is not fully merged.\nIf you are sure you want to delete it, run 'gitrs branch -D {}'.",
+                name, name
+            ));
+            continue;
+        }
+
+        ref_util::delete_ref(&ref_name, Some(&hash))?;
+        output += &format!("Deleted branch {} (was {}).\n", name, &hash[..7]);
+    }
+
+    if errors.is_empty() {
+        Ok(output)
+    } else {
+        Err(output + &errors.join("\n"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn to_args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|a| a.to_string()).collect()
+    }
+
+    #[test]
+    fn test_parse_args_list() {
+        assert_eq!(
+            Ok(BranchAction::List { verbose: true }),
+            parse_args(&to_args(&["-v"]))
+        );
+    }
+
+    #[test]
+    fn test_parse_args_create() {
+        assert_eq!(
+            Ok(BranchAction::Create {
+                name: "topic".to_string(),
+                start: "HEAD~2".to_string(),
+                force: false,
+            }),
+            parse_args(&to_args(&["topic", "HEAD~2"]))
+        );
+    }
+
+    #[test]
+    fn test_parse_args_rename_current() {
+        assert_eq!(
+            Ok(BranchAction::Rename {
+                old: None,
+                new: "main".to_string(),
+                force: true,
+            }),
+            parse_args(&to_args(&["-M", "main"]))
+        );
+    }
+
+    #[test]
+    fn test_parse_args_force_delete() {
+        assert_eq!(
+            Ok(BranchAction::Delete {
+                names: vec!["topic".to_string()],
+                force: true,
+            }),
+            parse_args(&to_args(&["-D", "topic"]))
+        );
+    }
+}
@@ -0,0 +1,173 @@
+use super::object_util;
+use std::str;
+
+// https://git-scm.com/docs/index-format#_cache_tree
+
+/// The `TREE` index extension, the tree object hash of each directory the index covers
+///
+/// A directory whose entries changed since its tree was written is invalid and has no hash,
+/// so `write-tree` only has to write the trees of invalid directories.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CacheTree {
+    /// The directory name relative to its parent, empty for the root
+    pub name: String,
+    /// How many index entries the directory covers, or -1 if it is invalid
+    pub entry_count: i32,
+    pub hash: Option<String>,
+    pub subtrees: Vec<CacheTree>,
+}
+
+impl CacheTree {
+    /// Returns the node for a subdirectory, valid or not
+    pub fn subtree(&self, name: &str) -> Option<&CacheTree> {
+        self.subtrees.iter().find(|t| t.name == name)
+    }
+
+    /// Marks every directory holding the path as invalid, from this one down
+    pub fn invalidate(&mut self, path: &str) {
+        self.entry_count = -1;
+        self.hash = None;
+        if let Some(i) = path.find('/') {
+            let (dir, rest) = (&path[..i], &path[i + 1..]);
+            if let Some(subtree) = self.subtrees.iter_mut().find(|t| t.name == dir) {
+                subtree.invalidate(rest);
+            }
+        }
+    }
+
+    /// Parses the content of a `TREE` extension
+    ///
+    /// # Format
+    ///
+    /// Each directory, starting with the root and then its subtrees depth first, is stored as
+    /// its NUL-terminated name, the entry count and the number of subtrees in ASCII separated
+    /// by a space and ending with a newline, then the 20 byte tree hash if the entry count isn't -1
+    pub fn parse(data: &[u8]) -> Result<CacheTree, String> {
+        let mut pos = 0;
+        let tree = parse_node(data, &mut pos)?;
+        match pos == data.len() {
+            true => Ok(tree),
+            false => Err("error: cache-tree has trailing data".to_string()),
+        }
+    }
+
+    /// Returns the content of the `TREE` extension for this tree, the reverse of [`CacheTree::parse`]
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut data = Vec::new();
+        self.write_node(&mut data);
+        data
+    }
+
+    fn write_node(&self, data: &mut Vec<u8>) {
+        data.extend_from_slice(self.name.as_bytes());
+        data.push(0);
+        data.extend_from_slice(
+            format!("{} {}\n", self.entry_count, self.subtrees.len()).as_bytes(),
+        );
+        if let Some(hash) = self.hash.as_ref().filter(|_| self.entry_count >= 0) {
+            data.append(&mut object_util::from_hex_string(hash).unwrap_or_default());
+        }
+
+        // Like git, shorter names come first, then names of the same length in byte order
+        let mut subtrees: Vec<&CacheTree> = self.subtrees.iter().collect();
+        subtrees.sort_by(|a, b| (a.name.len(), &a.name).cmp(&(b.name.len(), &b.name)));
+        for subtree in subtrees {
+            subtree.write_node(data);
+        }
+    }
+}
+
+fn parse_node(data: &[u8], pos: &mut usize) -> Result<CacheTree, String> {
+    let invalid = || "error: cache-tree is corrupt".to_string();
+    let rest = data.get(*pos..).ok_or_else(invalid)?;
+
+    let name_end = rest.iter().position(|b| *b == 0).ok_or_else(invalid)?;
+    let line_end = rest.iter().position(|b| *b == b'\n').ok_or_else(invalid)?;
+    if line_end < name_end {
+        return Err(invalid());
+    }
+    let name = str::from_utf8(&rest[..name_end]).map_err(|_| invalid())?;
+    let counts = str::from_utf8(&rest[name_end + 1..line_end]).map_err(|_| invalid())?;
+    let (entry_count, subtree_count) = counts.split_once(' ').ok_or_else(invalid)?;
+    let entry_count: i32 = entry_count.parse().map_err(|_| invalid())?;
+    let subtree_count: usize = subtree_count.parse().map_err(|_| invalid())?;
+    *pos += line_end + 1;
+
+    let hash = if entry_count >= 0 {
+        let hash = data.get(*pos..*pos + 20).ok_or_else(invalid)?;
+        *pos += 20;
+        Some(object_util::to_hex_string(hash))
+    } else {
+        None
+    };
+
+    let mut subtrees = Vec::with_capacity(subtree_count.min(data.len()));
+    for _ in 0..subtree_count {
+        subtrees.push(parse_node(data, pos)?);
+    }
+
+    Ok(CacheTree {
+        name: name.to_string(),
+        entry_count,
+        hash,
+        subtrees,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tree(name: &str, entry_count: i32, subtrees: Vec<CacheTree>) -> CacheTree {
+        CacheTree {
+            name: name.to_string(),
+            entry_count,
+            hash: match entry_count {
+                -1 => None,
+                _ => Some("4b825dc642cb6eb9a060e54bf8d69288fbee4904".to_string()),
+            },
+            subtrees,
+        }
+    }
+
+    #[test]
+    fn test_parse_round_trip() {
+        let root = tree(
+            "",
+            3,
+            vec![
+                tree("docs", -1, vec![]),
+                tree("src", 2, vec![tree("a", 1, vec![])]),
+            ],
+        );
+        let data = root.to_bytes();
+        assert!(data.starts_with(b"\x003 2\n"));
+
+        let parsed = CacheTree::parse(&data).unwrap();
+        // Subtrees are written shortest name first
+        assert_eq!("docs", parsed.subtrees[1].name);
+        assert_eq!(data, parsed.to_bytes());
+        assert!(CacheTree::parse(&data[..data.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn test_invalidate() {
+        let mut root = tree(
+            "",
+            3,
+            vec![
+                tree("src", 2, vec![tree("a", 1, vec![])]),
+                tree("doc", 1, vec![]),
+            ],
+        );
+        root.invalidate("src/b.rs");
+
+        assert_eq!(-1, root.entry_count);
+        assert_eq!(None, root.hash);
+        let src = root.subtree("src").unwrap();
+        assert_eq!(-1, src.entry_count);
+        assert_eq!(Some(1), src.subtree("a").map(|t| t.entry_count));
+        assert_eq!(Some(1), root.subtree("doc").map(|t| t.entry_count));
+        assert!(root.subtree("missing").is_none());
+    }
+}
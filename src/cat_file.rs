@@ -1,7 +1,12 @@
+use super::commit_util;
 use super::ls_tree;
 use super::object_util;
 use super::object_util::Object;
-use std::str;
+use super::pack_util;
+use super::rev_parse;
+use super::tag_util;
+use std::io;
+use std::io::{Read, Write};
 use std::string::String;
 
 #[derive(Debug)]
@@ -13,34 +18,51 @@ pub enum CatFlags {
 
 // https://git-scm.com/book/en/v2/Git-Internals-Git-Objects
 
-/// Returns various properties of the passed object.
+/// Writes various properties of the passed object.
 ///
 /// Current properties are file contents, header type, and content size.
+/// Commits and tags are parsed, so invalid ones are reported instead of printed,
+/// but their content is written as stored, byte for byte.
+/// Blobs are copied a chunk at a time, so large files aren't read into memory.
 ///
 /// # Arguments
 ///
-/// * `flag` - Controls which property will be returned
+/// * `flag` - Controls which property will be written
 /// * `object_hash` - The object in the object database to read
-pub fn cat_file(flag: CatFlags, object_hash: &str) -> String {
-    let git_object = object_util::read_object_file(object_hash);
-    let decoded = object_util::decode_object(git_object);
+/// * `out` - Where the output is written
+pub fn cat_file(flag: CatFlags, object_hash: &str, out: &mut dyn Write) -> Result<(), String> {
+    let mut object = object_util::open_object(object_hash)?;
 
-    let mut split = decoded.split(|num| num == &0u8);
-    let header = split.next().unwrap();
-    let object_type = object_util::get_header_type(header);
-
-    match flag {
+    let output = match flag {
+        CatFlags::Type => format!("{}\n", object.object_type).into_bytes(),
+        CatFlags::Size => format!("{}\n", object.size).into_bytes(),
+        CatFlags::Print if object.object_type == Object::Blob => {
+            return match io::copy(&mut object, out) {
+                Ok(_) => Ok(()),
+                // The reader went away, like `cat-file -p <blob> | head`
+                Err(e) if e.kind() == io::ErrorKind::BrokenPipe => Ok(()),
+                Err(e) => Err(format!("fatal: unable to read {}: {}", object_hash, e)),
+            };
+        }
         CatFlags::Print => {
-            // Assume the file has valid contents
-            if object_type == Object::Tree {
-                ls_tree::format_tree(&decoded)
-            } else {
-                let content = split.next().unwrap();
-                return str::from_utf8(content).unwrap().to_owned();
+            let mut content = Vec::with_capacity(object.size.min(pack_util::MAX_PREALLOC) as usize);
+            object
+                .read_to_end(&mut content)
+                .map_err(|e| format!("fatal: unable to read {}: {}", object_hash, e))?;
+            match object.object_type {
+                Object::Tree => {
+                    ls_tree::format_entries(&ls_tree::parse_tree(&content)?).into_bytes()
+                }
+                Object::Commit => commit_util::parse_commit(&content).map(|_| content)?,
+                Object::Tag => tag_util::parse_tag(&content).map(|_| content)?,
+                Object::Blob => content,
             }
         }
-        CatFlags::Type => return format!("{}\n", object_type),
-        CatFlags::Size => return format!("{}\n", object_util::get_header_size(header)),
+    };
+
+    match out.write_all(&output) {
+        Err(e) if e.kind() != io::ErrorKind::BrokenPipe => Err(e.to_string()),
+        _ => Ok(()),
     }
 }
 
@@ -53,5 +75,5 @@ pub fn parse_args(flag: &str, hash: &str) -> Result<(CatFlags, String), String>
         _ => Err(format!("{} is not recognized as a valid option", flag)),
     };
 
-    Ok((cat_flag?, hash.to_owned()))
+    Ok((cat_flag?, rev_parse::rev_parse(hash)?))
 }
@@ -0,0 +1,70 @@
+use super::ignore_util::IgnoreRules;
+use std::path::Path;
+
+// https://git-scm.com/docs/git-check-ignore
+
+/// Checks each path against the ignore rules
+///
+/// Returns the ignored paths, one per line. With `verbose`, each line is
+/// `<source>:<line>:<pattern>\t<path>` for every path matching a pattern,
+/// including negated patterns that re-include the path.
+pub fn check_ignore(paths: &[String], verbose: bool) -> String {
+    let rules = IgnoreRules::load();
+    let mut output = String::new();
+
+    for path in paths.iter() {
+        let is_dir = path.ends_with('/') || Path::new(path).is_dir();
+
+        if verbose {
+            if let Some(pattern) = rules.matching_pattern(path, is_dir) {
+                output += &format!(
+                    "{}:{}:{}\t{}\n",
+                    pattern.source, pattern.line, pattern.text, path
+                );
+            }
+        } else if rules.is_ignored(path, is_dir) {
+            output += &format!("{}\n", path);
+        }
+    }
+
+    output
+}
+
+/// Parse arguments for `check_ignore`
+///
+/// Returns the paths to check and whether to show the matching patterns
+pub fn parse_args(args: &[String]) -> Result<(Vec<String>, bool), String> {
+    let mut verbose = false;
+    let mut paths = Vec::new();
+
+    for arg in args.iter() {
+        match arg.as_str() {
+            "-v" | "--verbose" => verbose = true,
+            _ if arg.starts_with('-') => {
+                return Err(format!("{} is not recognized as a valid option", arg))
+            }
+            _ => paths.push(arg.to_owned()),
+        }
+    }
+
+    if paths.is_empty() {
+        return Err("fatal: no path specified".to_string());
+    }
+    Ok((paths, verbose))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_args_verbose() {
+        let args = vec!["-v".to_string(), "a.o".to_string()];
+        assert_eq!(Ok((vec!["a.o".to_string()], true)), parse_args(&args));
+    }
+
+    #[test]
+    fn test_parse_args_no_paths() {
+        assert!(parse_args(&["-v".to_string()]).is_err());
+    }
+}
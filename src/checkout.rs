@@ -0,0 +1,362 @@
+use super::commit_util;
+use super::index_util;
+use super::index_util::IndexFile;
+use super::object_util;
+use super::read_tree;
+use super::ref_util;
+use super::reflog_util;
+use super::rev_parse;
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+
+// https://git-scm.com/docs/git-checkout
+
+/// Options for `checkout` and `switch`
+#[derive(Debug, Default, PartialEq)]
+pub struct CheckoutOptions {
+    /// The branch or commit to check out
+    pub target: String,
+    /// Create a new branch with this name at `target` and switch to it
+    pub new_branch: Option<String>,
+    /// Detach HEAD at `target` even if it is a branch
+    pub detach: bool,
+    /// Throw away local changes that would be overwritten
+    pub force: bool,
+}
+
+/// Moves HEAD to a branch or commit, updating the index and working tree to match
+///
+/// Local changes to files that are the same in the current and target commits are kept.
+/// Unless `force` is set, fails without changing anything if a local change or
+/// untracked file would be overwritten.
+///
+/// Returns a message describing the new HEAD
+pub fn checkout(options: &CheckoutOptions) -> Result<String, String> {
+    let target_commit = rev_parse::rev_parse(&options.target)?;
+    let target_commit = rev_parse::peel(&target_commit, Some(object_util::Object::Commit))
+        .map_err(|_| format!("fatal: reference is not a tree: {}", options.target))?;
+
+    let branch = match &options.new_branch {
+        Some(name) => {
+            let new_ref = format!("refs/heads/{}", name);
+            if !ref_util::is_valid_ref_name(&new_ref) {
+                return Err(format!("fatal: '{}' is not a valid branch name", name));
+            }
+            if ref_util::read_ref(&new_ref).is_some() {
+                return Err(format!("fatal: a branch named '{}' already exists", name));
+            }
+            Some(new_ref)
+        }
+        None if options.detach => None,
+        None => ref_util::dwim_ref(&options.target).filter(|r| r.starts_with("refs/heads/")),
+    };
+
+    let head = ref_util::resolve_ref("HEAD")?;
+    let head_tree = match &head {
+        Some(head) => read_tree::read_tree_entries(&commit_util::read_commit(head)?.tree)?,
+        None => BTreeMap::new(),
+    };
+    let target_tree =
+        read_tree::read_tree_entries(&commit_util::read_commit(&target_commit)?.tree)?;
+
+    let (locked_index, items) = index_util::lock_index()?;
+    let index = read_tree::to_map(items);
+
+    let new_index = if options.force {
+        target_tree
+    } else {
+        let merged = read_tree::two_way_merge(index.clone(), head_tree, target_tree)
+            .map_err(|_| {
+                "error: Your local changes to the index would be overwritten by checkout.\nPlease commit your changes before you switch branches.\nAborting".to_string()
+            })?;
+        check_worktree(&index, &merged)?;
+        read_tree::to_map(merged)
+    };
+
+    let items = update_worktree(&index, new_index, options.force)?;
+    index_util::write_index(locked_index, items)?;
+
+    // Recorded in the reflog of HEAD as `checkout: moving from <from> to <to>`
+    let head_ref = ref_util::resolve_symbolic("HEAD")?;
+    let from = match head_ref.strip_prefix("refs/heads/") {
+        Some(name) => name.to_string(),
+        None => head.clone().unwrap_or_default(),
+    };
+    let to = options.new_branch.as_ref().unwrap_or(&options.target);
+    let reason = format!("checkout: moving from {} to {}", from, to);
+
+    match branch {
+        Some(b) => {
+            if options.new_branch.is_some() {
+                ref_util::update_ref(
+                    &b,
+                    &target_commit,
+                    Some(ref_util::NULL_HASH),
+                    &format!("branch: Created from {}", options.target),
+                )?;
+            }
+            ref_util::write_symbolic_ref("HEAD", &b)?;
+            let old = head.unwrap_or_else(|| ref_util::NULL_HASH.to_string());
+            reflog_util::append_reflog("HEAD", &old, &target_commit, &reason)?;
+            let name = b.trim_start_matches("refs/heads/");
+            if options.new_branch.is_some() {
+                Ok(format!("Switched to a new branch '{}'", name))
+            } else {
+                Ok(format!("Switched to branch '{}'", name))
+            }
+        }
+        None => {
+            ref_util::update_ref_no_deref("HEAD", &target_commit, None, &reason)?;
+            let subject = commit_util::read_commit(&target_commit)?.subject();
+            Ok(format!(
+                "HEAD is now at {} {}",
+                &target_commit[..7],
+                subject
+            ))
+        }
+    }
+}
+
+/// Parse arguments for `checkout` and `switch`
+///
+/// `checkout` creates branches with `-b`, `switch` with `-c`.
+/// `switch` only detaches HEAD when `--detach` is given.
+pub fn parse_args(args: &[String], is_switch: bool) -> Result<CheckoutOptions, String> {
+    let create_flag = if is_switch { "-c" } else { "-b" };
+    let mut options = CheckoutOptions::default();
+    let mut target = None;
+
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "-f" | "--force" => options.force = true,
+            "--detach" => options.detach = true,
+            _ if arg == create_flag => match iter.next() {
+                Some(name) => options.new_branch = Some(name.to_owned()),
+                None => {
+                    return Err(format!(
+                        "error: switch `{}' requires a value",
+                        &create_flag[1..]
+                    ))
+                }
+            },
+            _ if arg.starts_with('-') => {
+                return Err(format!("{} is not recognized as a valid option", arg))
+            }
+            _ if target.is_none() => target = Some(arg.to_owned()),
+            _ => return Err(format!("{} is not recognized as a valid option", arg)),
+        }
+    }
+
+    options.target = match target {
+        Some(t) => t,
+        None if options.new_branch.is_some() => "HEAD".to_string(),
+        None => return Err("fatal: missing branch or commit argument".to_string()),
+    };
+
+    let is_branch = ref_util::dwim_ref(&options.target)
+        .map(|r| r.starts_with("refs/heads/"))
+        .unwrap_or(false);
+    if is_switch && options.new_branch.is_none() && !options.detach && !is_branch {
+        return Err(format!(
+            "fatal: a branch is expected, got '{}'\nhint: use --detach to switch to a commit",
+            options.target
+        ));
+    }
+
+    Ok(options)
+}
+
+/// Checks that no modified or untracked working tree file will be overwritten
+fn check_worktree(
+    index: &BTreeMap<String, IndexFile>,
+    new_index: &[IndexFile],
+) -> Result<(), String> {
+    let mut modified = Vec::new();
+    let mut untracked = Vec::new();
+    let changed = |item: &IndexFile| exists(&item.filename) && !index_util::matches_worktree(item);
+
+    for item in new_index.iter() {
+        match index.get(&item.filename) {
+            Some(old) if old.object_hash == item.object_hash => (),
+            Some(old) => {
+                if changed(old) {
+                    modified.push(old.filename.clone());
+                }
+            }
+            None => {
+                if changed(item) {
+                    untracked.push(item.filename.clone());
+                }
+            }
+        }
+    }
+
+    // Files that are being removed
+    for (path, old) in index.iter() {
+        if !new_index.iter().any(|i| i.filename == *path) && changed(old) {
+            modified.push(path.clone());
+        }
+    }
+
+    if !modified.is_empty() {
+        return Err(format!(
+            "error: Your local changes to the following files would be overwritten by checkout:\n\t{}\nPlease commit your changes before you switch branches.\nAborting",
+            modified.join("\n\t")
+        ));
+    }
+    if !untracked.is_empty() {
+        return Err(format!(
+            "error: The following untracked working tree files would be overwritten by checkout:\n\t{}\nPlease move or remove them before you switch branches.\nAborting",
+            untracked.join("\n\t")
+        ));
+    }
+    Ok(())
+}
+
+/// Writes and removes working tree files so they match the new index
+///
+/// Like git, an unchanged skip-worktree entry is kept without looking at its file,
+/// and a changed one keeps the flag when its file is written.
+///
+/// Returns the new index entries, with the stat data of written files refreshed
+fn update_worktree(
+    index: &BTreeMap<String, IndexFile>,
+    new_index: BTreeMap<String, IndexFile>,
+    force: bool,
+) -> Result<Vec<IndexFile>, String> {
+    for path in index.keys() {
+        if !new_index.contains_key(path) {
+            remove_file(path)?;
+        }
+    }
+
+    let mut items = Vec::new();
+    for (path, mut item) in new_index.into_iter() {
+        if let Some(old) = index.get(&path) {
+            let unchanged = old.object_hash == item.object_hash && old.mode == item.mode;
+            // Forcing only rewrites files that don't match
+            if unchanged && (old.skip_worktree || !force || index_util::matches_worktree(old)) {
+                items.push(old.clone());
+                continue;
+            }
+            item.skip_worktree = old.skip_worktree;
+        }
+
+        // Like git, a submodule is only an empty directory until it is cloned
+        if item.mode == index_util::GITLINK_MODE {
+            fs::create_dir_all(&item.filename).map_err(|e| {
+                format!("error: unable to create directory {}: {}", item.filename, e)
+            })?;
+            items.push(item);
+            continue;
+        }
+        write_file(&item)?;
+        items.push(index_util::refresh_stat(&item));
+    }
+
+    Ok(items)
+}
+
+/// Writes the blob for an entry to the working tree
+fn write_file(item: &IndexFile) -> Result<(), String> {
+    let (_, content) = object_util::read_object(&item.object_hash);
+
+    if let Some(parent) = Path::new(&item.filename).parent() {
+        fs::create_dir_all(parent).map_err(|e| e.to_string())?;
+    }
+    let error = |e: std::io::Error| format!("error: unable to write file {}: {}", item.filename, e);
+    // Writing through an old symlink would change the file it points to,
+    // and a new symlink can't be created over an existing file
+    let is_symlink = |path: &str| fs::symlink_metadata(path).is_ok_and(|m| m.is_symlink());
+    if is_symlink(&item.filename) || (item.mode == 0o120000 && exists(&item.filename)) {
+        fs::remove_file(&item.filename).map_err(error)?;
+    }
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::ffi::OsStrExt;
+        use std::os::unix::fs::PermissionsExt;
+        if item.mode == 0o120000 {
+            let target = std::ffi::OsStr::from_bytes(&content);
+            return std::os::unix::fs::symlink(target, &item.filename).map_err(error);
+        }
+
+        fs::write(&item.filename, content).map_err(error)?;
+        let mode = if item.mode == 0o100755 { 0o755 } else { 0o644 };
+        fs::set_permissions(&item.filename, fs::Permissions::from_mode(mode))
+            .map_err(|e| e.to_string())?;
+    }
+    #[cfg(not(unix))]
+    fs::write(&item.filename, content).map_err(error)?;
+
+    Ok(())
+}
+
+/// Removes a file from the working tree, along with any directories left empty
+fn remove_file(path: &str) -> Result<(), String> {
+    match fs::symlink_metadata(path) {
+        // The directory of a submodule is only removed if it was never cloned
+        Ok(metadata) if metadata.is_dir() => {
+            if fs::remove_dir(path).is_err() {
+                return Ok(());
+            }
+        }
+        Ok(_) => {
+            fs::remove_file(path).map_err(|e| format!("error: unable to unlink {}: {}", path, e))?
+        }
+        Err(_) => (),
+    }
+
+    let mut dir = Path::new(path).parent();
+    while let Some(d) = dir {
+        // Stops at the first directory that isn't empty
+        if d.as_os_str().is_empty() || fs::remove_dir(d).is_err() {
+            break;
+        }
+        dir = d.parent();
+    }
+    Ok(())
+}
+
+/// Returns true if there is a file at the path, including a symlink whose target is missing
+fn exists(path: &str) -> bool {
+    fs::symlink_metadata(path).is_ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn to_args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|a| a.to_string()).collect()
+    }
+
+    #[test]
+    fn test_parse_args_checkout_new_branch() {
+        let options = parse_args(&to_args(&["-b", "topic"]), false).unwrap();
+        assert_eq!(
+            CheckoutOptions {
+                target: "HEAD".to_string(),
+                new_branch: Some("topic".to_string()),
+                detach: false,
+                force: false,
+            },
+            options
+        );
+    }
+
+    #[test]
+    fn test_parse_args_force_detach() {
+        let options = parse_args(&to_args(&["--force", "--detach", "HEAD~1"]), false).unwrap();
+        assert_eq!("HEAD~1", options.target);
+        assert!(options.force);
+        assert!(options.detach);
+    }
+
+    #[test]
+    fn test_parse_args_missing_target() {
+        assert!(parse_args(&to_args(&["-f"]), false).is_err());
+    }
+}
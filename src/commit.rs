@@ -0,0 +1,94 @@
+use super::commit_tree;
+use super::commit_util;
+use super::index_util;
+use super::ref_util;
+
+/// Records the current index as a new commit on the current branch
+///
+/// The parent of the new commit is the commit HEAD points to, if any.
+/// Returns a short summary of the new commit, e.g. `[master 1a2b3c4] message`
+pub fn commit(message: &str) -> Result<String, String> {
+    let head_ref = ref_util::resolve_symbolic("HEAD")?;
+    let parent = ref_util::resolve_ref("HEAD")?;
+
+    let tree = index_util::write_index_to_tree(false)?;
+
+    let parents = match &parent {
+        Some(p) => {
+            let parent_commit = commit_util::read_commit(p)?;
+            if parent_commit.tree == tree {
+                return Err("nothing to commit, working tree clean".to_string());
+            }
+            vec![p.to_owned()]
+        }
+        None => Vec::new(),
+    };
+
+    let hash = commit_tree::commit_tree(&tree, &parents, message)?;
+
+    // Only move the branch if nobody else has moved it since we read it
+    let old = parent.as_deref().unwrap_or(ref_util::NULL_HASH);
+    let subject = message.lines().next().unwrap_or("");
+    let reason = if parent.is_none() {
+        format!("commit (initial): {}", subject)
+    } else {
+        format!("commit: {}", subject)
+    };
+    ref_util::update_ref("HEAD", &hash, Some(old), &reason)?;
+
+    let branch = if head_ref == "HEAD" {
+        "detached HEAD"
+    } else {
+        head_ref.trim_start_matches("refs/heads/")
+    };
+    let root = if parent.is_none() {
+        " (root-commit)"
+    } else {
+        ""
+    };
+
+    Ok(format!("[{}{} {}] {}", branch, root, &hash[..7], subject))
+}
+
+/// Parse arguments for `commit`
+pub fn parse_args(args: &[String]) -> Result<String, String> {
+    let mut messages = Vec::new();
+
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "-m" => match iter.next() {
+                Some(message) => messages.push(message.to_owned()),
+                None => return Err("error: switch `m' requires a value".to_string()),
+            },
+            _ => return Err(format!("{} is not recognized as a valid option", arg)),
+        }
+    }
+
+    if messages.is_empty() {
+        return Err("Aborting commit due to empty commit message.".to_string());
+    }
+
+    Ok(commit_tree::join_messages(&messages))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_args_requires_message() {
+        assert!(parse_args(&[]).is_err());
+    }
+
+    #[test]
+    fn test_parse_args_joins_messages() {
+        let args = vec![
+            "-m".to_string(),
+            "Subject".to_string(),
+            "-m".to_string(),
+            "Body".to_string(),
+        ];
+        assert_eq!(Ok("Subject\n\nBody\n".to_string()), parse_args(&args));
+    }
+}
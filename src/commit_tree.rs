@@ -0,0 +1,140 @@
+use super::commit_util;
+use super::commit_util::Commit;
+use super::object_util;
+use super::object_util::Object;
+use super::rev_parse;
+use std::io;
+use std::io::Read;
+
+// https://git-scm.com/book/en/v2/Git-Internals-Git-Objects#_git_commit_objects
+
+/// Creates a new commit object from a tree and its parent commits
+///
+/// Returns the hash of the new commit object
+///
+/// # Arguments
+///
+/// * `tree` - The tree object the commit snapshots
+/// * `parents` - The hashes of the parent commits, empty for a root commit
+/// * `message` - The commit message
+pub fn commit_tree(tree: &str, parents: &[String], message: &str) -> Result<String, String> {
+    let tree = rev_parse::rev_parse(tree)?;
+    check_object(&tree, Object::Tree)?;
+
+    let mut parent_hashes = Vec::new();
+    for parent in parents.iter() {
+        let parent = rev_parse::rev_parse(parent)?;
+        check_object(&parent, Object::Commit)?;
+        parent_hashes.push(parent);
+    }
+
+    let author = commit_util::get_signature("AUTHOR")?;
+    let committer = commit_util::get_signature("COMMITTER")?;
+
+    let commit = Commit {
+        tree,
+        parents: parent_hashes,
+        author,
+        committer,
+        encoding: None,
+        extra_headers: Vec::new(),
+        message: message.as_bytes().to_vec(),
+        raw_headers: Vec::new(),
+    };
+
+    Ok(commit_util::write_commit(&commit))
+}
+
+/// Parse arguments for `commit_tree`
+///
+/// Returns the tree, the parents and the commit message.
+/// If no `-m` is given the message is read from stdin.
+pub fn parse_args(args: &[String]) -> Result<(String, Vec<String>, String), String> {
+    let mut tree = None;
+    let mut parents = Vec::new();
+    let mut messages = Vec::new();
+
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "-p" => match iter.next() {
+                Some(parent) => parents.push(parent.to_owned()),
+                None => return Err("error: switch `p' requires a value".to_string()),
+            },
+            "-m" => match iter.next() {
+                Some(message) => messages.push(message.to_owned()),
+                None => return Err("error: switch `m' requires a value".to_string()),
+            },
+            _ if tree.is_none() => tree = Some(arg.to_owned()),
+            _ => return Err(format!("{} is not recognized as a valid option", arg)),
+        }
+    }
+
+    let tree = tree.ok_or_else(|| "fatal: must give exactly one tree".to_string())?;
+
+    let message = if messages.is_empty() {
+        let mut message = String::new();
+        io::stdin()
+            .read_to_string(&mut message)
+            .map_err(|e| e.to_string())?;
+        message
+    } else {
+        join_messages(&messages)
+    };
+
+    Ok((tree, parents, message))
+}
+
+/// Joins each `-m` message as its own paragraph, ending with a newline
+pub fn join_messages(messages: &[String]) -> String {
+    let mut message = String::new();
+    for m in messages.iter() {
+        if !message.is_empty() {
+            message += "\n";
+        }
+        message += m;
+        if !m.ends_with('\n') {
+            message += "\n";
+        }
+    }
+    message
+}
+
+/// Checks that the object exists and has the expected type
+fn check_object(object_hash: &str, expected: Object) -> Result<(), String> {
+    if !object_util::is_valid_hash(object_hash) || !object_util::object_exists(object_hash) {
+        return Err(format!("fatal: not a valid object name {}", object_hash));
+    }
+
+    let object_type = object_util::read_object_type(object_hash)?;
+    if object_type != expected {
+        return Err(format!(
+            "fatal: {} is a {}, not a {}",
+            object_hash, object_type, expected
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_args_with_parents() {
+        let args: Vec<String> = vec!["tree", "-p", "a", "-p", "b", "-m", "msg"]
+            .into_iter()
+            .map(String::from)
+            .collect();
+        let (tree, parents, message) = parse_args(&args).unwrap();
+        assert_eq!("tree", tree);
+        assert_eq!(vec!["a".to_string(), "b".to_string()], parents);
+        assert_eq!("msg\n", message);
+    }
+
+    #[test]
+    fn test_join_messages_paragraphs() {
+        let messages = vec!["Subject".to_string(), "Body".to_string()];
+        assert_eq!("Subject\n\nBody\n", join_messages(&messages));
+    }
+}
@@ -0,0 +1,621 @@
+use super::config_util;
+use super::object_util;
+use super::object_util::Object;
+use super::timezone_util;
+use std::collections::HashSet;
+use std::env;
+use std::fmt;
+use std::time::{Duration, SystemTime};
+
+/// The identity and time attached to a commit
+///
+/// Displayed as `name <email> timestamp timezone`, e.g. `A U Thor <author@example.com> 1112911993 -0700`
+#[derive(Debug, Clone, PartialEq)]
+pub struct Signature {
+    pub name: String,
+    pub email: String,
+    pub timestamp: u64,
+    pub timezone: String,
+}
+
+impl fmt::Display for Signature {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} <{}> {} {}",
+            self.name, self.email, self.timestamp, self.timezone
+        )
+    }
+}
+
+/// The contents of a commit object
+#[derive(Debug, Clone, PartialEq)]
+pub struct Commit {
+    pub tree: String,
+    pub parents: Vec<String>,
+    pub author: Signature,
+    pub committer: Signature,
+    /// The character encoding of the message, if it isn't UTF-8
+    pub encoding: Option<String>,
+    /// Any other headers, such as `gpgsig` and `mergetag`, in the order they appear
+    pub extra_headers: Vec<(String, String)>,
+    /// The raw message, which may not be valid UTF-8
+    pub message: Vec<u8>,
+    /// The header lines as they were read, in the commit's encoding, or empty for a new commit
+    ///
+    /// A parsed commit is written back from these, so names that aren't UTF-8 are kept as stored.
+    pub raw_headers: Vec<u8>,
+}
+
+impl Commit {
+    /// Serializes the commit into the content of a commit object (without the object header)
+    pub fn to_bytes(&self) -> Vec<u8> {
+        if !self.raw_headers.is_empty() {
+            let mut bytes = self.raw_headers.clone();
+            bytes.push(b'\n');
+            bytes.extend_from_slice(&self.message);
+            return bytes;
+        }
+
+        let mut content = format!("tree {}\n", self.tree);
+        for parent in self.parents.iter() {
+            content += &format!("parent {}\n", parent);
+        }
+        content += &format!("author {}\n", self.author);
+        content += &format!("committer {}\n", self.committer);
+        if let Some(encoding) = &self.encoding {
+            content += &format!("encoding {}\n", encoding);
+        }
+        content += &format_headers(&self.extra_headers);
+        content += "\n";
+
+        let mut bytes = content.into_bytes();
+        bytes.extend_from_slice(&self.message);
+        bytes
+    }
+
+    /// Returns the message decoded using the commit's encoding
+    pub fn message_text(&self) -> String {
+        decode_message(&self.message, self.encoding.as_deref())
+    }
+
+    /// Returns the first line of the message
+    pub fn subject(&self) -> String {
+        self.message_text().lines().next().unwrap_or("").to_string()
+    }
+
+    /// Returns the author with the name and email decoded using the commit's encoding
+    pub fn author_text(&self) -> Signature {
+        let headers = decode_message(&self.raw_headers, self.encoding.as_deref());
+        parse_headers(&headers)
+            .and_then(|headers| headers.into_iter().find(|(key, _)| key == "author"))
+            .and_then(|(_, value)| parse_signature(&value).ok())
+            .unwrap_or_else(|| self.author.clone())
+    }
+}
+
+/// Parses the content of a commit object (without the object header)
+///
+/// # Format
+///
+/// A commit is a list of `key value` header lines, a blank line, then the commit message.
+/// Header values spanning several lines continue on lines starting with a space.
+pub fn parse_commit(content: &[u8]) -> Result<Commit, String> {
+    let invalid = || "fatal: invalid commit object".to_string();
+    let (raw_headers, message) = split_headers(content);
+    // Names may be in the commit's encoding, they are only decoded to be displayed
+    let headers = String::from_utf8_lossy(raw_headers);
+
+    let mut tree = None;
+    let mut parents = Vec::new();
+    let mut author = None;
+    let mut committer = None;
+    let mut encoding = None;
+    let mut extra_headers = Vec::new();
+
+    for (key, value) in parse_headers(&headers).ok_or_else(invalid)? {
+        match key.as_str() {
+            "tree" if tree.is_none() => tree = Some(value),
+            "parent" => parents.push(value),
+            "author" if author.is_none() => author = Some(parse_signature(&value)?),
+            "committer" if committer.is_none() => committer = Some(parse_signature(&value)?),
+            "encoding" if encoding.is_none() => encoding = Some(value),
+            _ => extra_headers.push((key, value)),
+        }
+    }
+
+    let valid_hashes = tree
+        .iter()
+        .chain(parents.iter())
+        .all(|h| object_util::is_valid_hash(h));
+    match (tree, author, committer) {
+        (Some(tree), Some(author), Some(committer)) if valid_hashes => Ok(Commit {
+            tree,
+            parents,
+            author,
+            committer,
+            encoding,
+            extra_headers,
+            message: message.to_vec(),
+            raw_headers: raw_headers.to_vec(),
+        }),
+        _ => Err(invalid()),
+    }
+}
+
+/// Splits the content of a commit or tag into its raw headers and message at the first blank line
+pub fn split_headers(content: &[u8]) -> (&[u8], &[u8]) {
+    match content.windows(2).position(|w| w == b"\n\n") {
+        Some(i) => (&content[..i + 1], &content[i + 2..]),
+        None => (content, &[]),
+    }
+}
+
+/// Parses `key value` header lines, joining continuation lines onto the previous value
+///
+/// Returns `None` if a continuation line comes before any header
+pub fn parse_headers(headers: &str) -> Option<Vec<(String, String)>> {
+    let mut parsed: Vec<(String, String)> = Vec::new();
+    for line in headers.lines() {
+        if let Some(continuation) = line.strip_prefix(' ') {
+            let (_, value) = parsed.last_mut()?;
+            value.push('\n');
+            value.push_str(continuation);
+            continue;
+        }
+        let (key, value) = match line.find(' ') {
+            Some(i) => (&line[..i], &line[i + 1..]),
+            None => (line, ""),
+        };
+        parsed.push((key.to_string(), value.to_string()));
+    }
+    Some(parsed)
+}
+
+/// Formats headers as `key value` lines, continuing multi-line values on lines starting with a space
+pub fn format_headers(headers: &[(String, String)]) -> String {
+    headers
+        .iter()
+        .map(|(key, value)| format!("{} {}\n", key, value.replace('\n', "\n ")))
+        .collect()
+}
+
+/// Decodes a message in the given encoding
+///
+/// Latin-1 messages are converted, anything else is read as UTF-8 with invalid bytes replaced
+pub fn decode_message(message: &[u8], encoding: Option<&str>) -> String {
+    match encoding.map(|e| e.to_lowercase()).as_deref() {
+        Some("iso-8859-1") | Some("iso8859-1") | Some("latin1") | Some("latin-1") => {
+            message.iter().map(|&b| b as char).collect()
+        }
+        _ => String::from_utf8_lossy(message).into_owned(),
+    }
+}
+
+/// Reads and parses the given commit from the object database
+pub fn read_commit(object_hash: &str) -> Result<Commit, String> {
+    if !object_util::is_valid_hash(object_hash) || !object_util::object_exists(object_hash) {
+        return Err(format!("fatal: not a valid object name {}", object_hash));
+    }
+
+    let (object_type, content) = object_util::read_object(object_hash);
+    if object_type != Object::Commit {
+        return Err(format!("fatal: {} is not a commit", object_hash));
+    }
+    parse_commit(&content)
+}
+
+/// Returns true if `ancestor` can be reached from `commit` by following parents
+///
+/// A commit counts as its own ancestor
+pub fn is_ancestor(ancestor: &str, commit: &str) -> Result<bool, String> {
+    let mut pending = vec![commit.to_string()];
+    let mut seen = HashSet::new();
+    while let Some(hash) = pending.pop() {
+        if hash == ancestor {
+            return Ok(true);
+        }
+        if seen.insert(hash.clone()) {
+            pending.extend(read_commit(&hash)?.parents);
+        }
+    }
+    Ok(false)
+}
+
+/// Parses a signature in the form `name <email> timestamp timezone`
+pub fn parse_signature(value: &str) -> Result<Signature, String> {
+    let invalid = || format!("fatal: invalid signature: {}", value);
+
+    let email_start = value.find('<').ok_or_else(invalid)?;
+    let email_end = value.rfind('>').ok_or_else(invalid)?;
+    if email_end < email_start {
+        return Err(invalid());
+    }
+
+    let (timestamp, timezone) = parse_date(&value[email_end + 1..]).map_err(|_| invalid())?;
+
+    Ok(Signature {
+        name: value[..email_start].trim().to_string(),
+        email: value[email_start + 1..email_end].to_string(),
+        timestamp,
+        timezone,
+    })
+}
+
+/// Writes the commit to the object database
+///
+/// Returns the hash of the new commit object
+pub fn write_commit(commit: &Commit) -> String {
+    object_util::write_object(Object::Commit, &commit.to_bytes())
+}
+
+/// Returns the signature for the given role, either `AUTHOR` or `COMMITTER`
+///
+/// The name and email are read from `GIT_<role>_NAME` and `GIT_<role>_EMAIL`,
+/// falling back to `user.name` and `user.email` in the config.
+/// The date can be overridden with `GIT_<role>_DATE` in the form `<timestamp> <timezone>`.
+pub fn get_signature(role: &str) -> Result<Signature, String> {
+    let name = env::var(format!("GIT_{}_NAME", role))
+        .ok()
+        .or_else(|| config_util::get_value("user.name"));
+    let email = env::var(format!("GIT_{}_EMAIL", role))
+        .ok()
+        .or_else(|| config_util::get_value("user.email"));
+
+    let (name, email) = match (name, email) {
+        (Some(n), Some(e)) if !n.is_empty() => (n, e),
+        _ => {
+            return Err(format!(
+                "{} identity unknown\n\n*** Please tell me who you are.\n\nSet user.name and user.email in gitrs/config",
+                capitalize(role)
+            ))
+        }
+    };
+
+    let (timestamp, timezone) = match env::var(format!("GIT_{}_DATE", role)) {
+        Ok(date) => parse_date(&date)?,
+        Err(_) => {
+            let now = now();
+            (now, timezone_util::local_timezone(now))
+        }
+    };
+
+    Ok(Signature {
+        name,
+        email,
+        timestamp,
+        timezone,
+    })
+}
+
+/// Returns the current time in seconds since the unix epoch
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_else(|_| Duration::new(0, 0))
+        .as_secs()
+}
+
+/// Formats a timestamp in git's default date format, e.g. `Thu Apr 7 15:13:13 2005 -0700`
+///
+/// The date is shown in the given timezone
+pub fn format_date(timestamp: u64, timezone: &str) -> String {
+    const DAYS: [&str; 7] = ["Sun", "Mon", "Tue", "Wed", "Thu", "Fri", "Sat"];
+    const MONTHS: [&str; 12] = [
+        "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+    ];
+
+    let offset = timezone_offset(timezone);
+    let local = timestamp as i64 + offset;
+    let days = local.div_euclid(86400);
+    let secs = local.rem_euclid(86400);
+    let (year, month, day) = civil_from_days(days);
+
+    format!(
+        "{} {} {} {:02}:{:02}:{:02} {} {}",
+        DAYS[(days + 4).rem_euclid(7) as usize],
+        MONTHS[(month - 1) as usize],
+        day,
+        secs / 3600,
+        secs % 3600 / 60,
+        secs % 60,
+        year,
+        timezone
+    )
+}
+
+/// Formats a timestamp as a `YYYY-MM-DD` date in UTC, like git's short date format
+pub fn format_short_date(timestamp: u64) -> String {
+    let (year, month, day) = civil_from_days(timestamp as i64 / 86400);
+    format!("{}-{:02}-{:02}", year, month, day)
+}
+
+/// Parses a date the way `@{<date>}` and `--expire=<date>` do
+///
+/// Supports `now`, `yesterday`, relative dates such as `2.weeks.ago` or `3 days ago`,
+/// `YYYY-MM-DD [HH:MM[:SS]]` (in UTC) and `@<timestamp>`.
+/// Relative dates are counted back from `now`
+pub fn parse_approxidate(date: &str, now: u64) -> Option<u64> {
+    let date = date.trim();
+    if let Some(timestamp) = date.strip_prefix('@') {
+        return timestamp.parse::<u64>().ok();
+    }
+    match date {
+        "now" => return Some(now),
+        "yesterday" => return now.checked_sub(86400),
+        _ => (),
+    }
+
+    if let Some((day, time)) = parse_iso_date(date) {
+        return Some(day * 86400 + time);
+    }
+
+    let words: Vec<&str> = date
+        .split(|c: char| c == '.' || c == '_' || c.is_whitespace())
+        .filter(|w| !w.is_empty())
+        .collect();
+    let (count, unit) = match words[..] {
+        [count, unit, "ago"] | [count, unit] => (count.parse::<u64>().ok()?, unit),
+        _ => return None,
+    };
+    let seconds = match unit.trim_end_matches('s') {
+        "second" | "sec" => 1,
+        "minute" | "min" => 60,
+        "hour" => 3600,
+        "day" => 86400,
+        "week" => 7 * 86400,
+        "month" => 30 * 86400,
+        "year" => 365 * 86400,
+        _ => return None,
+    };
+    now.checked_sub(count.checked_mul(seconds)?)
+}
+
+/// Parses `YYYY-MM-DD [HH:MM[:SS]]` into days since the unix epoch and seconds into the day
+fn parse_iso_date(date: &str) -> Option<(u64, u64)> {
+    let mut parts = date.splitn(2, [' ', 'T', '.']);
+    let ymd: Vec<i64> = parts
+        .next()?
+        .split('-')
+        .map(|p| p.parse::<i64>().ok())
+        .collect::<Option<_>>()?;
+    let (year, month, day) = match ymd[..] {
+        [y, m, d] if (1..=12).contains(&m) && (1..=31).contains(&d) => (y, m, d),
+        _ => return None,
+    };
+
+    let time = match parts.next() {
+        Some(time) => {
+            let hms: Vec<u64> = time
+                .split(':')
+                .map(|p| p.parse::<u64>().ok())
+                .collect::<Option<_>>()?;
+            match hms[..] {
+                [h, m] => h * 3600 + m * 60,
+                [h, m, s] => h * 3600 + m * 60 + s,
+                _ => return None,
+            }
+        }
+        None => 0,
+    };
+
+    let days = days_from_civil(year, month, day);
+    if days < 0 {
+        return None;
+    }
+    Some((days as u64, time))
+}
+
+/// Returns the offset in seconds of a timezone such as `-0700`
+fn timezone_offset(timezone: &str) -> i64 {
+    let value = timezone[1..].parse::<i64>().unwrap_or(0);
+    let offset = value / 100 * 3600 + value % 100 * 60;
+    if timezone.starts_with('-') {
+        -offset
+    } else {
+        offset
+    }
+}
+
+/// Converts days since the unix epoch to a `(year, month, day)` date
+///
+/// See http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+pub fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+/// Parses a date in git's internal format, `<timestamp> <timezone>`
+fn parse_date(date: &str) -> Result<(u64, String), String> {
+    let mut split = date.trim().split(' ');
+    let timestamp = split
+        .next()
+        .and_then(|t| t.trim_start_matches('@').parse::<u64>().ok());
+    let timezone = split.next().unwrap_or("+0000");
+
+    let valid_timezone = timezone.len() == 5
+        && (timezone.starts_with('+') || timezone.starts_with('-'))
+        && timezone[1..].chars().all(|c| c.is_ascii_digit());
+
+    match timestamp {
+        Some(t) if valid_timezone => Ok((t, timezone.to_string())),
+        _ => Err(format!("fatal: invalid date format: {}", date)),
+    }
+}
+
+fn capitalize(role: &str) -> String {
+    let lower = role.to_lowercase();
+    let mut chars = lower.chars();
+    match chars.next() {
+        Some(c) => c.to_uppercase().collect::<String>() + chars.as_str(),
+        None => lower,
+    }
+}
+
+/// Converts a date to days since the unix epoch, the inverse of [`civil_from_days`]
+pub fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let yoe = year.rem_euclid(400);
+    let mp = if month > 2 { month - 3 } else { month + 9 };
+    let doy = (153 * mp + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146097 + doe - 719468
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn signature() -> Signature {
+        Signature {
+            name: "A U Thor".to_string(),
+            email: "author@example.com".to_string(),
+            timestamp: 1112911993,
+            timezone: "-0700".to_string(),
+        }
+    }
+
+    #[test]
+    fn test_commit_to_bytes() {
+        let commit = Commit {
+            tree: "4b825dc642cb6eb9a060e54bf8d69288fbee4904".to_string(),
+            parents: vec!["0123456789012345678901234567890123456789".to_string()],
+            author: signature(),
+            committer: signature(),
+            encoding: None,
+            extra_headers: Vec::new(),
+            message: b"Initial commit\n".to_vec(),
+            raw_headers: Vec::new(),
+        };
+        let expected = "tree 4b825dc642cb6eb9a060e54bf8d69288fbee4904\n\
+                        parent 0123456789012345678901234567890123456789\n\
+                        author A U Thor <author@example.com> 1112911993 -0700\n\
+                        committer A U Thor <author@example.com> 1112911993 -0700\n\
+                        \n\
+                        Initial commit\n";
+        assert_eq!(expected.as_bytes(), &commit.to_bytes()[..]);
+    }
+
+    #[test]
+    fn test_parse_commit_round_trip() {
+        let commit = Commit {
+            tree: "4b825dc642cb6eb9a060e54bf8d69288fbee4904".to_string(),
+            parents: vec![
+                "0123456789012345678901234567890123456789".to_string(),
+                "9876543210987654321098765432109876543210".to_string(),
+            ],
+            author: signature(),
+            committer: signature(),
+            encoding: None,
+            extra_headers: Vec::new(),
+            message: b"Merge\n\nWith a body\n".to_vec(),
+            raw_headers: Vec::new(),
+        };
+        let parsed = parse_commit(&commit.to_bytes()).unwrap();
+        assert_eq!(commit.to_bytes(), parsed.to_bytes());
+        assert_eq!(
+            commit,
+            Commit {
+                raw_headers: Vec::new(),
+                ..parsed
+            }
+        );
+    }
+
+    #[test]
+    fn test_format_date() {
+        assert_eq!(
+            "Thu Apr 7 15:13:13 2005 -0700",
+            format_date(1112911993, "-0700")
+        );
+        assert_eq!("Thu Jan 1 00:00:00 1970 +0000", format_date(0, "+0000"));
+        assert_eq!("2005-04-07", format_short_date(1112911993));
+    }
+
+    #[test]
+    fn test_parse_commit_extra_headers() {
+        let content = b"tree 4b825dc642cb6eb9a060e54bf8d69288fbee4904\n\
+author A U Thor <author@example.com> 1112911993 -0700\n\
+committer A U Thor <author@example.com> 1112911993 -0700\n\
+encoding ISO-8859-1\n\
+gpgsig -----BEGIN PGP SIGNATURE-----\n \n iQEzBAABCAAdFiEE\n -----END PGP SIGNATURE-----\n\
+\n\
+Caf\xe9\n";
+        let commit = parse_commit(content).unwrap();
+        assert_eq!(Some("ISO-8859-1".to_string()), commit.encoding);
+        assert_eq!(
+            vec![(
+                "gpgsig".to_string(),
+                "-----BEGIN PGP SIGNATURE-----\n\niQEzBAABCAAdFiEE\n-----END PGP SIGNATURE-----"
+                    .to_string()
+            )],
+            commit.extra_headers
+        );
+        assert_eq!("Caf\u{e9}", commit.subject());
+        assert_eq!(&content[..], &commit.to_bytes()[..]);
+    }
+
+    #[test]
+    fn test_parse_commit_header_encoding() {
+        let content = b"tree 4b825dc642cb6eb9a060e54bf8d69288fbee4904\n\
+author Ren\xe9 <rene@example.com> 1112911993 -0700\n\
+committer Ren\xe9 <rene@example.com> 1112911993 -0700\n\
+encoding latin1\n\
+\n\
+message\n";
+        let commit = parse_commit(content).unwrap();
+        assert_eq!("Ren\u{e9}", commit.author_text().name);
+        assert_eq!(&content[..], &commit.to_bytes()[..]);
+    }
+
+    #[test]
+    fn test_parse_commit_missing_tree() {
+        let content = b"author A U Thor <author@example.com> 1112911993 -0700\n\
+committer A U Thor <author@example.com> 1112911993 -0700\n\nmessage\n";
+        assert!(parse_commit(content).is_err());
+    }
+
+    #[test]
+    fn test_parse_approxidate() {
+        let now = 1112911993;
+        assert_eq!(Some(now - 86400), parse_approxidate("yesterday", now));
+        assert_eq!(
+            Some(now - 2 * 7 * 86400),
+            parse_approxidate("2.weeks.ago", now)
+        );
+        assert_eq!(Some(now - 3 * 3600), parse_approxidate("3 hours ago", now));
+        assert_eq!(Some(1112911993), parse_approxidate("@1112911993", now));
+        assert_eq!(Some(1112918400), parse_approxidate("2005-04-08", now));
+        assert_eq!(Some(now), parse_approxidate("2005-04-07 22:13:13", now));
+        assert_eq!(None, parse_approxidate("someday", now));
+        assert_eq!(None, parse_approxidate("99999999999999999 years ago", now));
+    }
+
+    #[test]
+    fn test_parse_signature() {
+        assert_eq!(
+            Ok(signature()),
+            parse_signature("A U Thor <author@example.com> 1112911993 -0700")
+        );
+        assert!(parse_signature("A U Thor 1112911993 -0700").is_err());
+    }
+
+    #[test]
+    fn test_parse_date() {
+        assert_eq!(
+            Ok((1112911993, "-0700".to_string())),
+            parse_date("1112911993 -0700")
+        );
+        assert!(parse_date("yesterday").is_err());
+        assert!(parse_date("1112911993 0700").is_err());
+    }
+}
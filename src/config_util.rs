@@ -0,0 +1,116 @@
+use super::lock_util::LockFile;
+use std::fs;
+
+const CONFIG_PATH: &str = "gitrs/config";
+
+/// Returns the value for the given key from the repository config, if it is set
+///
+/// Keys are written as `section.key` or `section.subsection.key`, e.g. `user.name`
+pub fn get_value(key: &str) -> Option<String> {
+    let content = fs::read_to_string(CONFIG_PATH).ok()?;
+    let wanted = normalize_key(key);
+
+    // Later entries override earlier ones, like git
+    parse_config(&content)
+        .into_iter()
+        .rev()
+        .find(|(k, _)| *k == wanted)
+        .map(|(_, v)| v)
+}
+
+/// Replaces the repository config, holding `config.lock` while it is written
+pub fn write_config(content: &str) -> Result<(), String> {
+    LockFile::lock(CONFIG_PATH)?.commit(content.as_bytes())
+}
+
+/// Parses the contents of a config file into a list of keys and values
+///
+/// # Format
+///
+/// A section starts with `[section]` or `[section "subsection"]`,
+/// followed by `key = value` lines. `#` and `;` start comments.
+/// Section and key names are case insensitive, subsection names are not.
+fn parse_config(content: &str) -> Vec<(String, String)> {
+    let mut entries = Vec::new();
+    let mut section = String::new();
+
+    for line in content.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') || line.starts_with(';') {
+            continue;
+        }
+
+        if line.starts_with('[') {
+            let inner = line.trim_start_matches('[').trim_end_matches(']');
+            section = match inner.find(' ') {
+                Some(i) => {
+                    let subsection = inner[i..].trim().trim_matches('"');
+                    format!("{}.{}", inner[..i].to_lowercase(), subsection)
+                }
+                None => inner.to_lowercase(),
+            };
+            continue;
+        }
+
+        // A key without a value is a boolean true
+        let (key, value) = match line.find('=') {
+            Some(i) => (line[..i].trim(), line[i + 1..].trim()),
+            None => (line, "true"),
+        };
+        let value = value.trim_matches('"');
+
+        entries.push((
+            format!("{}.{}", section, key.to_lowercase()),
+            value.to_string(),
+        ));
+    }
+
+    entries
+}
+
+/// Lower cases the section and key name, leaving any subsection as is
+fn normalize_key(key: &str) -> String {
+    let first = key.find('.');
+    let last = key.rfind('.');
+    match (first, last) {
+        (Some(f), Some(l)) if f != l => format!(
+            "{}{}{}",
+            key[..f].to_lowercase(),
+            &key[f..l],
+            key[l..].to_lowercase()
+        ),
+        _ => key.to_lowercase(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_config_sections() {
+        let content = "[core]\n    bare = false\n[User]\n    Name = Some One\n";
+        let entries = parse_config(content);
+        assert_eq!(
+            vec![
+                ("core.bare".to_string(), "false".to_string()),
+                ("user.name".to_string(), "Some One".to_string())
+            ],
+            entries
+        );
+    }
+
+    #[test]
+    fn test_parse_config_subsection() {
+        let content = "[branch \"Main\"]\n\tremote = origin\n";
+        let entries = parse_config(content);
+        assert_eq!("branch.Main.remote", entries[0].0);
+        assert_eq!("origin", entries[0].1);
+    }
+
+    #[test]
+    fn test_normalize_key() {
+        assert_eq!("user.email", normalize_key("User.Email"));
+        assert_eq!("branch.Main.merge", normalize_key("Branch.Main.Merge"));
+    }
+}
@@ -0,0 +1,310 @@
+use super::pack_util;
+use std::collections::HashMap;
+
+// https://git-scm.com/docs/pack-format#_deltified_representation
+
+/// Bytes of the base hashed together when looking for matches
+const BLOCK_SIZE: usize = 16;
+/// The most base offsets kept for one block hash, so repetitive data stays fast
+const MAX_BLOCK_CANDIDATES: usize = 64;
+/// The most bytes a single copy instruction is given, larger copies are split
+const MAX_COPY_SIZE: usize = 0x10000;
+/// The most bytes a single insert instruction can hold
+const MAX_INSERT_SIZE: usize = 0x7f;
+/// The multiplier of the rolling block hash
+const HASH_PRIME: u32 = 0x0100_0193;
+
+/// The blocks of a delta base, indexed by hash so matches can be found in a target
+pub struct DeltaIndex {
+    base: Vec<u8>,
+    blocks: HashMap<u32, Vec<usize>>,
+}
+
+impl DeltaIndex {
+    /// Indexes each non-overlapping block of the base
+    pub fn new(base: Vec<u8>) -> DeltaIndex {
+        let mut blocks: HashMap<u32, Vec<usize>> = HashMap::new();
+        for offset in (0..base.len().saturating_sub(BLOCK_SIZE - 1)).step_by(BLOCK_SIZE) {
+            let candidates = blocks
+                .entry(hash_block(&base[offset..offset + BLOCK_SIZE]))
+                .or_default();
+            if candidates.len() < MAX_BLOCK_CANDIDATES {
+                candidates.push(offset);
+            }
+        }
+        DeltaIndex { base, blocks }
+    }
+
+    /// Returns the offset and length of the longest match in the base for the target at `pos`
+    fn find_match(&self, target: &[u8], pos: usize, hash: u32) -> Option<(usize, usize)> {
+        let mut best: Option<(usize, usize)> = None;
+        for &offset in self.blocks.get(&hash)?.iter() {
+            let len = self.base[offset..]
+                .iter()
+                .zip(target[pos..].iter())
+                .take_while(|(a, b)| a == b)
+                .count();
+            if len >= BLOCK_SIZE && best.is_none_or(|(_, best_len)| len > best_len) {
+                best = Some((offset, len));
+            }
+        }
+        best
+    }
+}
+
+/// Encodes the target as a delta against the indexed base, the reverse of [`apply_delta`]
+///
+/// Blocks of the target found in the base become copy instructions, extended in both
+/// directions as far as the data matches, and everything else is inserted.
+///
+/// Returns `None` if the delta would be larger than `max_size`
+pub fn create_delta(index: &DeltaIndex, target: &[u8], max_size: usize) -> Option<Vec<u8>> {
+    let base = &index.base;
+    let mut delta = encode_delta_size(base.len() as u64);
+    delta.extend(encode_delta_size(target.len() as u64));
+
+    // Target bytes from here up to `pos` haven't been matched and are waiting to be inserted
+    let mut insert_start = 0;
+    let mut pos = 0;
+    let mut hash = target.get(..BLOCK_SIZE).map(hash_block).unwrap_or(0);
+    let roll_factor = (1..BLOCK_SIZE).fold(1u32, |f, _| f.wrapping_mul(HASH_PRIME));
+
+    while pos + BLOCK_SIZE <= target.len() {
+        match index.find_match(target, pos, hash) {
+            Some((mut offset, mut len)) => {
+                // Take back any matching bytes that were about to be inserted
+                while pos > insert_start && offset > 0 && target[pos - 1] == base[offset - 1] {
+                    pos -= 1;
+                    offset -= 1;
+                    len += 1;
+                }
+                push_inserts(&mut delta, &target[insert_start..pos]);
+                push_copies(&mut delta, offset, len);
+
+                pos += len;
+                insert_start = pos;
+                if pos + BLOCK_SIZE <= target.len() {
+                    hash = hash_block(&target[pos..pos + BLOCK_SIZE]);
+                }
+            }
+            None => {
+                if pos + BLOCK_SIZE < target.len() {
+                    hash = hash
+                        .wrapping_sub((target[pos] as u32).wrapping_mul(roll_factor))
+                        .wrapping_mul(HASH_PRIME)
+                        .wrapping_add(target[pos + BLOCK_SIZE] as u32);
+                }
+                pos += 1;
+            }
+        }
+        if delta.len() + (pos - insert_start) > max_size {
+            return None;
+        }
+    }
+
+    push_inserts(&mut delta, &target[insert_start..]);
+    if delta.len() > max_size {
+        return None;
+    }
+    Some(delta)
+}
+
+/// Hashes a block so that it can be rolled forward a byte at a time
+fn hash_block(block: &[u8]) -> u32 {
+    block.iter().fold(0u32, |h, b| {
+        h.wrapping_mul(HASH_PRIME).wrapping_add(*b as u32)
+    })
+}
+
+/// Appends insert instructions for the data, splitting it into runs the format can hold
+fn push_inserts(delta: &mut Vec<u8>, data: &[u8]) {
+    for chunk in data.chunks(MAX_INSERT_SIZE) {
+        delta.push(chunk.len() as u8);
+        delta.extend_from_slice(chunk);
+    }
+}
+
+/// Appends copy instructions for a range of the base, only writing the non-zero bytes
+/// of each offset and size
+fn push_copies(delta: &mut Vec<u8>, mut offset: usize, mut len: usize) {
+    while len > 0 {
+        let size = len.min(MAX_COPY_SIZE);
+        let mut instruction = 0x80u8;
+        let mut args = Vec::new();
+        for i in 0..4 {
+            let byte = (offset >> (i * 8)) as u8;
+            if byte != 0 {
+                instruction |= 1 << i;
+                args.push(byte);
+            }
+        }
+        // A size of 0x10000 is written as no size bytes at all
+        for i in 0..3 {
+            let byte = ((size % MAX_COPY_SIZE) >> (i * 8)) as u8;
+            if byte != 0 {
+                instruction |= 0x10 << i;
+                args.push(byte);
+            }
+        }
+        delta.push(instruction);
+        delta.extend(args);
+
+        offset += size;
+        len -= size;
+    }
+}
+
+/// Encodes a size at the start of a delta, the reverse of [`parse_delta_size`]
+fn encode_delta_size(mut size: u64) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    loop {
+        let byte = (size & 0x7f) as u8;
+        size >>= 7;
+        if size == 0 {
+            bytes.push(byte);
+            return bytes;
+        }
+        bytes.push(byte | 0x80);
+    }
+}
+
+/// Rebuilds an object from its base and a delta
+///
+/// # Format
+///
+/// A delta starts with the sizes of the base and the result, then a list of instructions.
+/// An instruction with the high bit set copies a range of the base, its low 4 bits say which
+/// offset bytes follow and the next 3 bits which size bytes follow. Otherwise the low 7 bits
+/// are a count of bytes to insert from the delta itself.
+pub fn apply_delta(base: &[u8], delta: &[u8]) -> Result<Vec<u8>, String> {
+    let corrupt = || "error: corrupt delta".to_string();
+
+    let (base_size, mut pos) = parse_delta_size(delta, 0).ok_or_else(corrupt)?;
+    let (result_size, next) = parse_delta_size(delta, pos).ok_or_else(corrupt)?;
+    pos = next;
+    if base_size != base.len() as u64 {
+        return Err("error: delta base size mismatch".to_string());
+    }
+
+    let mut result = Vec::with_capacity(result_size.min(pack_util::MAX_PREALLOC) as usize);
+    while pos < delta.len() {
+        let instruction = delta[pos];
+        pos += 1;
+
+        if instruction & 0x80 != 0 {
+            let mut offset = 0usize;
+            let mut size = 0usize;
+            for i in 0..4 {
+                if instruction & (1 << i) != 0 {
+                    offset |= (*delta.get(pos).ok_or_else(corrupt)? as usize) << (i * 8);
+                    pos += 1;
+                }
+            }
+            for i in 0..3 {
+                if instruction & (0x10 << i) != 0 {
+                    size |= (*delta.get(pos).ok_or_else(corrupt)? as usize) << (i * 8);
+                    pos += 1;
+                }
+            }
+            // A size of zero means 0x10000
+            if size == 0 {
+                size = 0x10000;
+            }
+            let end = offset.checked_add(size).ok_or_else(corrupt)?;
+            result.extend_from_slice(base.get(offset..end).ok_or_else(corrupt)?);
+        } else if instruction != 0 {
+            let end = pos + instruction as usize;
+            result.extend_from_slice(delta.get(pos..end).ok_or_else(corrupt)?);
+            pos = end;
+        } else {
+            // Zero is reserved
+            return Err(corrupt());
+        }
+    }
+
+    if result.len() as u64 != result_size {
+        return Err("error: delta result size mismatch".to_string());
+    }
+    Ok(result)
+}
+
+/// Parses a size at the start of a delta, 7 bits per byte, least significant first
+fn parse_delta_size(delta: &[u8], mut pos: usize) -> Option<(u64, usize)> {
+    let mut size = 0u64;
+    let mut shift = 0;
+    loop {
+        let byte = *delta.get(pos)?;
+        pos += 1;
+        size |= ((byte & 0x7f) as u64) << shift;
+        shift += 7;
+        if byte & 0x80 == 0 {
+            return Some((size, pos));
+        }
+        if shift > 63 {
+            return None;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_apply_delta() {
+        let base = b"Hello, World";
+        // base size 12, result size 12, copy 7 bytes from offset 0, insert "Rust!"
+        let delta = [12, 12, 0x90, 7, 5, b'R', b'u', b's', b't', b'!'];
+        assert_eq!(b"Hello, Rust!".to_vec(), apply_delta(base, &delta).unwrap());
+
+        let delta = [12, 11, 0x90, 7, 5, b'R', b'u', b's', b't', b'!'];
+        assert_eq!(
+            Err("error: delta result size mismatch".to_string()),
+            apply_delta(base, &delta)
+        );
+    }
+
+    #[test]
+    fn test_apply_delta_copy_out_of_range() {
+        let delta = [3, 4, 0x91, 2, 4];
+        assert!(apply_delta(b"abc", &delta).is_err());
+    }
+
+    #[test]
+    fn test_apply_delta_huge_result_size() {
+        // A result size of 1 << 50 followed by a copy of the whole base
+        let delta = [3, 0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x02, 0x90, 3];
+        assert_eq!(
+            Err("error: delta result size mismatch".to_string()),
+            apply_delta(b"abc", &delta)
+        );
+    }
+
+    #[test]
+    fn test_create_delta_round_trip() {
+        let base: Vec<u8> = (0..2000).map(|i| (i * 7 % 251) as u8).collect();
+        let mut target = base[100..1500].to_vec();
+        target.extend_from_slice(b"something new in the middle");
+        target.extend_from_slice(&base[..300]);
+
+        let delta = create_delta(&DeltaIndex::new(base.clone()), &target, usize::MAX).unwrap();
+        assert!(delta.len() < 64);
+        assert_eq!(target, apply_delta(&base, &delta).unwrap());
+    }
+
+    #[test]
+    fn test_create_delta_large_copy() {
+        let base: Vec<u8> = (0..200_000).map(|i| (i * 31 % 253) as u8).collect();
+        let delta = create_delta(&DeltaIndex::new(base.clone()), &base, usize::MAX).unwrap();
+        assert_eq!(base, apply_delta(&base, &delta).unwrap());
+    }
+
+    #[test]
+    fn test_create_delta_too_large() {
+        let index = DeltaIndex::new(b"0123456789abcdef0123456789abcdef".to_vec());
+        assert_eq!(
+            None,
+            create_delta(&index, b"nothing in common here at all", 10)
+        );
+    }
+}
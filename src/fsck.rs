@@ -0,0 +1,185 @@
+use super::index_util;
+use super::index_util::IndexFile;
+use super::object_util;
+use super::pack_objects;
+use std::collections::{HashMap, HashSet};
+
+// https://git-scm.com/docs/git-fsck
+
+/// Options for `fsck`
+#[derive(Debug, Default, PartialEq)]
+pub struct FsckOptions {
+    /// Only checks the index, not the objects reachable from the refs
+    pub index_only: bool,
+}
+
+/// Checks the index, then that every reachable object is present and hashes to its name
+///
+/// Returns an error listing every problem found
+pub fn fsck(options: &FsckOptions) -> Result<String, String> {
+    let (_header, items) = index_util::parse_index()?;
+    let mut problems = check_index(&items);
+    problems.extend(
+        items
+            .iter()
+            .filter(|item| !item.intent_to_add && !object_util::object_exists(&item.object_hash))
+            .map(|item| {
+                format!(
+                    "error: index entry '{}' points to missing object {}",
+                    item.filename, item.object_hash
+                )
+            }),
+    );
+
+    if !options.index_only {
+        // A missing object stops the walk, but the problems found so far are still shown
+        let objects = pack_objects::reachable_objects().unwrap_or_else(|e| {
+            problems.push(e);
+            Vec::new()
+        });
+        for (hash, _) in objects {
+            let mut object = match object_util::open_object(&hash) {
+                Ok(object) => object,
+                Err(e) => {
+                    problems.push(e);
+                    continue;
+                }
+            };
+            let (object_type, size) = (object.object_type, object.size);
+            match object_util::hash_object_stream(object_type, size, &mut object) {
+                Ok(actual) if actual == hash => (),
+                Ok(actual) => problems.push(format!(
+                    "error: {} {}: hash mismatch, the content hashes to {}",
+                    object_type, hash, actual
+                )),
+                Err(e) => problems.push(format!("error: {} {}: {}", object_type, hash, e)),
+            }
+        }
+    }
+
+    match problems.is_empty() {
+        true => Ok(String::new()),
+        false => Err(problems.join("\n")),
+    }
+}
+
+/// Parse arguments for `fsck`
+pub fn parse_args(args: &[String]) -> Result<FsckOptions, String> {
+    let mut options = FsckOptions::default();
+    for arg in args.iter() {
+        match arg.as_str() {
+            "--index" => options.index_only = true,
+            _ => return Err(format!("{} is not recognized as a valid option", arg)),
+        }
+    }
+    Ok(options)
+}
+
+/// Lists entries that are out of order or duplicated
+///
+/// Entries must be sorted by path, then by stage,
+/// and a path can have either one stage 0 entry or conflicted entries in stages 1-3
+fn check_index(items: &[IndexFile]) -> Vec<String> {
+    let mut problems = Vec::new();
+    let key = |item: &IndexFile| (item.filename.as_bytes().to_vec(), item.stage);
+
+    for pair in items.windows(2) {
+        if key(&pair[0]) > key(&pair[1]) {
+            problems.push(format!(
+                "error: index is not sorted: '{}' (stage {}) comes after '{}' (stage {})",
+                pair[1].filename, pair[1].stage, pair[0].filename, pair[0].stage
+            ));
+        }
+    }
+
+    let mut seen = HashSet::new();
+    let mut stages: HashMap<&str, Vec<u8>> = HashMap::new();
+    for item in items.iter() {
+        if !seen.insert((item.filename.as_str(), item.stage)) {
+            problems.push(format!(
+                "error: index has duplicate entries for '{}' (stage {})",
+                item.filename, item.stage
+            ));
+        }
+        stages
+            .entry(item.filename.as_str())
+            .or_default()
+            .push(item.stage);
+    }
+    for item in items.iter().filter(|item| item.stage == 0) {
+        if stages[item.filename.as_str()]
+            .iter()
+            .any(|stage| *stage != 0)
+        {
+            problems.push(format!(
+                "error: index has both merged and conflicted entries for '{}'",
+                item.filename
+            ));
+        }
+    }
+
+    problems
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn to_args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|a| a.to_string()).collect()
+    }
+
+    fn entry(name: &str, stage: u8) -> IndexFile {
+        IndexFile::new(
+            name,
+            0o100644,
+            "e69de29bb2d1d6434b8b29ae775ad8c2e48c5391",
+            stage,
+        )
+    }
+
+    #[test]
+    fn test_parse_args() {
+        assert_eq!(
+            Ok(FsckOptions { index_only: true }),
+            parse_args(&to_args(&["--index"]))
+        );
+        assert_eq!(Ok(FsckOptions::default()), parse_args(&[]));
+        assert!(parse_args(&to_args(&["--full"])).is_err());
+    }
+
+    #[test]
+    fn test_check_index_sorted() {
+        let items = vec![
+            entry("a", 0),
+            entry("b", 1),
+            entry("b", 2),
+            entry("b.txt", 0),
+            entry("b/c", 0),
+        ];
+        assert!(check_index(&items).is_empty());
+    }
+
+    #[test]
+    fn test_check_index_problems() {
+        let items = vec![
+            entry("b", 0),
+            entry("a", 0),
+            entry("c", 0),
+            entry("c", 0),
+            entry("d", 2),
+            entry("d", 1),
+            entry("e", 0),
+            entry("e", 3),
+        ];
+        assert_eq!(
+            vec![
+                "error: index is not sorted: 'a' (stage 0) comes after 'b' (stage 0)",
+                "error: index is not sorted: 'd' (stage 1) comes after 'd' (stage 2)",
+                "error: index has duplicate entries for 'c' (stage 0)",
+                "error: index has both merged and conflicted entries for 'e'",
+            ],
+            check_index(&items)
+        );
+    }
+}
@@ -1,49 +1,54 @@
 use super::object_util;
-use sha1::Sha1;
-use std::fs;
-use std::str;
+use super::object_util::Object;
+use std::fs::File;
 
 /// Returns the SHA1 hash of the passed file.
+///
+/// The file is hashed a chunk at a time, so large files aren't read into memory.
 pub fn generate_hash(filepath: &str) -> std::string::String {
-    let file = fs::read(filepath).expect("Unable to read file");
-    // Assume a valid UTF-8 file
-    let content = str::from_utf8(&file).unwrap();
-
-    let store = create_store(content);
-    Sha1::from(store.as_bytes()).hexdigest()
+    let (file, size) = open_file(filepath);
+    object_util::hash_object_stream(Object::Blob, size, file).expect("Unable to read file")
 }
 
 /// Like `hash_object` returns the SHA1 hash of the passed file,
 /// but also writes the object to the object database.
+///
+/// The file is hashed and compressed a chunk at a time, so large files aren't read into memory.
 pub fn write_hash_object(filepath: &str) -> std::string::String {
-    let file = fs::read(filepath).expect("Unable to read file");
-    // Assume a valid UTF-8 file
-    let content = str::from_utf8(&file).unwrap();
-
-    let store = create_store(content);
-    let hash = Sha1::from(store.as_bytes()).hexdigest();
-
-    object_util::write_object_file(&hash, store.as_bytes());
-
-    hash
+    let (file, size) = open_file(filepath);
+    object_util::write_object_stream(Object::Blob, size, file).expect("Unable to write object")
 }
 
-/// Creates the store that can be used to generate an object hash
-fn create_store(content: &str) -> String {
-    // Assume type is blob if not specified
-    let object_type = "blob";
-    let header = format!("{} {}", object_type, content.len());
-    format!("{}\0{}", header, content)
+/// Opens a file to be read as a blob, along with its size
+fn open_file(filepath: &str) -> (File, u64) {
+    let file = File::open(filepath).expect("Unable to read file");
+    let size = file.metadata().expect("Unable to read file").len();
+    (file, size)
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::hash_object::create_store;
+    use super::*;
+    use std::env;
+    use std::fs;
+    use std::process;
 
     #[test]
-    fn test_create_store_blob() {
-        let content = "Hello, World";
-        let store = "blob 12\0Hello, World";
-        assert_eq!(store, create_store(content));
+    fn test_generate_hash_blob() {
+        let path = env::temp_dir().join(format!("gitrs-hash-object-test-{}", process::id()));
+        let path = path.to_str().unwrap();
+
+        fs::write(path, "Hello, World").unwrap();
+        assert_eq!(
+            "1856e9be02756984c385482a07e42f42efd5d2f3",
+            generate_hash(path)
+        );
+
+        fs::write(path, b"\xff\x00\xfe\x89PNG\r\n").unwrap();
+        assert_eq!(
+            "8011e4dc51aa0074c21a543916b2d34d47a420e2",
+            generate_hash(path)
+        );
+        fs::remove_file(path).unwrap();
     }
 }
@@ -0,0 +1,461 @@
+use super::config_util;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+
+// https://git-scm.com/docs/gitignore
+
+const EXCLUDE_PATH: &str = "gitrs/info/exclude";
+
+/// A single pattern from an exclude file
+#[derive(Debug, Clone, PartialEq)]
+pub struct Pattern {
+    /// The glob to match, without any `!` prefix, leading `/` or trailing `/`
+    glob: String,
+    /// The pattern started with `!` and re-includes paths excluded by earlier patterns
+    pub negated: bool,
+    /// The pattern ended with `/` and only matches directories
+    dir_only: bool,
+    /// The pattern contains a `/` and is matched against the path relative to `base`
+    /// instead of just the file name
+    anchored: bool,
+    /// The directory of the `.gitignore` the pattern came from, empty for the top level
+    base: String,
+    /// The file the pattern came from
+    pub source: String,
+    /// The line number of the pattern in `source`, starting at 1
+    pub line: usize,
+    /// The pattern as written
+    pub text: String,
+}
+
+/// The ignore patterns for a repository
+///
+/// Patterns are read from `core.excludesFile`, `gitrs/info/exclude`, and every `.gitignore`
+/// from the top of the working tree down to the directory of the path being checked.
+/// Later patterns take precedence, so a `.gitignore` in a subdirectory can override its parents.
+#[derive(Debug, Default)]
+pub struct IgnoreRules {
+    global: Vec<Pattern>,
+    /// `.gitignore` patterns, keyed by directory, read as they are needed
+    per_dir: RefCell<HashMap<String, Vec<Pattern>>>,
+}
+
+impl IgnoreRules {
+    /// Loads the patterns from `core.excludesFile` and `gitrs/info/exclude`
+    ///
+    /// `.gitignore` files are read when a path in their directory is checked
+    pub fn load() -> IgnoreRules {
+        let mut global = Vec::new();
+
+        if let Some(path) = config_util::get_value("core.excludesFile") {
+            let path = expand_home(&path);
+            if let Ok(content) = fs::read_to_string(&path) {
+                global.append(&mut parse_patterns(&content, "", &path));
+            }
+        }
+        if let Ok(content) = fs::read_to_string(EXCLUDE_PATH) {
+            global.append(&mut parse_patterns(&content, "", EXCLUDE_PATH));
+        }
+
+        IgnoreRules {
+            global,
+            per_dir: RefCell::new(HashMap::new()),
+        }
+    }
+
+    /// Returns true if the path (relative to the top of the working tree) is ignored
+    ///
+    /// A path inside an ignored directory is always ignored, since git never looks inside it
+    pub fn is_ignored(&self, path: &str, is_dir: bool) -> bool {
+        match self.matching_pattern(path, is_dir) {
+            Some(p) => !p.negated,
+            None => false,
+        }
+    }
+
+    /// Returns the pattern that decides whether the path is ignored, if any
+    ///
+    /// If the pattern is negated the path is not ignored.
+    /// A path inside an ignored directory gets the pattern that ignored the directory
+    pub fn matching_pattern(&self, path: &str, is_dir: bool) -> Option<Pattern> {
+        let path = path.trim_end_matches('/');
+
+        let mut end = 0;
+        while let Some(i) = path[end..].find('/') {
+            let dir = &path[..end + i];
+            if let Some(p) = self.last_matching_pattern(dir, true) {
+                if !p.negated {
+                    return Some(p);
+                }
+            }
+            end += i + 1;
+        }
+
+        self.last_matching_pattern(path, is_dir)
+    }
+
+    /// Returns the last pattern that matches the path itself, if any
+    ///
+    /// Parent directories are not checked, see [`IgnoreRules::matching_pattern`]
+    fn last_matching_pattern(&self, path: &str, is_dir: bool) -> Option<Pattern> {
+        let path = path.trim_end_matches('/');
+        let name = path.rsplit('/').next().unwrap_or(path);
+
+        let mut found = self
+            .global
+            .iter()
+            .rev()
+            .find(|p| p.matches(path, name, is_dir))
+            .cloned();
+
+        // Check .gitignore files from the top level down to the path's directory
+        let mut dirs = vec![String::new()];
+        let mut end = 0;
+        while let Some(i) = path[end..].find('/') {
+            dirs.push(path[..end + i].to_string());
+            end += i + 1;
+        }
+
+        for dir in dirs.iter() {
+            self.load_dir(dir);
+            let per_dir = self.per_dir.borrow();
+            if let Some(p) = per_dir[dir]
+                .iter()
+                .rev()
+                .find(|p| p.matches(path, name, is_dir))
+            {
+                found = Some(p.clone());
+            }
+        }
+
+        found
+    }
+
+    /// Reads the `.gitignore` in a directory, if it hasn't been read yet
+    fn load_dir(&self, dir: &str) {
+        if self.per_dir.borrow().contains_key(dir) {
+            return;
+        }
+
+        let source = if dir.is_empty() {
+            ".gitignore".to_string()
+        } else {
+            format!("{}/.gitignore", dir)
+        };
+        let patterns = match fs::read_to_string(&source) {
+            Ok(content) => parse_patterns(&content, dir, &source),
+            Err(_) => Vec::new(),
+        };
+        self.per_dir.borrow_mut().insert(dir.to_string(), patterns);
+    }
+}
+
+impl Pattern {
+    /// Returns true if the pattern matches the path
+    ///
+    /// `path` is relative to the top of the working tree, and `name` is its last component
+    fn matches(&self, path: &str, name: &str, is_dir: bool) -> bool {
+        if self.dir_only && !is_dir {
+            return false;
+        }
+
+        let relative = if self.base.is_empty() {
+            path
+        } else {
+            match path.strip_prefix(&self.base) {
+                Some(rest) if rest.starts_with('/') => &rest[1..],
+                _ => return false,
+            }
+        };
+
+        if self.anchored {
+            glob_match(&self.glob, relative)
+        } else {
+            glob_match(&self.glob, name)
+        }
+    }
+}
+
+/// Parses the lines of an exclude file into patterns
+///
+/// # Format
+///
+/// * Blank lines and lines starting with `#` are skipped, `\#` starts a pattern with `#`
+/// * Trailing spaces are ignored unless escaped with `\`
+/// * A leading `!` negates the pattern, `\!` starts a pattern with `!`
+/// * A trailing `/` only matches directories
+/// * A `/` anywhere else anchors the pattern to the directory of the file
+fn parse_patterns(content: &str, base: &str, source: &str) -> Vec<Pattern> {
+    let mut patterns = Vec::new();
+    for (i, line) in content.lines().enumerate() {
+        let text = trim_trailing_spaces(line);
+        if text.is_empty() || text.starts_with('#') {
+            continue;
+        }
+
+        let mut glob = text;
+        let negated = glob.starts_with('!');
+        if negated {
+            glob = &glob[1..];
+        }
+
+        let dir_only = glob.ends_with('/');
+        let glob = glob.trim_end_matches('/');
+        let anchored = glob.contains('/');
+        let glob = glob.strip_prefix('/').unwrap_or(glob);
+
+        if glob.is_empty() {
+            continue;
+        }
+
+        patterns.push(Pattern {
+            glob: glob.to_string(),
+            negated,
+            dir_only,
+            anchored,
+            base: base.to_string(),
+            source: source.to_string(),
+            line: i + 1,
+            text: text.to_string(),
+        });
+    }
+    patterns
+}
+
+/// Removes trailing spaces, except one escaped with a backslash
+fn trim_trailing_spaces(line: &str) -> &str {
+    let trimmed = line.trim_end_matches(' ');
+    if trimmed.ends_with('\\') && trimmed.len() < line.len() {
+        &line[..trimmed.len() + 1]
+    } else {
+        trimmed
+    }
+}
+
+/// Expands a leading `~/` to the home directory
+fn expand_home(path: &str) -> String {
+    match (path.strip_prefix("~/"), env::var("HOME")) {
+        (Some(rest), Ok(home)) => format!("{}/{}", home, rest),
+        _ => path.to_string(),
+    }
+}
+
+/// Matches text against a glob pattern
+///
+/// * `*` matches any run of characters except `/`
+/// * `?` matches a single character except `/`
+/// * `[...]` matches one character in the set, `[!...]` one character not in the set
+/// * `**/` matches zero or more directories, and a trailing `/**` matches everything inside
+/// * `\` makes the next character literal
+pub fn glob_match(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    glob_match_from(&pattern, &text, true)
+}
+
+/// Matches text against a pattern like [`glob_match`], but `/` isn't special
+///
+/// `*`, `?` and `[...]` match `/` too, as in `git tag -l` patterns
+pub fn fnmatch(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    glob_match_from(&pattern, &text, false)
+}
+
+/// Matches the rest of a pattern, where `pathname` stops wildcards from matching `/`
+fn glob_match_from(pattern: &[char], text: &[char], pathname: bool) -> bool {
+    let matches = |c: &char| !pathname || *c != '/';
+    match pattern.first() {
+        None => text.is_empty(),
+        Some('*') if pathname && pattern.get(1) == Some(&'*') => {
+            let rest = &pattern[2..];
+            match rest.first() {
+                // A trailing `**` matches everything
+                None => true,
+                // `**/` matches zero or more whole directories
+                Some('/') => {
+                    let rest = &rest[1..];
+                    glob_match_from(rest, text, pathname)
+                        || (0..text.len())
+                            .filter(|&i| text[i] == '/')
+                            .any(|i| glob_match_from(rest, &text[i + 1..], pathname))
+                }
+                // Otherwise `**` is the same as `*`
+                Some(_) => glob_match_from(&pattern[1..], text, pathname),
+            }
+        }
+        Some('*') => {
+            // Try every split point, `*` can match nothing
+            (0..=text.len())
+                .take_while(|&i| i == 0 || matches(&text[i - 1]))
+                .any(|i| glob_match_from(&pattern[1..], &text[i..], pathname))
+        }
+        Some('?') => match text.first() {
+            Some(c) if matches(c) => glob_match_from(&pattern[1..], &text[1..], pathname),
+            _ => false,
+        },
+        Some('[') => match (text.first(), match_bracket(pattern, text.first())) {
+            (Some(c), Some((true, len))) if matches(c) => {
+                glob_match_from(&pattern[len..], &text[1..], pathname)
+            }
+            (_, Some(_)) => false,
+            // An unclosed `[` is a literal
+            (Some('['), None) => glob_match_from(&pattern[1..], &text[1..], pathname),
+            (_, None) => false,
+        },
+        Some('\\') if pattern.len() > 1 => match text.first() {
+            Some(c) if *c == pattern[1] => glob_match_from(&pattern[2..], &text[1..], pathname),
+            _ => false,
+        },
+        Some(p) => match text.first() {
+            Some(c) if c == p => glob_match_from(&pattern[1..], &text[1..], pathname),
+            _ => false,
+        },
+    }
+}
+
+/// Matches a character against a bracket expression at the start of the pattern
+///
+/// Returns whether it matched and the length of the expression, or `None` if the bracket isn't closed
+fn match_bracket(pattern: &[char], c: Option<&char>) -> Option<(bool, usize)> {
+    let mut i = 1;
+    let negated = matches!(pattern.get(i), Some('!') | Some('^'));
+    if negated {
+        i += 1;
+    }
+
+    let mut matched = false;
+    let mut first = true;
+    while i < pattern.len() {
+        if pattern[i] == ']' && !first {
+            let matched = c.is_some() && matched != negated;
+            return Some((matched, i + 1));
+        }
+        first = false;
+
+        let low = pattern[i];
+        if pattern.get(i + 1) == Some(&'-') && pattern.get(i + 2).is_some_and(|h| *h != ']') {
+            let high = pattern[i + 2];
+            if let Some(c) = c {
+                matched |= low <= *c && *c <= high;
+            }
+            i += 3;
+        } else {
+            matched |= c == Some(&low);
+            i += 1;
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rules(content: &str) -> IgnoreRules {
+        IgnoreRules {
+            global: parse_patterns(content, "", ".gitignore"),
+            per_dir: RefCell::new(HashMap::new()),
+        }
+    }
+
+    #[test]
+    fn test_glob_match() {
+        assert!(glob_match("*.o", "main.o"));
+        assert!(!glob_match("*.o", "main.c"));
+        assert!(glob_match("?.txt", "a.txt"));
+        assert!(!glob_match("*.o", "dir/main.o"));
+    }
+
+    #[test]
+    fn test_glob_match_double_star() {
+        assert!(glob_match("**/foo", "foo"));
+        assert!(glob_match("**/foo", "a/b/foo"));
+        assert!(glob_match("abc/**", "abc/x/y"));
+        assert!(glob_match("a/**/b", "a/b"));
+        assert!(glob_match("a/**/b", "a/x/y/b"));
+        assert!(!glob_match("a/**/b", "a/x/c"));
+    }
+
+    #[test]
+    fn test_glob_match_brackets() {
+        assert!(glob_match("*.[oa]", "lib.a"));
+        assert!(!glob_match("*.[oa]", "lib.c"));
+        assert!(glob_match("[a-c]x", "bx"));
+        assert!(glob_match("[!a-c]x", "dx"));
+        assert!(glob_match("\\*x", "*x"));
+    }
+
+    #[test]
+    fn test_fnmatch() {
+        assert!(fnmatch("v1.*", "v1.0/rc1"));
+        assert!(fnmatch("release?1", "release/1"));
+        assert!(fnmatch("a**b", "a/x/b"));
+        assert!(!fnmatch("v2*", "v1.0"));
+    }
+
+    #[test]
+    fn test_parse_patterns() {
+        let patterns = parse_patterns("# comment\n\n!/build/\n\\#file \\ \n", "", ".gitignore");
+        assert_eq!(2, patterns.len());
+        assert!(patterns[0].negated);
+        assert!(patterns[0].dir_only);
+        assert!(patterns[0].anchored);
+        assert_eq!("build", patterns[0].glob);
+        assert_eq!(3, patterns[0].line);
+        assert_eq!("\\#file \\ ", patterns[1].glob);
+    }
+
+    #[test]
+    fn test_is_ignored_dir_only() {
+        let rules = rules("target/\n*.log\n");
+        assert!(rules.is_ignored("target", true));
+        assert!(!rules.is_ignored("target", false));
+        assert!(rules.is_ignored("logs/debug.log", false));
+    }
+
+    #[test]
+    fn test_is_ignored_anchored() {
+        let rules = rules("/build/out\n/root.txt\n");
+        assert!(rules.is_ignored("build/out", true));
+        assert!(!rules.is_ignored("src/build/out", true));
+        assert!(rules.is_ignored("root.txt", false));
+        assert!(!rules.is_ignored("src/root.txt", false));
+    }
+
+    #[test]
+    fn test_is_ignored_negation() {
+        let rules = rules("*.log\n!keep.log\n");
+        assert!(rules.is_ignored("debug.log", false));
+        assert!(!rules.is_ignored("keep.log", false));
+    }
+
+    #[test]
+    fn test_is_ignored_inside_ignored_dir() {
+        // A file can't be re-included if its parent directory is excluded
+        let rules = rules("build/\n!build/keep.txt\n");
+        assert!(rules.is_ignored("build/keep.txt", false));
+
+        let pattern = rules.matching_pattern("build/keep.txt", false).unwrap();
+        assert!(!pattern.negated);
+        assert_eq!(1, pattern.line);
+    }
+
+    #[test]
+    fn test_pattern_with_base() {
+        let patterns = parse_patterns("/out\n*.tmp\n", "src", "src/.gitignore");
+        assert!(patterns[0].matches("src/out", "out", false));
+        assert!(!patterns[0].matches("out", "out", false));
+        assert!(!patterns[0].matches("src/a/out", "out", false));
+        assert!(patterns[1].matches("src/a/x.tmp", "x.tmp", false));
+        assert!(!patterns[1].matches("x.tmp", "x.tmp", false));
+    }
+
+    #[test]
+    fn test_trim_trailing_spaces() {
+        assert_eq!("a", trim_trailing_spaces("a  "));
+        assert_eq!("a\\ ", trim_trailing_spaces("a\\   "));
+    }
+}
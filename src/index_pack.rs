@@ -0,0 +1,435 @@
+use super::delta_util;
+use super::object_util;
+use super::object_util::Object;
+use super::pack_util;
+use super::pack_util::{DeltaBase, PackIndexEntry};
+use flate2::bufread::ZlibDecoder;
+use flate2::Crc;
+use sha1::Sha1;
+use std::collections::HashMap;
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::{BufRead, BufReader, Read};
+use std::rc::Rc;
+
+// https://git-scm.com/docs/git-index-pack
+
+/// Options for `index-pack`
+#[derive(Debug, Default, PartialEq)]
+pub struct IndexPackOptions {
+    pub pack_path: String,
+    /// Where to write the index, next to the pack with an `.idx` extension by default
+    pub idx_path: Option<String>,
+}
+
+/// An entry found while reading through a pack
+#[derive(Debug, PartialEq)]
+struct IndexedEntry {
+    offset: u64,
+    crc: u32,
+    base: Option<DeltaBase>,
+    /// The object hash, known straight away for whole objects and once resolved for deltas
+    hash: Option<String>,
+}
+
+/// Builds the `.idx` for a pack, checking the pack along the way
+///
+/// The pack is read once from start to end, hashing whole objects as they are inflated.
+/// Deltas are then resolved from each base outwards to find their hashes.
+///
+/// Returns the checksum of the pack
+pub fn index_pack(options: &IndexPackOptions) -> Result<String, String> {
+    let pack_path = &options.pack_path;
+    if !pack_path.ends_with(".pack") && options.idx_path.is_none() {
+        return Err(format!(
+            "fatal: packfile name '{}' does not end with '.pack'",
+            pack_path
+        ));
+    }
+    let file = File::open(pack_path)
+        .map_err(|e| format!("fatal: cannot open packfile '{}': {}", pack_path, e))?;
+
+    let (mut entries, checksum) = read_pack_stream(BufReader::new(file))?;
+    resolve_deltas(pack_path, &mut entries)?;
+
+    let mut index_entries = Vec::with_capacity(entries.len());
+    for entry in entries.iter() {
+        let hash = entry.hash.as_deref().unwrap_or_default();
+        index_entries.push(PackIndexEntry {
+            hash: object_util::from_hex_string(hash).unwrap_or_default(),
+            offset: entry.offset,
+            crc: entry.crc,
+        });
+    }
+
+    let idx_path = match &options.idx_path {
+        Some(path) => path.to_owned(),
+        None => format!("{}.idx", pack_path.trim_end_matches(".pack")),
+    };
+    let idx = pack_util::build_index(&mut index_entries, &checksum);
+    // Like `write_pack`, a half written index is never left where it would be used
+    let tmp_idx_path = format!("{}.tmp", idx_path);
+    fs::write(&tmp_idx_path, idx)
+        .and_then(|_| fs::rename(&tmp_idx_path, &idx_path))
+        .map_err(|e| {
+            let _ = fs::remove_file(&tmp_idx_path);
+            format!("fatal: unable to write {}: {}", idx_path, e)
+        })?;
+
+    Ok(format!("{}\n", object_util::to_hex_string(&checksum)))
+}
+
+/// Parse arguments for `index-pack`
+pub fn parse_args(args: &[String]) -> Result<IndexPackOptions, String> {
+    let mut options = IndexPackOptions::default();
+    let mut paths = Vec::new();
+
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "-o" => match iter.next() {
+                Some(path) => options.idx_path = Some(path.to_owned()),
+                None => return Err("fatal: -o requires a file name".to_string()),
+            },
+            _ if arg.starts_with('-') => {
+                return Err(format!("{} is not recognized as a valid option", arg))
+            }
+            _ => paths.push(arg.to_owned()),
+        }
+    }
+
+    match paths.len() {
+        1 => {
+            options.pack_path = paths.remove(0);
+            Ok(options)
+        }
+        _ => Err("usage: index-pack [-o <index-file>] <pack-file>".to_string()),
+    }
+}
+
+/// Reads a pack in one pass, returning its entries and checksum
+///
+/// Whole objects are hashed as they are inflated, deltas are left to be resolved
+fn read_pack_stream<R: BufRead>(reader: R) -> Result<(Vec<IndexedEntry>, Vec<u8>), String> {
+    let mut stream = PackStream {
+        inner: reader,
+        hasher: Sha1::new(),
+        crc: Crc::new(),
+        offset: 0,
+    };
+    let truncated = |_| "fatal: premature end of pack file".to_string();
+
+    let mut header = [0u8; 12];
+    stream.read_exact(&mut header).map_err(truncated)?;
+    if &header[..4] != b"PACK" {
+        return Err("fatal: pack signature mismatch".to_string());
+    }
+    let version = u32::from_be_bytes([header[4], header[5], header[6], header[7]]);
+    if version != 2 && version != 3 {
+        return Err(format!("fatal: pack version {} unsupported", version));
+    }
+    let count = u32::from_be_bytes([header[8], header[9], header[10], header[11]]);
+
+    // The count and sizes come from the pack, so buffers grow with the data actually read
+    let mut entries = Vec::new();
+    for _ in 0..count {
+        let offset = stream.offset;
+        stream.crc.reset();
+        let corrupt = |what: &str| format!("fatal: {} at offset {}", what, offset);
+
+        // The header is at most 10 bytes, each with a continuation bit
+        let mut entry_header = vec![stream.read_byte().map_err(truncated)?];
+        while entry_header.last().is_some_and(|b| b & 0x80 != 0) && entry_header.len() < 10 {
+            entry_header.push(stream.read_byte().map_err(truncated)?);
+        }
+        let (type_code, size, _) = pack_util::parse_entry_header(&entry_header)
+            .ok_or_else(|| corrupt("bad entry header"))?;
+
+        let base = match type_code {
+            pack_util::OBJ_OFS_DELTA => {
+                let mut bytes = vec![stream.read_byte().map_err(truncated)?];
+                while bytes.last().is_some_and(|b| b & 0x80 != 0) && bytes.len() < 10 {
+                    bytes.push(stream.read_byte().map_err(truncated)?);
+                }
+                let (distance, _) = pack_util::parse_ofs_distance(&bytes)
+                    .ok_or_else(|| corrupt("bad delta offset"))?;
+                let base_offset = offset
+                    .checked_sub(distance)
+                    .filter(|_| distance != 0)
+                    .ok_or_else(|| corrupt("delta base offset out of bound"))?;
+                Some(DeltaBase::Offset(base_offset))
+            }
+            pack_util::OBJ_REF_DELTA => {
+                let mut hash = [0u8; 20];
+                stream.read_exact(&mut hash).map_err(truncated)?;
+                Some(DeltaBase::Hash(object_util::to_hex_string(&hash)))
+            }
+            _ => None,
+        };
+
+        let mut data = Vec::with_capacity(size.min(pack_util::MAX_PREALLOC) as usize);
+        ZlibDecoder::new(&mut stream)
+            .take(size.saturating_add(1))
+            .read_to_end(&mut data)
+            .map_err(|_| corrupt("inflate returned an error"))?;
+        if data.len() as u64 != size {
+            return Err(corrupt("size mismatch"));
+        }
+
+        let hash = match base {
+            Some(_) => None,
+            None => {
+                let object_type = pack_util::type_from_code(type_code)
+                    .map_err(|_| corrupt(&format!("unknown object type {}", type_code)))?;
+                Some(object_util::hash_object(object_type, &data))
+            }
+        };
+        entries.push(IndexedEntry {
+            offset,
+            crc: stream.crc.sum(),
+            base,
+            hash,
+        });
+    }
+
+    let computed = stream.hasher.digest().bytes();
+    let mut checksum = vec![0u8; 20];
+    stream.read_exact(&mut checksum).map_err(truncated)?;
+    if computed[..] != checksum[..] {
+        return Err("fatal: pack is corrupted (SHA1 mismatch)".to_string());
+    }
+    if !stream
+        .inner
+        .fill_buf()
+        .map_err(|e| e.to_string())?
+        .is_empty()
+    {
+        return Err("fatal: pack has junk at the end".to_string());
+    }
+    Ok((entries, checksum))
+}
+
+/// An entry whose deltas can be applied, with its type and full content
+type ResolvedBase = (usize, Object, Rc<Vec<u8>>);
+
+/// Finds the hash of each delta by applying it to its base, starting from each whole object
+///
+/// Bases outside the pack are looked up in the object database, so thin packs can be indexed
+fn resolve_deltas(pack_path: &str, entries: &mut [IndexedEntry]) -> Result<(), String> {
+    let mut by_offset: HashMap<u64, Vec<usize>> = HashMap::new();
+    let mut by_hash: HashMap<String, Vec<usize>> = HashMap::new();
+    for (i, entry) in entries.iter().enumerate() {
+        match &entry.base {
+            Some(DeltaBase::Offset(offset)) => by_offset.entry(*offset).or_default().push(i),
+            Some(DeltaBase::Hash(hash)) => by_hash.entry(hash.clone()).or_default().push(i),
+            None => (),
+        }
+    }
+
+    // Bases still waiting for their deltas to be applied
+    let mut stack: Vec<ResolvedBase> = Vec::new();
+    let mut roots: Vec<usize> = (0..entries.len())
+        .filter(|i| entries[*i].base.is_none())
+        .collect();
+    roots.reverse();
+
+    loop {
+        let (parent, object_type, content) = match stack.pop() {
+            Some(base) => base,
+            None => match roots.pop() {
+                Some(i) => {
+                    let entry = &entries[i];
+                    let has_children = by_offset.contains_key(&entry.offset)
+                        || entry.hash.as_ref().is_some_and(|h| by_hash.contains_key(h));
+                    if !has_children {
+                        continue;
+                    }
+                    let packed = pack_util::read_entry(pack_path, entry.offset)?;
+                    let object_type = pack_util::type_from_code(packed.type_code)?;
+                    (i, object_type, Rc::new(packed.data))
+                }
+                None => {
+                    // Only bases outside the pack are left
+                    let thin = by_hash
+                        .keys()
+                        .find(|h| object_util::object_exists(h))
+                        .cloned();
+                    match thin {
+                        Some(hash) => {
+                            let (object_type, content) = object_util::read_object(&hash);
+                            for child in by_hash.remove(&hash).unwrap_or_default() {
+                                stack.push(apply_child(
+                                    pack_path,
+                                    entries,
+                                    child,
+                                    object_type,
+                                    &content,
+                                )?);
+                            }
+                            continue;
+                        }
+                        None => break,
+                    }
+                }
+            },
+        };
+
+        let mut children = by_offset
+            .remove(&entries[parent].offset)
+            .unwrap_or_default();
+        if let Some(hash) = &entries[parent].hash {
+            children.extend(by_hash.remove(hash).unwrap_or_default());
+        }
+        for child in children {
+            stack.push(apply_child(
+                pack_path,
+                entries,
+                child,
+                object_type,
+                &content,
+            )?);
+        }
+    }
+
+    let unresolved = entries.iter().filter(|e| e.hash.is_none()).count();
+    if unresolved > 0 {
+        return Err(format!("fatal: pack has {} unresolved deltas", unresolved));
+    }
+    Ok(())
+}
+
+/// Applies a delta entry to its base content, recording the hash of the result
+fn apply_child(
+    pack_path: &str,
+    entries: &mut [IndexedEntry],
+    child: usize,
+    object_type: Object,
+    base: &[u8],
+) -> Result<ResolvedBase, String> {
+    let delta = pack_util::read_entry(pack_path, entries[child].offset)?.data;
+    let content = delta_util::apply_delta(base, &delta)?;
+    entries[child].hash = Some(object_util::hash_object(object_type, &content));
+    Ok((child, object_type, Rc::new(content)))
+}
+
+/// Reads a pack, hashing every byte read and the CRC32 of the current entry
+///
+/// Only the bytes the zlib decoder actually uses are consumed, so each entry
+/// starts exactly where the previous one ended
+struct PackStream<R: BufRead> {
+    inner: R,
+    hasher: Sha1,
+    crc: Crc,
+    offset: u64,
+}
+
+impl<R: BufRead> PackStream<R> {
+    fn read_byte(&mut self) -> io::Result<u8> {
+        let mut byte = [0u8; 1];
+        self.read_exact(&mut byte)?;
+        Ok(byte[0])
+    }
+}
+
+impl<R: BufRead> Read for PackStream<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let available = self.fill_buf()?;
+        let n = available.len().min(buf.len());
+        buf[..n].copy_from_slice(&available[..n]);
+        self.consume(n);
+        Ok(n)
+    }
+}
+
+impl<R: BufRead> BufRead for PackStream<R> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        self.inner.fill_buf()
+    }
+
+    fn consume(&mut self, amt: usize) {
+        // The bytes are already buffered, so this doesn't read anything new
+        if let Ok(available) = self.inner.fill_buf() {
+            let used = &available[..amt.min(available.len())];
+            self.hasher.update(used);
+            self.crc.update(used);
+        }
+        self.offset += amt as u64;
+        self.inner.consume(amt);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::write::ZlibEncoder;
+    use std::io::Write;
+
+    fn to_args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|a| a.to_string()).collect()
+    }
+
+    fn compress(data: &[u8]) -> Vec<u8> {
+        let mut encoder = ZlibEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    #[test]
+    fn test_parse_args() {
+        assert_eq!(
+            Ok(IndexPackOptions {
+                pack_path: "in.pack".to_string(),
+                idx_path: Some("out.idx".to_string()),
+            }),
+            parse_args(&to_args(&["-o", "out.idx", "in.pack"]))
+        );
+        assert!(parse_args(&[]).is_err());
+    }
+
+    #[test]
+    fn test_read_pack_stream() {
+        let mut pack = b"PACK\0\0\0\x02\0\0\0\x02".to_vec();
+        pack.extend(pack_util::encode_entry_header(pack_util::OBJ_BLOB, 0));
+        pack.extend(compress(b""));
+        let delta_offset = pack.len() as u64;
+        // A delta from the empty blob inserting "a"
+        pack.extend(pack_util::encode_entry_header(pack_util::OBJ_OFS_DELTA, 4));
+        pack.extend(pack_util::encode_ofs_distance(delta_offset - 12));
+        pack.extend(compress(&[0, 1, 1, b'a']));
+        let checksum = Sha1::from(&pack).digest().bytes();
+        pack.extend_from_slice(&checksum);
+
+        let (entries, read_checksum) = read_pack_stream(&pack[..]).unwrap();
+        assert_eq!(checksum.to_vec(), read_checksum);
+        assert_eq!(2, entries.len());
+        assert_eq!(
+            Some("e69de29bb2d1d6434b8b29ae775ad8c2e48c5391".to_string()),
+            entries[0].hash
+        );
+        assert_eq!(delta_offset, entries[1].offset);
+        assert_eq!(Some(DeltaBase::Offset(12)), entries[1].base);
+    }
+
+    #[test]
+    fn test_read_pack_stream_bad_checksum() {
+        let mut pack = b"PACK\0\0\0\x02\0\0\0\0".to_vec();
+        pack.extend_from_slice(&[0u8; 20]);
+        assert_eq!(
+            Err("fatal: pack is corrupted (SHA1 mismatch)".to_string()),
+            read_pack_stream(&pack[..])
+        );
+    }
+
+    #[test]
+    fn test_read_pack_stream_huge_sizes() {
+        // Neither the object count nor the entry size is allocated up front
+        let mut pack = b"PACK\0\0\0\x02\xff\xff\xff\xff".to_vec();
+        pack.extend(pack_util::encode_entry_header(pack_util::OBJ_BLOB, 1 << 50));
+        pack.extend(compress(b"small"));
+        assert_eq!(
+            Err("fatal: size mismatch at offset 12".to_string()),
+            read_pack_stream(&pack[..])
+        );
+    }
+}
@@ -1,16 +1,24 @@
+use super::cache_tree_util::CacheTree;
+use super::hash_object;
+use super::lock_util::LockFile;
+use super::ls_tree::TreeEntry;
 use super::object_util;
+use super::pack_util;
+use super::resolve_undo_util;
+use super::resolve_undo_util::ResolveUndo;
 use sha1::Sha1;
-use std::fs::OpenOptions;
-use std::io::{Read, Write};
+use std::collections::{HashMap, HashSet};
+use std::fmt;
 use std::path::Path;
 use std::str;
+use std::time::{Duration, SystemTime};
 use std::{fs, io};
 
 const INDEX_PATH: &str = "gitrs/index";
 
+/// The header of an index file, after its `DIRC` signature
 #[derive(Debug)]
 pub struct IndexHeader {
-    pub magic: String,
     pub version: u32,
     pub num_files: u32,
 }
@@ -23,281 +31,914 @@ pub struct IndexFile {
     pub mtime_fractions: u32,
     pub dev: Option<u32>,
     pub ino: Option<u32>,
-    pub permissions: String,
+    /// The object type and permissions, 0o100644, 0o100755, 0o120000 for a symlink or 0o160000 for a gitlink
+    pub mode: u32,
     pub uid: Option<u32>,
     pub gid: Option<u32>,
     pub size: u32,
     pub object_hash: String,
     pub filename: String,
+    /// Set by `update-index --assume-unchanged`, git skips checking the file for changes
+    pub assume_valid: bool,
+    /// Set by `update-index --skip-worktree`, git leaves the working tree file alone, from version 3
+    pub skip_worktree: bool,
+    /// Set by `add -N`, the path is tracked but its content isn't staged yet, from version 3
+    pub intent_to_add: bool,
+    /// Merge stage, 0 for a normal entry or 1-3 for the base, ours and theirs versions of a conflict
+    pub stage: u8,
+}
+
+impl IndexFile {
+    /// Creates an entry with no stat data, as if the file had never been checked out
+    pub fn new(filename: &str, mode: u32, object_hash: &str, stage: u8) -> IndexFile {
+        IndexFile {
+            ctime: 0,
+            ctime_fractions: 0,
+            mtime: 0,
+            mtime_fractions: 0,
+            dev: None,
+            ino: None,
+            mode,
+            uid: None,
+            gid: None,
+            size: 0,
+            object_hash: object_hash.to_string(),
+            filename: filename.to_string(),
+            assume_valid: false,
+            skip_worktree: false,
+            intent_to_add: false,
+            stage,
+        }
+    }
+}
+
+/// The index extensions that are kept when the index is written
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct IndexExtensions {
+    /// `TREE`, the tree objects last written for each directory
+    pub cache_tree: Option<CacheTree>,
+    /// `REUC`, the conflicted versions of paths that have been resolved, sorted by path
+    pub resolve_undo: Vec<ResolveUndo>,
+}
+
+/// Size of an entry before its path: ten 32-bit fields, the object hash and the flags
+const ENTRY_FIXED_SIZE: usize = 62;
+
+/// Name lengths this long or longer are stored as 0xFFF, and the name is NUL-terminated instead
+const NAME_MASK: usize = 0xFFF;
+
+/// The index versions that can be read and written
+pub const INDEX_VERSIONS: std::ops::RangeInclusive<u32> = 2..=4;
+
+/// The mode of a symlink entry, whose blob holds the path it points to
+const SYMLINK_MODE: u32 = 0o120000;
+/// The mode of a submodule entry, whose object is a commit in another repository
+pub const GITLINK_MODE: u32 = 0o160000;
+
+/// Set in the flags of an entry followed by 16 bits of extended flags, from version 3
+const EXTENDED_FLAG: u16 = 0x4000;
+/// Extended flag set by `update-index --skip-worktree`
+const SKIP_WORKTREE_FLAG: u16 = 0x4000;
+/// Extended flag set by `add -N`
+const INTENT_TO_ADD_FLAG: u16 = 0x2000;
+
+/// Why the index file couldn't be read
+#[derive(Debug, PartialEq)]
+pub enum IndexError {
+    /// The index file couldn't be opened or read
+    Io(String),
+    /// The file is too short to hold a header and checksum
+    TooShort,
+    /// The file doesn't start with `DIRC`
+    BadSignature,
+    UnsupportedVersion(u32),
+    /// The trailing SHA-1 doesn't match the rest of the file
+    BadChecksum,
+    /// The entry at `offset` in the file, the `index`th one, is malformed
+    BadEntry {
+        index: usize,
+        offset: usize,
+        reason: String,
+    },
+    /// The extension at `offset` in the file is malformed, or required but not understood
+    BadExtension {
+        signature: String,
+        offset: usize,
+        reason: String,
+    },
+}
+
+impl fmt::Display for IndexError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            IndexError::Io(e) => return write!(f, "fatal: unable to read index: {}", e),
+            IndexError::TooShort => write!(f, "error: index file smaller than expected")?,
+            IndexError::BadSignature => write!(f, "error: bad index file signature")?,
+            IndexError::UnsupportedVersion(version) => {
+                write!(f, "error: bad index version {}", version)?
+            }
+            IndexError::BadChecksum => write!(f, "error: bad index file sha1 signature")?,
+            IndexError::BadEntry {
+                index,
+                offset,
+                reason,
+            } => write!(
+                f,
+                "error: index entry {} at offset {}: {}",
+                index, offset, reason
+            )?,
+            IndexError::BadExtension {
+                signature,
+                offset,
+                reason,
+            } => write!(
+                f,
+                "error: index extension {} at offset {}: {}",
+                signature, offset, reason
+            )?,
+        }
+        write!(f, "\nfatal: index file corrupt")
+    }
+}
+
+impl From<IndexError> for String {
+    fn from(e: IndexError) -> String {
+        e.to_string()
+    }
 }
 
 /// Parse the index file and return the index header and index files
-pub fn parse_index() -> Result<(IndexHeader, Vec<IndexFile>), String> {
-    let mut file = fs::File::open(INDEX_PATH).expect("Could not read index file");
+pub fn parse_index() -> Result<(IndexHeader, Vec<IndexFile>), IndexError> {
+    read_index().map(|(header, items, _)| (header, items))
+}
+
+/// Parse the index file and return the index header, index files and extensions
+fn read_index() -> Result<(IndexHeader, Vec<IndexFile>, IndexExtensions), IndexError> {
+    let data = fs::read(INDEX_PATH).map_err(|e| IndexError::Io(e.to_string()))?;
+    parse_index_data(&data)
+}
+
+/// Parses the content of an index file into its header and entries
+///
+/// # Format
+///
+/// https://git-scm.com/docs/index-format
+///
+/// A 12-byte header, then each entry as ctime, mtime (seconds and nanoseconds), dev, ino,
+/// mode, uid, gid and size (all 32-bit), the 20 byte object hash, 16 bits of flags, then the path.
+/// From version 3 the flags can be followed by 16 bits of extended flags.
+///
+/// Up to version 3 the path is NUL-terminated and padded with NULs so the entry is a multiple of 8 bytes.
+/// Version 4 has no padding, and instead of the whole path stores how many bytes to remove from
+/// the end of the previous path, then the NUL-terminated bytes to add after it.
+///
+/// The entries are followed by extensions, each a 4 byte signature, a 32-bit size and its data,
+/// then the SHA-1 checksum of everything before it.
+fn parse_index_data(
+    data: &[u8],
+) -> Result<(IndexHeader, Vec<IndexFile>, IndexExtensions), IndexError> {
+    if data.len() < 12 + 20 {
+        return Err(IndexError::TooShort);
+    }
 
-    let mut header = [0; 12];
-    file.read_exact(&mut header).expect("Invalid index file");
+    // An all zero checksum means git was told not to compute it, with `index.skipHash`
+    let (data, checksum) = data.split_at(data.len() - 20);
+    if checksum.iter().any(|b| *b != 0) && Sha1::from(data).digest().bytes() != checksum {
+        return Err(IndexError::BadChecksum);
+    }
 
     // Check magic number (DIRC)
-    if header[..4].to_vec() != vec![0x44, 0x49, 0x52, 0x43] {
-        return Err("invalid file type, expected DIRC".to_owned());
+    if data[..4] != [0x44, 0x49, 0x52, 0x43] {
+        return Err(IndexError::BadSignature);
     }
 
-    // Check verison number (2)
-    // 3 and 4 are also valid versions, but not currently supported
-    if header[4..8].to_vec() != vec![0x00, 0x00, 0x00, 0x02] {
-        return Err("unsupported index version".to_owned());
+    let version = array_to_int(&data[4..8]);
+    if !INDEX_VERSIONS.contains(&version) {
+        return Err(IndexError::UnsupportedVersion(version));
     }
 
-    let num_file = array_to_int(&header[8..]);
+    let num_file = array_to_int(&data[8..12]);
 
-    // First 2 fields should be constant since we check for the magic 'number'
-    // and only support 1 verison
     let index_header = IndexHeader {
-        magic: String::from("DIRC"),
-        version: 2,
+        version,
         num_files: num_file,
     };
 
     let mut index_files = Vec::<IndexFile>::new();
+    let mut pos = 12;
+    let mut previous_name: Vec<u8> = Vec::new();
 
-    for _ in 0..num_file {
-        // Read 6 32-bit (24-byte) info fields
-        // ctime seconds, ctime nanosecond fractions, mtime seconds, mtime nanosecond fractions, dev (null on windows), ino (null on windows)
-        let mut info_fields = [0; 24];
-        file.read_exact(&mut info_fields)
-            .expect("Invalid index format");
-
-        let ctime = array_to_int(&info_fields[0..4]);
-        let ctime_fractions = array_to_int(&info_fields[4..8]);
-        let mtime = array_to_int(&info_fields[8..12]);
-        let mtime_fractions = array_to_int(&info_fields[12..16]);
-        let dev = array_to_int(&info_fields[16..20]);
-        let ino = array_to_int(&info_fields[20..24]);
-
-        let mut mode = [0; 2];
-        file.read_exact(&mut mode).expect("Invalid index format");
+    for index in 0..num_file as usize {
+        let offset = pos;
+        let bad = |reason: &str| IndexError::BadEntry {
+            index,
+            offset,
+            reason: reason.to_string(),
+        };
+        let fields = data
+            .get(pos..pos + ENTRY_FIXED_SIZE)
+            .ok_or_else(|| bad("truncated entry"))?;
+        let field = |n: usize| array_to_int(&fields[n * 4..n * 4 + 4]);
 
-        // read 3 32-bit (12 bytes) info fields
-        // uid (null on windows), guid (null on windows), file size
-        let mut file_info = [0; 12];
-        file.read_exact(&mut file_info)
-            .expect("Invalid index format");
+        // ctime seconds, ctime nanosecond fractions, mtime seconds, mtime nanosecond fractions,
+        // dev, ino, mode, uid, gid, file size, each 32-bit, then the hash and the flags
+        let (dev, ino, mode, uid, gid) = (field(4), field(5), field(6), field(7), field(8));
+        let flags = &fields[60..62];
+        let mut name_start = pos + ENTRY_FIXED_SIZE;
 
-        let uid = array_to_int(&file_info[0..4]);
-        let guid = array_to_int(&file_info[4..8]);
-        let size = array_to_int(&file_info[8..12]);
+        // Regular files, symlinks and gitlinks are the only things an index can hold
+        match mode {
+            0o100644 | 0o100755 | SYMLINK_MODE | GITLINK_MODE => (),
+            _ => return Err(bad(&format!("invalid mode {:o}", mode))),
+        }
 
-        let mut hash = [0; 20];
-        file.read_exact(&mut hash).expect("Invalid index format");
+        let mut extended_flags = 0;
+        if u16::from_be_bytes([flags[0], flags[1]]) & EXTENDED_FLAG != 0 {
+            if version < 3 {
+                return Err(bad(&format!(
+                    "extended flags in a version {} index",
+                    version
+                )));
+            }
+            let bytes = data
+                .get(name_start..name_start + 2)
+                .ok_or_else(|| bad("truncated extended flags"))?;
+            extended_flags = u16::from_be_bytes([bytes[0], bytes[1]]);
+            name_start += 2;
+        }
+        let rest = data.get(name_start..).unwrap_or_default();
 
-        let mut flags = [0; 2];
-        file.read_exact(&mut flags).expect("Invalid index format");
-        let filename_length = flags_to_length(&flags);
+        let filename = if version == 4 {
+            // The prefix is shared with the previous path, so only the rest of the path is stored
+            let (strip, used) = pack_util::parse_ofs_distance(rest)
+                .ok_or_else(|| bad("truncated path prefix length"))?;
+            let keep = previous_name
+                .len()
+                .checked_sub(strip as usize)
+                .ok_or_else(|| bad("removes more of the previous path than it has"))?;
+            let suffix_length = rest[used..]
+                .iter()
+                .position(|b| *b == 0)
+                .ok_or_else(|| bad("path is not NUL-terminated"))?;
 
-        let mut filename_buf = vec![0; filename_length as usize].into_boxed_slice();
-        file.read_exact(&mut filename_buf)
-            .expect("Invalid index format");
+            let mut filename = previous_name[..keep].to_vec();
+            filename.extend_from_slice(&rest[used..used + suffix_length]);
+            pos = name_start + used + suffix_length + 1;
+            filename
+        } else {
+            // Long names don't fit in the flags, so the terminating NUL is searched for instead
+            let name_length = match flags_to_length(flags) as usize {
+                NAME_MASK => rest
+                    .iter()
+                    .position(|b| *b == 0)
+                    .ok_or_else(|| bad("path is not NUL-terminated"))?,
+                length => length,
+            };
+            let filename = rest
+                .get(..name_length)
+                .ok_or_else(|| bad("path runs past the end of the entries"))?;
 
-        // Read at least one NUL byte (and up to 8),
-        // the filename ends with a NUL-terminator, and is padded to the nearest multiple of 8 bytes (for the entry)
-        let bytes_read = 60; // bytes written for an entry
-        let total_bytes = bytes_read + filename_length;
-        let padding = 8 - (total_bytes % 8);
-        let mut nul_buf = vec![0; padding as usize].into_boxed_slice();
-        file.read_exact(&mut nul_buf).expect("Invalid index format");
+            // The filename ends with at least one NUL byte (and up to 8),
+            // padding the entry to the nearest multiple of 8 bytes
+            let entry_end = pos + entry_length(name_start - pos, name_length);
+            let padding = data
+                .get(name_start + name_length..entry_end)
+                .ok_or_else(|| bad("padding runs past the end of the entries"))?;
+            if padding.iter().any(|b| *b != 0) {
+                return Err(bad(&format!(
+                    "path is longer than its length of {} in the flags",
+                    name_length
+                )));
+            }
+            pos = entry_end;
+            filename.to_vec()
+        };
+        if filename.is_empty() {
+            return Err(bad("empty path"));
+        }
+        previous_name = filename;
 
-        let permissions = mode_to_permissions(&mode);
-        let filename = str::from_utf8(&filename_buf).unwrap();
+        let filename = str::from_utf8(&previous_name).map_err(|_| bad("path isn't UTF-8"))?;
 
         index_files.push(IndexFile {
-            ctime,
-            ctime_fractions,
-            mtime,
-            mtime_fractions,
+            ctime: field(0),
+            ctime_fractions: field(1),
+            mtime: field(2),
+            mtime_fractions: field(3),
             dev: if dev == 0 { None } else { Some(dev) },
             ino: if ino == 0 { None } else { Some(ino) },
-            permissions: String::from(permissions),
+            mode: field(6),
             uid: if uid == 0 { None } else { Some(uid) },
-            gid: if guid == 0 { None } else { Some(guid) },
-            size,
-            object_hash: object_util::to_hex_string(&hash),
+            gid: if gid == 0 { None } else { Some(gid) },
+            size: field(9),
+            object_hash: object_util::to_hex_string(&fields[40..60]),
             filename: String::from(filename),
+            assume_valid: flags_to_assume_valid(flags),
+            skip_worktree: extended_flags & SKIP_WORKTREE_FLAG != 0,
+            intent_to_add: extended_flags & INTENT_TO_ADD_FLAG != 0,
+            stage: flags_to_stage(flags),
         });
     }
 
-    Ok((index_header, index_files))
+    let mut extensions = IndexExtensions::default();
+    while pos < data.len() {
+        let offset = pos;
+        let signature = String::from_utf8_lossy(&data[pos..(pos + 4).min(data.len())]).into_owned();
+        let bad = |reason: &str| IndexError::BadExtension {
+            signature: signature.clone(),
+            offset,
+            reason: reason.trim_start_matches("error: ").to_string(),
+        };
+        let header = data
+            .get(pos..pos + 8)
+            .ok_or_else(|| bad("truncated extension header"))?;
+        let size = array_to_int(&header[4..8]) as usize;
+        let content = data
+            .get(pos + 8..pos + 8 + size)
+            .ok_or_else(|| bad(&format!("size {} runs past the end of the file", size)))?;
+
+        match &header[..4] {
+            b"TREE" => {
+                extensions.cache_tree = Some(CacheTree::parse(content).map_err(|e| bad(&e))?)
+            }
+            b"REUC" => {
+                extensions.resolve_undo = resolve_undo_util::parse(content).map_err(|e| bad(&e))?
+            }
+            // Extensions starting with a capital letter are optional, and can be dropped
+            sig if sig[0].is_ascii_uppercase() => (),
+            _ => return Err(bad("required extension which we do not understand")),
+        }
+        pos += 8 + size;
+    }
+
+    Ok((index_header, index_files, extensions))
 }
 
-/// Writes the given index structs back to the index file
-pub fn write_index(items: Vec<IndexFile>) -> io::Result<()> {
-    // https://doc.rust-lang.org/stable/std/fs/struct.OpenOptions.html#method.truncate
+/// The index held by `index.lock` from when it is read until it is written back
+///
+/// Dropping it without writing releases the lock and leaves the index as it was.
+pub struct LockedIndex {
+    lock: LockFile,
+    version: u32,
+    items: Vec<IndexFile>,
+    extensions: IndexExtensions,
+}
 
-    // Might want to create and replace the index file instead of overwriting it in case of errors
-    let mut file = OpenOptions::new()
-        .write(true)
-        .truncate(true)
-        .open(INDEX_PATH)
-        .expect("Could not open index file");
+/// Locks the index and returns its entries, so no other process can change it until it is written
+///
+/// A missing index has no entries
+pub fn lock_index() -> Result<(LockedIndex, Vec<IndexFile>), String> {
+    let lock = LockFile::lock(INDEX_PATH)?;
+    let (version, items, extensions) = match read_index() {
+        Ok((header, items, extensions)) => (header.version, items, extensions),
+        Err(IndexError::Io(_)) if !Path::new(INDEX_PATH).exists() => {
+            (2, Vec::new(), IndexExtensions::default())
+        }
+        Err(e) => return Err(e.into()),
+    };
+    let index = LockedIndex {
+        lock,
+        version,
+        items: items.clone(),
+        extensions,
+    };
+    Ok((index, items))
+}
 
-    // Write magic number and version
-    file.write_all(&[0x44, 0x49, 0x52, 0x43, 0x0, 0x0, 0x0, 0x02])?;
+/// Writes the given index structs back to the locked index and releases it
+///
+/// The index keeps its version, except that versions 2 and 3 switch between each other
+/// depending on whether any entry needs extended flags, like git does.
+/// Its extensions are kept and updated for the entries that changed.
+pub fn write_index(index: LockedIndex, items: Vec<IndexFile>) -> Result<(), String> {
+    let version = index.version;
+    write_index_as(index, items, version)
+}
 
-    let num_file = items.len() as u32;
+/// Writes the given index structs to the locked index, in the given index version
+pub fn write_index_version(
+    index: LockedIndex,
+    items: Vec<IndexFile>,
+    version: u32,
+) -> Result<(), String> {
+    write_index_as(index, items, version)
+}
+
+fn write_index_as(index: LockedIndex, items: Vec<IndexFile>, version: u32) -> Result<(), String> {
+    let LockedIndex {
+        lock,
+        items: old_items,
+        mut extensions,
+        ..
+    } = index;
+    update_extensions(&old_items, &items, &mut extensions);
+    write_index_file(lock, &items, version, &extensions)
+}
+
+/// Writes the index file as is, with the given entries and extensions
+///
+/// The new index is written to the held `index.lock` and then renamed over the index,
+/// so a failed write leaves the old index in place.
+fn write_index_file(
+    lock: LockFile,
+    items: &[IndexFile],
+    version: u32,
+    extensions: &IndexExtensions,
+) -> Result<(), String> {
+    let version = match version {
+        4 => 4,
+        _ if items
+            .iter()
+            .any(|item| item.skip_worktree || item.intent_to_add) =>
+        {
+            3
+        }
+        _ => 2,
+    };
+    lock.commit(&build_index_data(items, version, extensions))
+}
+
+/// Brings the extensions up to date with the entries replacing the old ones
+///
+/// Directories holding a path that changed lose their cached tree,
+/// and paths whose conflict was resolved get a resolve-undo record of the conflicted versions
+fn update_extensions(old: &[IndexFile], new: &[IndexFile], extensions: &mut IndexExtensions) {
+    let key = |item: &IndexFile| {
+        (
+            (item.filename.clone(), item.stage),
+            (item.object_hash.clone(), item.mode),
+        )
+    };
+    let old_entries: HashMap<_, _> = old.iter().map(key).collect();
+    let new_entries: HashMap<_, _> = new.iter().map(key).collect();
+
+    if let Some(cache_tree) = extensions.cache_tree.as_mut() {
+        let changed = old_entries
+            .iter()
+            .filter(|(path, entry)| new_entries.get(path) != Some(entry))
+            .chain(
+                new_entries
+                    .iter()
+                    .filter(|(path, _)| !old_entries.contains_key(path)),
+            );
+        for ((filename, _), _) in changed {
+            cache_tree.invalidate(filename);
+        }
+    }
+
+    let conflicted: HashSet<&str> = new
+        .iter()
+        .filter(|item| item.stage != 0)
+        .map(|item| item.filename.as_str())
+        .collect();
+    let mut resolved: Vec<ResolveUndo> = Vec::new();
+    for item in old.iter().filter(|item| item.stage != 0) {
+        if conflicted.contains(item.filename.as_str()) {
+            continue;
+        }
+        let record = match resolved.iter_mut().find(|r| r.path == item.filename) {
+            Some(record) => record,
+            None => {
+                resolved.push(ResolveUndo {
+                    path: item.filename.clone(),
+                    stages: [None, None, None],
+                });
+                resolved.last_mut().unwrap()
+            }
+        };
+        record.stages[(item.stage as usize - 1) % 3] = Some((item.mode, item.object_hash.clone()));
+    }
+
+    // A path that is conflicted again no longer has anything to undo
+    extensions.resolve_undo.retain(|r| {
+        !conflicted.contains(r.path.as_str()) && !resolved.iter().any(|n| n.path == r.path)
+    });
+    extensions.resolve_undo.append(&mut resolved);
+    extensions
+        .resolve_undo
+        .sort_by(|a, b| a.path.as_bytes().cmp(b.path.as_bytes()));
+}
+
+/// Builds the content of an index file holding the given entries and extensions, ending with its checksum
+fn build_index_data(items: &[IndexFile], version: u32, extensions: &IndexExtensions) -> Vec<u8> {
+    // Write magic number and version
+    let mut data = vec![0x44, 0x49, 0x52, 0x43];
+    data.extend_from_slice(&version.to_be_bytes());
 
     // Write number of files as 4 byte number
-    file.write_all(&num_file.to_be_bytes())?;
+    data.extend_from_slice(&(items.len() as u32).to_be_bytes());
 
+    let mut previous_name: &[u8] = &[];
     for item in items.iter() {
+        let entry_start = data.len();
+
         // Write 6 32-bit (4-byte) info fields
         // ctime seconds, ctime nanosecond fractions, mtime seconds, mtime nanosecond fractions, dev (null on windows), ino (null on windows)
-        file.write_all(&item.ctime.to_be_bytes())?;
-        file.write_all(&item.ctime_fractions.to_be_bytes())?;
-        file.write_all(&item.mtime.to_be_bytes())?;
-        file.write_all(&item.mtime_fractions.to_be_bytes())?;
-        match item.dev {
-            Some(x) => file.write_all(&x.to_be_bytes())?,
-            None => file.write_all(&[0, 0, 0, 0])?,
-        };
-        match item.ino {
-            Some(x) => file.write_all(&x.to_be_bytes())?,
-            None => file.write_all(&[0, 0, 0, 0])?,
-        };
+        data.extend_from_slice(&item.ctime.to_be_bytes());
+        data.extend_from_slice(&item.ctime_fractions.to_be_bytes());
+        data.extend_from_slice(&item.mtime.to_be_bytes());
+        data.extend_from_slice(&item.mtime_fractions.to_be_bytes());
+        data.extend_from_slice(&item.dev.unwrap_or(0).to_be_bytes());
+        data.extend_from_slice(&item.ino.unwrap_or(0).to_be_bytes());
 
-        // Write out mode
+        // Write out the 32-bit mode
+        // 16-bits: unused
         // 4-bits: object type (regular (1000), symlink(1010), gitlink(1110))
         // 3-bits: unused
-        // 9-bit: permissions (755 or 644)
-        // assume regular file for now
-        file.write_all(&[0b1000_0001])?;
-
-        if item.permissions == "644" {
-            file.write_all(&[0b1010_0100])?;
-        } else {
-            file.write_all(&[0b1110_1101])?;
-        }
+        // 9-bit: permissions (755 or 644, 0 for symlinks and gitlinks)
+        data.extend_from_slice(&item.mode.to_be_bytes());
 
         // Write 3 4-byte info fields
         // uid (null on windows), guid (null on windows), file size
-        match item.uid {
-            Some(x) => file.write_all(&x.to_be_bytes())?,
-            None => file.write_all(&[0, 0, 0, 0])?,
-        };
-        match item.gid {
-            Some(x) => file.write_all(&x.to_be_bytes())?,
-            None => file.write_all(&[0, 0, 0, 0])?,
-        };
-        file.write_all(&item.size.to_be_bytes())?;
+        data.extend_from_slice(&item.uid.unwrap_or(0).to_be_bytes());
+        data.extend_from_slice(&item.gid.unwrap_or(0).to_be_bytes());
+        data.extend_from_slice(&item.size.to_be_bytes());
 
-        file.write_all(&hash_to_vec(&item.object_hash))?;
-
-        let filename_length = item.filename.len();
-        debug_assert!(filename_length <= 255);
+        data.append(&mut hash_to_vec(&item.object_hash));
 
         // write flag bytes
         // 1 bit assume valid
         // 1 bit extended (must be 0 in version 2)
         // 2 bit stage (during merge)
-        // 12 bit name length if the length is less than 0xFF; otherwise 0xFF
-        file.write_all(&[0b1000_0000, (filename_length as u8)])?;
+        // 12 bit name length if the length is less than 0xFFF; otherwise 0xFFF
+        let filename_length = item.filename.len();
+        let extended_flags = if item.skip_worktree {
+            SKIP_WORKTREE_FLAG
+        } else {
+            0
+        } | if item.intent_to_add {
+            INTENT_TO_ADD_FLAG
+        } else {
+            0
+        };
+        let flags = ((item.assume_valid as u16) << 15)
+            | if extended_flags != 0 {
+                EXTENDED_FLAG
+            } else {
+                0
+            }
+            | ((item.stage as u16 & 0b11) << 12)
+            | filename_length.min(NAME_MASK) as u16;
+        data.extend_from_slice(&flags.to_be_bytes());
+        if extended_flags != 0 {
+            data.extend_from_slice(&extended_flags.to_be_bytes());
+        }
 
-        file.write_all(item.filename.as_bytes())?;
+        let filename = item.filename.as_bytes();
+        if version == 4 {
+            // Only write the part of the path that differs from the previous one
+            let common = previous_name
+                .iter()
+                .zip(filename.iter())
+                .take_while(|(a, b)| a == b)
+                .count();
+            data.append(&mut pack_util::encode_ofs_distance(
+                (previous_name.len() - common) as u64,
+            ));
+            data.extend_from_slice(&filename[common..]);
+            data.push(0);
+        } else {
+            data.extend_from_slice(filename);
 
-        // Write at least one NUL byte (and up to 8),
-        // The filename ends with a NUL-terminator, and is padded to the nearest multiple of 8 bytes (for the entry)
-        let bytes_written = 60; // bytes written for an entry
-        let total_bytes = bytes_written + filename_length;
-        let padding = 8 - (total_bytes % 8);
-        let nul_buf = vec![0; padding as usize];
-        file.write_all(&nul_buf)?;
+            // Write at least one NUL byte (and up to 8),
+            // The filename ends with a NUL-terminator, and is padded to the nearest multiple of 8 bytes (for the entry)
+            let fixed_size = data.len() - entry_start - filename_length;
+            let padding = entry_length(fixed_size, filename_length) - fixed_size - filename_length;
+            data.resize(data.len() + padding, 0);
+        }
+        previous_name = filename;
     }
 
-    Ok(())
+    let mut write_extension = |signature: &[u8], content: Vec<u8>| {
+        data.extend_from_slice(signature);
+        data.extend_from_slice(&(content.len() as u32).to_be_bytes());
+        data.extend_from_slice(&content);
+    };
+    if let Some(cache_tree) = extensions.cache_tree.as_ref() {
+        write_extension(b"TREE", cache_tree.to_bytes());
+    }
+    if !extensions.resolve_undo.is_empty() {
+        write_extension(
+            b"REUC",
+            resolve_undo_util::to_bytes(&extensions.resolve_undo),
+        );
+    }
+
+    let checksum = Sha1::from(&data).digest().bytes();
+    data.extend_from_slice(&checksum);
+    data
+}
+
+/// Returns the length of an entry with a name of the given length, including its NUL padding
+///
+/// `fixed_size` is the size of the entry before the name, which depends on whether it has extended flags
+fn entry_length(fixed_size: usize, name_length: usize) -> usize {
+    (fixed_size + name_length + 8) & !7
 }
 
 /// Parses the index file and writes it to the store as a tree object
 ///
+/// Files in subdirectories are written to their own tree objects, which are linked from their parent tree.
+/// Directories whose tree is still in the index's cache tree aren't written again,
+/// and the cache tree is saved with the new trees afterwards.
+///
 /// Returns the hash of the resulting object
 pub fn write_index_to_tree(missing_ok: bool) -> Result<String, String> {
-    let (header, items) = parse_index()?;
+    // Like git, the tree is still written if the index is locked, only the cache tree isn't saved
+    let lock = LockFile::lock(INDEX_PATH).ok();
+    let (header, items, mut extensions) = read_index()?;
 
     debug_assert_eq!(header.num_files as usize, items.len());
 
-    let mut tree_content = Vec::<u8>::new();
-
+    let mut entries = Vec::new();
     for file in items.iter() {
-        // Check that object exists in object database
-        let object_path = object_util::get_object_path(&file.object_hash);
-        let exists = Path::new(&object_path).exists();
+        if file.stage != 0 {
+            return Err(format!(
+                "error: {}: unmerged ({})\nfatal: write-tree: error building trees",
+                file.filename, file.object_hash
+            ));
+        }
 
-        if !exists && !missing_ok {
+        // An intent-to-add entry isn't staged yet, so git leaves it out of the tree
+        // unless it is also skip-worktree
+        let intent_to_add = file.intent_to_add && !file.skip_worktree;
+
+        // Check that object exists in object database, a gitlink's commit is in another repository
+        if !missing_ok
+            && !intent_to_add
+            && file.mode != GITLINK_MODE
+            && !object_util::object_exists(&file.object_hash)
+        {
             //TODO: more generic error
             let error = format!(
-                "invalid object {} {} for {}\nfatal: write-tree: error building trees",
-                file.permissions, file.object_hash, file.filename
+                "invalid object {:o} {} for {}\nfatal: write-tree: error building trees",
+                file.mode, file.object_hash, file.filename
             );
             return Err(error);
         }
 
-        // permissions, space, the filename, null byte, the hex hash (20 bytes)
-        // Write as bytes so the hash isn't mangled
-        tree_content.append(&mut "100".as_bytes().to_vec());
-        tree_content.append(&mut file.permissions.as_bytes().to_vec());
+        entries.push((
+            TreeEntry {
+                mode: format!("{:o}", file.mode),
+                name: file.filename.clone(),
+                hash: file.object_hash.clone(),
+            },
+            intent_to_add,
+        ));
+    }
+
+    let cache_tree = write_tree_level("", entries, extensions.cache_tree.as_ref());
+    let hash = cache_tree.hash.clone().unwrap_or_default();
+    extensions.cache_tree = Some(cache_tree);
+    if let Some(lock) = lock {
+        write_index_file(lock, &items, header.version, &extensions)?;
+    }
+    Ok(hash)
+}
+
+/// Writes a tree object for a single directory, recursively writing its subdirectories first
+///
+/// Entry names are paths relative to this directory.
+/// If the cached tree for the directory is still valid it is used instead.
+/// Entries marked as intent-to-add are left out of the tree, and like git the cache tree
+/// of a directory holding them is left invalid, so it is rebuilt once they are added.
+///
+/// Returns the cache tree for the directory, holding the hash of its tree object
+fn write_tree_level(
+    name: &str,
+    entries: Vec<(TreeEntry, bool)>,
+    cached: Option<&CacheTree>,
+) -> CacheTree {
+    if let Some(cached) = cached {
+        let unchanged = cached.entry_count >= 0 && cached.entry_count as usize == entries.len();
+        if unchanged && cached.hash.iter().all(|h| object_util::object_exists(h)) {
+            return cached.clone();
+        }
+    }
+
+    let mut entry_count = entries.len() as i32;
+    let mut invalid = false;
+    let mut tree_entries = Vec::new();
+    let mut subdirs: Vec<(String, Vec<(TreeEntry, bool)>)> = Vec::new();
+
+    for (entry, intent_to_add) in entries.into_iter() {
+        match entry.name.find('/') {
+            Some(i) => {
+                let dir = entry.name[..i].to_string();
+                let child = (
+                    TreeEntry {
+                        name: entry.name[i + 1..].to_string(),
+                        ..entry
+                    },
+                    intent_to_add,
+                );
+                match subdirs.iter_mut().find(|(d, _)| *d == dir) {
+                    Some((_, children)) => children.push(child),
+                    None => subdirs.push((dir, vec![child])),
+                }
+            }
+            None if intent_to_add => invalid = true,
+            None => tree_entries.push(entry),
+        }
+    }
+
+    let mut subtrees = Vec::new();
+    for (dir, children) in subdirs.into_iter() {
+        let subtree = write_tree_level(&dir, children, cached.and_then(|c| c.subtree(&dir)));
+        let hash = subtree.hash.clone().unwrap_or_default();
+        if subtree.entry_count < 0 {
+            invalid = true;
+        }
+        // A directory holding only intent-to-add entries would be an empty tree, which git doesn't write
+        if subtree.entry_count >= 0 || hash != "4b825dc642cb6eb9a060e54bf8d69288fbee4904" {
+            tree_entries.push(TreeEntry {
+                mode: "40000".to_string(),
+                name: dir,
+                hash,
+            });
+        }
+        subtrees.push(subtree);
+    }
+    if invalid {
+        entry_count = -1;
+    }
+
+    sort_tree_entries(&mut tree_entries);
+    let hash = object_util::write_object(
+        object_util::Object::Tree,
+        &build_tree_content(&tree_entries),
+    );
+    CacheTree {
+        name: name.to_string(),
+        entry_count,
+        hash: Some(hash),
+        subtrees,
+    }
+}
+
+/// Sorts tree entries in git's canonical order
+///
+/// Entries are sorted by name, but trees sort as if their name ended with a `/`,
+/// so `foo.txt` comes before the directory `foo`, which comes before `foo0`
+pub fn sort_tree_entries(entries: &mut [TreeEntry]) {
+    entries.sort_by_key(tree_sort_key);
+}
+
+fn tree_sort_key(entry: &TreeEntry) -> Vec<u8> {
+    let mut key = entry.name.as_bytes().to_vec();
+    if entry.object_type() == object_util::Object::Tree {
+        key.push(b'/');
+    }
+    key
+}
+
+/// Builds the content of a tree object (without the object header) from its sorted entries
+pub fn build_tree_content(entries: &[TreeEntry]) -> Vec<u8> {
+    // File information is the permissions, space, the filename, null byte, the hex hash (20 bytes)
+    // Write as bytes so the hash isn't mangled
+    let mut tree_content = Vec::<u8>::new();
+    for entry in entries.iter() {
+        tree_content.extend_from_slice(entry.mode.as_bytes());
         tree_content.push(32u8); // space is 32 in ascii
-        tree_content.append(&mut file.filename.as_bytes().to_vec());
+        tree_content.extend_from_slice(entry.name.as_bytes());
         tree_content.push(0);
-        tree_content.append(&mut hash_to_vec(&file.object_hash));
+        tree_content.append(&mut hash_to_vec(&entry.hash));
     }
+    tree_content
+}
 
-    // A tree is a zlib compressed file of a header and a list of file information
-    // An object header is the type of object, a space, the size of the contents in bytes, then a null byte
-    // File information is the permissions, space, the filename, null byte, the hex hash (20 bytes)
+/// Returns a copy of the entry with its stat data refreshed from the working tree file
+pub fn refresh_stat(item: &IndexFile) -> IndexFile {
+    match fs::symlink_metadata(&item.filename) {
+        Ok(metadata) => with_stat(item, &metadata),
+        Err(_) => item.clone(),
+    }
+}
+
+/// Returns a copy of the entry with the given stat data, like git's `fill_stat_cache_info`
+fn with_stat(item: &IndexFile, metadata: &fs::Metadata) -> IndexFile {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        let non_zero = |value: u32| Some(value).filter(|v| *v != 0);
+        IndexFile {
+            ctime: metadata.ctime() as u32,
+            ctime_fractions: metadata.ctime_nsec() as u32,
+            mtime: metadata.mtime() as u32,
+            mtime_fractions: metadata.mtime_nsec() as u32,
+            dev: non_zero(metadata.dev() as u32),
+            ino: non_zero(metadata.ino() as u32),
+            uid: non_zero(metadata.uid()),
+            gid: non_zero(metadata.gid()),
+            size: metadata.len() as u32,
+            ..item.clone()
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        let (mtime, mtime_fractions) = to_time(metadata.modified());
+        IndexFile {
+            mtime,
+            mtime_fractions,
+            size: metadata.len() as u32,
+            ..item.clone()
+        }
+    }
+}
 
-    let tree_header = format!("tree {}\0", tree_content.len());
-    let mut store = Vec::from(tree_header.as_bytes());
-    store.append(&mut tree_content);
-    let hash = Sha1::from(&store).hexdigest();
+/// Returns true if the working tree file has the same content as the entry
+///
+/// The stat data is checked first, and the file is only rehashed if it looks changed.
+/// A file changed in the same instant the index was written can't be told apart by its
+/// stat data, so like git, entries that aren't older than the index are always rehashed.
+pub fn matches_worktree(item: &IndexFile) -> bool {
+    // Submodules aren't cloned, so their empty directory is all there is to match
+    if item.mode == GITLINK_MODE {
+        return fs::symlink_metadata(&item.filename).is_ok_and(|m| m.is_dir());
+    }
+    let metadata = match fs::symlink_metadata(&item.filename) {
+        Ok(metadata) if metadata.is_file() || metadata.file_type().is_symlink() => metadata,
+        _ => return false,
+    };
+    if metadata.file_type().is_symlink() != (item.mode == SYMLINK_MODE) {
+        return false;
+    }
 
-    let store_buf = store.into_boxed_slice();
+    let stat = |item: &IndexFile| {
+        (
+            (item.ctime, item.ctime_fractions),
+            (item.mtime, item.mtime_fractions),
+            (item.ino, item.uid, item.gid),
+            item.size,
+        )
+    };
+    if item.mtime != 0 && stat(item) == stat(&with_stat(item, &metadata)) && !is_racy(item) {
+        return true;
+    }
 
-    object_util::write_object_file(&hash, &store_buf);
+    worktree_hash(item) == Some(item.object_hash.clone())
+}
 
-    Ok(hash)
+/// Returns true if the entry was changed no earlier than the index was written
+fn is_racy(item: &IndexFile) -> bool {
+    match fs::metadata(INDEX_PATH) {
+        Ok(metadata) => (item.mtime, item.mtime_fractions) >= to_time(metadata.modified()),
+        Err(_) => true,
+    }
+}
+
+/// Hashes the working tree file of an entry, reading a symlink's target instead of following it
+fn worktree_hash(item: &IndexFile) -> Option<String> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::ffi::OsStrExt;
+        if item.mode == SYMLINK_MODE {
+            let target = fs::read_link(&item.filename).ok()?;
+            return Some(object_util::hash_object(
+                object_util::Object::Blob,
+                target.as_os_str().as_bytes(),
+            ));
+        }
+    }
+    Some(hash_object::generate_hash(&item.filename))
+}
+
+/// Converts a file time to seconds and nanoseconds since the unix epoch, or 0 if it isn't available
+fn to_time(time: io::Result<SystemTime>) -> (u32, u32) {
+    let time = time
+        .ok()
+        .and_then(|t| t.duration_since(SystemTime::UNIX_EPOCH).ok())
+        .unwrap_or_else(|| Duration::new(0, 0));
+    (time.as_secs() as u32, time.subsec_nanos())
 }
 
 /// Takes first 4 bytes and returns an unsigned int
 fn array_to_int(array: &[u8]) -> u32 {
-    ((array[0] as u32) << 12)
-        + ((array[1] as u32) << 8)
-        + ((array[2] as u32) << 4)
+    ((array[0] as u32) << 24)
+        + ((array[1] as u32) << 16)
+        + ((array[2] as u32) << 8)
         + (array[3] as u32)
 }
 
-/// Takes last 2 bytes of permissions and returns the file permissions
-fn mode_to_permissions(array: &[u8]) -> &str {
-    // 4-bits: object type (reg, symlink, gitlink)
-    // 3-bits: unused
-    // 9-bit: permissions (755 or 644)
-    // Since the only valid values for the first permission octal are 7 and 6 (111 or 110),
-    // we only need the third byte to figure out the permissions
-    // 1: type; 2: 000x; 3: xxxx; 4: xxxx
-    // 644 => 110 100 100 => 1_1010_0100
-    // 755 => 111 101 101 => 1_1110_1101
-    if array[1] == 0b1010_0100 {
-        "644"
-    } else {
-        "755"
-    }
-}
-
 /// Takes flag bytes and returns the length of the file name
-fn flags_to_length(array: &[u8]) -> u8 {
+fn flags_to_length(array: &[u8]) -> u16 {
     // 1 bit assume valid
     // 1 bit extended (must be 0 in version 2)
     // 2 bit stage (during merge)
-    // 12 bit name length, if the length is less than 0xFF; otherwise 0xFF
-    // Mask off the upper half of the the first byte so we can correctly cast it
-    array[1]
+    // 12 bit name length, if the length is less than 0xFFF; otherwise 0xFFF
+    // Mask off the upper half of the the first byte so only the length is left
+    (((array[0] & 0x0F) as u16) << 8) + (array[1] as u16)
+}
+
+/// Takes flag bytes and returns whether the entry is marked assume valid
+fn flags_to_assume_valid(array: &[u8]) -> bool {
+    array[0] & 0b1000_0000 != 0
+}
+
+/// Takes flag bytes and returns the merge stage
+fn flags_to_stage(array: &[u8]) -> u8 {
+    // 2 bit stage, after the assume valid and extended bits
+    (array[0] >> 4) & 0b11
 }
 
 /// Convert an object hash to a vector for usable in the index file
@@ -340,39 +981,323 @@ mod tests {
     use super::*;
 
     #[test]
-    fn test_mode_to_permissons_rw() {
-        let permissions = [0b1000_0001, 0b1010_0100];
-        assert_eq!("644", mode_to_permissions(&permissions))
-    }
+    fn test_modes_round_trip() {
+        let items = vec![
+            IndexFile::new("exe", 0o100755, "1".repeat(40).as_str(), 0),
+            IndexFile::new("link", 0o120000, "2".repeat(40).as_str(), 0),
+            IndexFile::new("sub", GITLINK_MODE, "3".repeat(40).as_str(), 0),
+        ];
+        let data = build_index_data(&items, 2, &IndexExtensions::default());
+        assert_eq!(&[0, 0, 0x81, 0xed], &data[12 + 24..12 + 28]);
 
-    #[test]
-    fn test_mode_to_permissons_rwx() {
-        let permissions = [0b1000_0001, 0b1110_1101];
-        assert_eq!("755", mode_to_permissions(&permissions))
+        let (_, parsed, _) = parse_index_data(&data).unwrap();
+        let modes: Vec<u32> = parsed.iter().map(|item| item.mode).collect();
+        assert_eq!(vec![0o100755, 0o120000, GITLINK_MODE], modes);
+        assert_eq!(
+            data,
+            build_index_data(&parsed, 2, &IndexExtensions::default())
+        );
     }
 
     #[test]
     fn test_array_to_int_size_4() {
-        let array = [0x0u8, 0xB, 0xB, 0x8];
+        let array = [0x0u8, 0x0, 0xB, 0xB8];
         assert_eq!(3000, array_to_int(&array));
     }
 
     #[test]
     fn test_array_to_int_oversized() {
-        let array = [0x0u8, 0xB, 0xB, 0x8, 0xF, 0xF];
+        let array = [0x0u8, 0x0, 0xB, 0xB8, 0xF, 0xF];
         assert_eq!(3000, array_to_int(&array))
     }
 
+    #[test]
+    fn test_array_to_int_all_bytes() {
+        let array = [0x61u8, 0x2F, 0x3A, 0x10];
+        assert_eq!(1_630_485_008, array_to_int(&array))
+    }
+
     #[test]
     fn test_flags_to_length_max() {
         let array = [0b1000_0000, 0xFF];
-        assert_eq!(255u8, flags_to_length(&array))
+        assert_eq!(255u16, flags_to_length(&array))
+    }
+
+    #[test]
+    fn test_flags_to_length_long() {
+        let array = [0b1011_0001, 0x2C];
+        assert_eq!(300u16, flags_to_length(&array));
+        assert!(flags_to_assume_valid(&array));
+        assert_eq!(3, flags_to_stage(&array));
     }
 
     #[test]
     fn test_flags_to_length_average() {
         let array = [0b1000_0000, 0x0F];
-        assert_eq!(15u8, flags_to_length(&array))
+        assert_eq!(15u16, flags_to_length(&array))
+    }
+
+    #[test]
+    fn test_sort_tree_entries_canonical_order() {
+        let entry = |name: &str, mode: &str| TreeEntry {
+            mode: mode.to_string(),
+            name: name.to_string(),
+            hash: "4b825dc642cb6eb9a060e54bf8d69288fbee4904".to_string(),
+        };
+        let mut entries = vec![
+            entry("foo0", "100644"),
+            entry("foo", "40000"),
+            entry("foo.txt", "100644"),
+        ];
+        sort_tree_entries(&mut entries);
+        let names: Vec<&str> = entries.iter().map(|e| e.name.as_str()).collect();
+        assert_eq!(vec!["foo.txt", "foo", "foo0"], names);
+    }
+
+    #[test]
+    fn test_build_tree_content() {
+        let entries = vec![TreeEntry {
+            mode: "40000".to_string(),
+            name: "src".to_string(),
+            hash: "4b825dc642cb6eb9a060e54bf8d69288fbee4904".to_string(),
+        }];
+        let mut expected = b"40000 src\0".to_vec();
+        expected.append(&mut hash_to_vec("4b825dc642cb6eb9a060e54bf8d69288fbee4904"));
+        assert_eq!(expected, build_tree_content(&entries));
+    }
+
+    #[test]
+    fn test_flags_to_stage() {
+        let array = [0b1010_0000, 0x0F];
+        assert_eq!(2, flags_to_stage(&array))
+    }
+
+    #[test]
+    fn test_index_written_by_git_round_trips() {
+        // `git add a.txt run.sh` (run.sh executable), then `git update-index --assume-unchanged a.txt`
+        let data = object_util::from_hex_string(
+            "4449524300000002000000026ad428171d3e759b6ad428171d3e759b0000fe000012a0a6000081a4000000000000000000000006ce013625030ba8dba906f756967f9e9ca394464a8005612e74787400000000006ad428171d4dcde76ad428171d3e759b0000fe000012a0a7000081ed00000000000000000000000a1a2485251c33a70432394c93fb89330ef214bfc9000672756e2e73680000000090b53ddbfc3f9b09e79d4a92f41b06d9a1cee704",
+        )
+        .unwrap();
+        let (header, items, extensions) = parse_index_data(&data).unwrap();
+        assert_eq!(2, header.num_files);
+        assert_eq!("a.txt", items[0].filename);
+        assert_eq!(1_792_288_791, items[0].mtime);
+        assert_eq!(490_632_603, items[0].mtime_fractions);
+        assert_eq!(Some(65024), items[0].dev);
+        assert_eq!(Some(1_220_774), items[0].ino);
+        assert_eq!(6, items[0].size);
+        assert!(items[0].assume_valid);
+        assert_eq!(0o100755, items[1].mode);
+        assert!(!items[1].assume_valid);
+        assert_eq!(data, build_index_data(&items, 2, &extensions));
+    }
+
+    #[test]
+    fn test_index_v4_written_by_git_round_trips() {
+        // `git add src/a/one.rs src/a/two.rs top`, `git update-index --skip-worktree src/a/two.rs`,
+        // then `git update-index --index-version 4`
+        let data = object_util::from_hex_string(
+            "4449524300000004000000036ad428760db28ed56ad428760db28ed50000fe000012a0c3000081a4000000000000000000000002587be6b4c3f93f93c489c0111bba5596147a26cb000c007372632f612f6f6e652e7273006ad428760db28ed56ad428760db28ed50000fe000012a0c4000081a4000000000000000000000002975fbec8256d3e8a3797e7a3611380f27c49f4ac400c40000674776f2e7273006ad428760db28ed56ad428760db28ed50000fe000012a0c5000081a4000000000000000000000002b68025345d5301abad4d9ec9166f455243a0d74600030c746f7000fd5b51c2798d5989e7263567d71b94f44374cba7",
+        )
+        .unwrap();
+        let (header, items, extensions) = parse_index_data(&data).unwrap();
+        assert_eq!(4, header.version);
+        let names: Vec<&str> = items.iter().map(|i| i.filename.as_str()).collect();
+        assert_eq!(vec!["src/a/one.rs", "src/a/two.rs", "top"], names);
+        assert!(!items[0].skip_worktree);
+        assert!(items[1].skip_worktree);
+        assert_eq!(data, build_index_data(&items, 4, &extensions));
+    }
+
+    #[test]
+    fn test_extended_flags_round_trip() {
+        let mut item = IndexFile::new("dir/file", 0o100644, "1".repeat(40).as_str(), 0);
+        item.intent_to_add = true;
+        let items = vec![
+            item,
+            IndexFile::new("dir/other", 0o100755, "2".repeat(40).as_str(), 0),
+        ];
+
+        for version in INDEX_VERSIONS.skip(1) {
+            let data = build_index_data(&items, version, &IndexExtensions::default());
+            let (header, parsed, _) = parse_index_data(&data).unwrap();
+            assert_eq!(version, header.version);
+            assert!(parsed[0].intent_to_add);
+            assert!(!parsed[0].skip_worktree);
+            assert!(!parsed[1].intent_to_add);
+            assert_eq!("dir/other", parsed[1].filename);
+            assert_eq!(
+                data,
+                build_index_data(&parsed, version, &IndexExtensions::default())
+            );
+        }
+
+        // Version 2 has no room for extended flags
+        let data = build_index_data(&items, 3, &IndexExtensions::default());
+        let mut v2 = data[..data.len() - 20].to_vec();
+        v2[7] = 2;
+        let checksum = Sha1::from(&v2).digest().bytes();
+        v2.extend_from_slice(&checksum);
+        assert!(parse_index_data(&v2).is_err());
+    }
+
+    #[test]
+    fn test_index_extensions_written_by_git_round_trip() {
+        // `git merge` with a conflict in c, then `git add c` and `git write-tree`
+        let data = object_util::from_hex_string(
+            "4449524300000002000000026ad428b51ea56ab16ad428b51ea56ab10000fe000012a0f0000081a4000000000000000000000002b8626c4cff2849624fb67f87cd0ad72b163671ad000163006ad428b51d30656a6ad428b51d30656a0000fe000012a0ef000081a4000000000000000000000002d00491fd7e5bb6fa28c517a0bb32b8b506539d4d0003642f66000000000000005452454500000033003220310a2f00b74cb2f4a70e578383f107dab48b9329a32164003120300afd43cc879db368e808a98b81005d6f21a8852a1552455543000000536300313030363434003130303634340031303036343400d00491fd7e5bb6fa28c517a0bb32b8b506539d4d00750edc07d6415dcc07ae0351e9397b0222b7ba0cfbf08886fca9a91cb753ec8734c84fcbe52c9f08d100d8c3736cc955f7827eb9dd37713251f261",
+        )
+        .unwrap();
+        let (_, items, extensions) = parse_index_data(&data).unwrap();
+        assert_eq!(2, items.len());
+
+        let cache_tree = extensions.cache_tree.as_ref().unwrap();
+        assert_eq!(2, cache_tree.entry_count);
+        assert_eq!(Some(1), cache_tree.subtree("d").map(|t| t.entry_count));
+
+        assert_eq!(1, extensions.resolve_undo.len());
+        let record = &extensions.resolve_undo[0];
+        assert_eq!("c", record.path);
+        assert_eq!(
+            Some((
+                0o100644,
+                "d00491fd7e5bb6fa28c517a0bb32b8b506539d4d".to_string()
+            )),
+            record.stages[0]
+        );
+        assert_eq!(data, build_index_data(&items, 2, &extensions));
+    }
+
+    #[test]
+    fn test_bad_checksum_is_rejected() {
+        let items = vec![IndexFile::new("a", 0o100644, "1".repeat(40).as_str(), 0)];
+        let mut data = build_index_data(&items, 2, &IndexExtensions::default());
+        assert!(parse_index_data(&data).is_ok());
+
+        data[20] ^= 1;
+        assert!(parse_index_data(&data).is_err());
+    }
+
+    #[test]
+    fn test_malformed_entry_reports_its_offset() {
+        let items = vec![
+            IndexFile::new("a", 0o100644, "1".repeat(40).as_str(), 0),
+            IndexFile::new("bb", 0o100644, "2".repeat(40).as_str(), 0),
+        ];
+        let data = build_index_data(&items, 2, &IndexExtensions::default());
+        let data = &data[..data.len() - 20];
+        let with_checksum = |mut data: Vec<u8>| {
+            let checksum = Sha1::from(&data).digest().bytes();
+            data.extend_from_slice(&checksum);
+            data
+        };
+
+        // The second entry starts after the 12 byte header and the 64 byte first entry
+        let mut bad_length = data.to_vec();
+        bad_length[12 + 64 + 61] = 1;
+        assert_eq!(
+            Err(IndexError::BadEntry {
+                index: 1,
+                offset: 76,
+                reason: "path is longer than its length of 1 in the flags".to_string(),
+            }),
+            parse_index_data(&with_checksum(bad_length)).map(|_| ())
+        );
+
+        let mut bad_mode = data.to_vec();
+        bad_mode[12 + 27] = 0;
+        let error = parse_index_data(&with_checksum(bad_mode)).unwrap_err();
+        assert_eq!(
+            "error: index entry 0 at offset 12: invalid mode 100400\nfatal: index file corrupt",
+            error.to_string()
+        );
+
+        let mut truncated = data.to_vec();
+        truncated[11] = 3;
+        assert!(matches!(
+            parse_index_data(&with_checksum(truncated)),
+            Err(IndexError::BadEntry { index: 2, .. })
+        ));
+
+        let mut extension = data.to_vec();
+        extension.extend_from_slice(b"link\0\0\0\0");
+        assert!(matches!(
+            parse_index_data(&with_checksum(extension)),
+            Err(IndexError::BadExtension { offset: 148, .. })
+        ));
+        assert_eq!(
+            Err(IndexError::TooShort),
+            parse_index_data(&data[..20]).map(|_| ())
+        );
+    }
+
+    #[test]
+    fn test_update_extensions() {
+        let hash = |c: &str| c.repeat(40);
+        let old = vec![
+            IndexFile::new("c", 0o100644, &hash("1"), 1),
+            IndexFile::new("c", 0o100644, &hash("2"), 2),
+            IndexFile::new("c", 0o100755, &hash("3"), 3),
+            IndexFile::new("d/f", 0o100644, &hash("4"), 0),
+            IndexFile::new("e/f", 0o100644, &hash("5"), 0),
+        ];
+        let mut new = old[3..].to_vec();
+        new.insert(0, IndexFile::new("c", 0o100644, &hash("6"), 0));
+        new[1].object_hash = hash("7");
+
+        let mut extensions = IndexExtensions {
+            cache_tree: Some(CacheTree {
+                name: String::new(),
+                entry_count: 3,
+                hash: Some(hash("8")),
+                subtrees: ["d", "e"]
+                    .iter()
+                    .map(|name| CacheTree {
+                        name: name.to_string(),
+                        entry_count: 1,
+                        hash: Some(hash("9")),
+                        subtrees: Vec::new(),
+                    })
+                    .collect(),
+            }),
+            resolve_undo: Vec::new(),
+        };
+        update_extensions(&old, &new, &mut extensions);
+
+        let cache_tree = extensions.cache_tree.unwrap();
+        assert_eq!(-1, cache_tree.entry_count);
+        assert_eq!(Some(-1), cache_tree.subtree("d").map(|t| t.entry_count));
+        assert_eq!(Some(1), cache_tree.subtree("e").map(|t| t.entry_count));
+        assert_eq!(
+            vec![ResolveUndo {
+                path: "c".to_string(),
+                stages: [
+                    Some((0o100644, hash("1"))),
+                    Some((0o100644, hash("2"))),
+                    Some((0o100755, hash("3"))),
+                ],
+            }],
+            extensions.resolve_undo
+        );
+    }
+
+    #[test]
+    fn test_long_filename_round_trips() {
+        let mut long = IndexFile::new(&"d/".repeat(2500), 0o100644, "1".repeat(40).as_str(), 2);
+        long.assume_valid = true;
+        long.size = 0x8000_0001;
+        let short = IndexFile::new(&"x".repeat(300), 0o100755, "2".repeat(40).as_str(), 0);
+
+        let extensions = IndexExtensions::default();
+        let data = build_index_data(&[long, short], 2, &extensions);
+        let (_, items, _) = parse_index_data(&data).unwrap();
+        assert_eq!(5000, items[0].filename.len());
+        assert!(items[0].assume_valid);
+        assert_eq!(2, items[0].stage);
+        assert_eq!(0x8000_0001, items[0].size);
+        assert_eq!(300, items[1].filename.len());
+        assert_eq!(data, build_index_data(&items, 2, &extensions));
     }
 
     #[test]
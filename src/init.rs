@@ -1,3 +1,5 @@
+use super::config_util;
+use sha1::Sha1;
 use std::fs;
 use std::io;
 
@@ -26,7 +28,8 @@ pub fn create_git_dir() -> io::Result<()> {
     fs::create_dir(format!("{}refs/heads", dir))?;
     fs::create_dir(format!("{}refs/tags", dir))?;
     create_and_copy_to_file("initFiles/exclude", &format!("{}info/exclude", dir))?;
-    create_and_copy_to_file("initFiles/config", &format!("{}config", dir))?;
+    config_util::write_config(&fs::read_to_string("initFiles/config")?)
+        .map_err(io::Error::other)?;
     fs::write(
         format!("{}description", dir),
         "Unnamed repository; edit this file 'description' to name the repository.\n",
@@ -37,9 +40,12 @@ pub fn create_git_dir() -> io::Result<()> {
     // DIRC (magic number),
     // 3 null bytes, 02 (4-byte version),
     // 4 null bytes (4-byte file count)
-    let index_content = [
+    // followed by the SHA-1 checksum of the header
+    let mut index_content = vec![
         0x44, 0x49, 0x52, 0x43, 0x0, 0x0, 0x0, 0x02, 0x0, 0x0, 0x0, 0x0,
     ];
+    let checksum = Sha1::from(&index_content).digest().bytes();
+    index_content.extend_from_slice(&checksum);
     fs::write(format!("{}index", dir), index_content)?;
     Ok(())
 }
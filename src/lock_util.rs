@@ -0,0 +1,114 @@
+use std::fs;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::Write;
+
+// https://git-scm.com/docs/api-lockfile
+
+/// A file locked for writing by holding `<path>.lock`, like git's lockfiles
+///
+/// The lock file is created with `O_EXCL`, so only one process can hold it at a time.
+/// The new content is written to the lock file, synced, then renamed over the file,
+/// so the file is never left half written.
+/// The lock is released when dropped if it was never committed.
+pub struct LockFile {
+    path: String,
+    lock_path: String,
+    file: File,
+    committed: bool,
+}
+
+impl LockFile {
+    /// Takes the lock for the file at `path`, which doesn't need to exist yet
+    pub fn lock(path: &str) -> Result<LockFile, String> {
+        let lock_path = format!("{}.lock", path);
+        let file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&lock_path)
+            .map_err(|e| match e.kind() {
+                io::ErrorKind::AlreadyExists => format!(
+                    "fatal: Unable to create '{}': File exists.\n\n\
+                     Another gitrs process seems to be running in this repository.\n\
+                     Please make sure all processes are terminated then try again.\n\
+                     If it still fails, a gitrs process may have crashed in this\n\
+                     repository earlier: remove the file manually to continue.",
+                    lock_path
+                ),
+                _ => format!("fatal: Unable to create '{}': {}", lock_path, e),
+            })?;
+
+        Ok(LockFile {
+            path: path.to_string(),
+            lock_path,
+            file,
+            committed: false,
+        })
+    }
+
+    /// Writes the new content and moves it into place, releasing the lock
+    ///
+    /// If anything fails the lock file is removed and the file is left as it was
+    pub fn commit(mut self, content: &[u8]) -> Result<(), String> {
+        self.file
+            .write_all(content)
+            .and_then(|_| self.file.sync_all())
+            .and_then(|_| fs::rename(&self.lock_path, &self.path))
+            .map_err(|e| format!("fatal: could not write {}: {}", self.path, e))?;
+        self.committed = true;
+        Ok(())
+    }
+}
+
+impl Drop for LockFile {
+    fn drop(&mut self) {
+        if !self.committed {
+            let _ = fs::remove_file(&self.lock_path);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::path::Path;
+    use std::process;
+
+    #[test]
+    fn test_lock_commit_and_release() {
+        let path = env::temp_dir().join(format!("gitrs-lock-test-{}", process::id()));
+        let path = path.to_str().unwrap();
+        let lock_path = format!("{}.lock", path);
+
+        let lock = LockFile::lock(path).unwrap();
+        assert!(Path::new(&lock_path).exists());
+        let error = LockFile::lock(path).err().unwrap();
+        assert!(error.starts_with(&format!(
+            "fatal: Unable to create '{}': File exists.",
+            lock_path
+        )));
+        drop(lock);
+        assert!(!Path::new(&lock_path).exists());
+
+        let lock = LockFile::lock(path).unwrap();
+        lock.commit(b"new content").unwrap();
+        assert!(!Path::new(&lock_path).exists());
+        assert_eq!(b"new content".to_vec(), fs::read(path).unwrap());
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_failed_commit_releases_lock() {
+        let path = env::temp_dir().join(format!("gitrs-lock-test-dir-{}", process::id()));
+        fs::create_dir_all(path.join("child")).unwrap();
+        let path = path.to_str().unwrap();
+
+        // Renaming a file over a non-empty directory fails
+        let lock = LockFile::lock(path).unwrap();
+        assert!(lock.commit(b"content").is_err());
+        assert!(!Path::new(&format!("{}.lock", path)).exists());
+        assert!(LockFile::lock(path).is_ok());
+        fs::remove_dir_all(path).unwrap();
+    }
+}
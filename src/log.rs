@@ -0,0 +1,439 @@
+use super::commit_util;
+use super::commit_util::Commit;
+use super::ls_tree;
+use super::object_util::Object;
+use super::ref_util;
+use super::rev_parse;
+use std::collections::{BinaryHeap, HashMap, HashSet};
+
+// https://git-scm.com/docs/git-log
+
+/// Options for `log`
+#[derive(Debug, Default, PartialEq)]
+pub struct LogOptions {
+    /// The commit to start from, `HEAD` if not given
+    pub rev: Option<String>,
+    /// The most commits to show
+    pub max_count: Option<usize>,
+    /// Show each commit as `<abbrev> <subject>`
+    pub oneline: bool,
+    /// Draw the commit graph next to the log
+    pub graph: bool,
+    /// Only show commits that change these paths
+    pub paths: Vec<String>,
+}
+
+/// Shows the commits reachable from a revision, newest first
+///
+/// Commits are walked in committer date order, so commits on different
+/// branches of a merge are interleaved by when they were made.
+pub fn log(options: &LogOptions) -> Result<String, String> {
+    let start = match &options.rev {
+        Some(rev) => rev_parse::rev_parse(rev)?,
+        None => ref_util::resolve_ref("HEAD")?.ok_or_else(|| {
+            let branch = ref_util::resolve_symbolic("HEAD").unwrap_or_default();
+            format!(
+                "fatal: your current branch '{}' does not have any commits yet",
+                branch.trim_start_matches("refs/heads/")
+            )
+        })?,
+    };
+    let start = rev_parse::peel(&start, Some(Object::Commit))?;
+
+    let mut graph = Graph::default();
+    let mut output = String::new();
+    let mut shown = 0;
+
+    for step in Walk::new(&start, &options.paths)? {
+        if options.max_count.is_some_and(|n| shown >= n) {
+            break;
+        }
+        let Step {
+            hash,
+            commit,
+            parents,
+            changed,
+        } = step?;
+        if !changed {
+            // Its column still moves on to its parents, so the graph doesn't keep it open
+            if options.graph {
+                graph.skip(&hash, &parents);
+            }
+            continue;
+        }
+        shown += 1;
+
+        let text = if options.oneline {
+            format_oneline(&hash, &commit)
+        } else {
+            format_full(&hash, &commit)
+        };
+        // Full commits are separated by a blank line
+        let separate = !options.oneline && shown > 1;
+
+        if options.graph {
+            output += &graph.render(&hash, &parents, &text, separate);
+        } else {
+            if separate {
+                output += "\n";
+            }
+            output += &text;
+        }
+    }
+
+    Ok(output)
+}
+
+/// Parse arguments for `log`
+pub fn parse_args(args: &[String]) -> Result<LogOptions, String> {
+    let mut options = LogOptions::default();
+
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "--oneline" => options.oneline = true,
+            "--graph" => options.graph = true,
+            "--" => {
+                options.paths = iter.map(|p| p.trim_end_matches('/').to_owned()).collect();
+                break;
+            }
+            "-n" => {
+                let value = iter
+                    .next()
+                    .ok_or_else(|| "error: switch `n' requires a value".to_string())?;
+                options.max_count = Some(parse_count(value)?);
+            }
+            _ if arg.starts_with("--max-count=") => {
+                options.max_count = Some(parse_count(&arg["--max-count=".len()..])?)
+            }
+            _ if arg.len() > 1 && arg[1..].chars().all(|c| c.is_ascii_digit()) => {
+                options.max_count = Some(parse_count(&arg[1..])?)
+            }
+            _ if arg.starts_with('-') => {
+                return Err(format!("{} is not recognized as a valid option", arg))
+            }
+            _ if options.rev.is_none() => options.rev = Some(arg.to_owned()),
+            _ => return Err(format!("{} is not recognized as a valid option", arg)),
+        }
+    }
+
+    Ok(options)
+}
+
+fn parse_count(value: &str) -> Result<usize, String> {
+    value
+        .parse::<usize>()
+        .map_err(|_| format!("fatal: '{}': not an integer", value))
+}
+
+/// Walks the commits reachable from a commit, newest committer date first
+///
+/// Commits with the same date are returned in the order they were found.
+/// Commits are only read as the walk reaches them, so stopping early doesn't read the whole history.
+///
+/// When limited to paths, history is simplified like git does by default:
+/// a merge with the same paths as one of its parents only follows that parent.
+struct Walk {
+    paths: Vec<String>,
+    /// Commits waiting to be returned, by date and then the order they were found in
+    queue: BinaryHeap<(u64, i64, String)>,
+    queued: HashMap<String, Commit>,
+    found: HashSet<String>,
+    order: i64,
+}
+
+/// A commit reached by a [`Walk`]
+struct Step {
+    hash: String,
+    commit: Commit,
+    /// The parents the walk follows from this commit
+    parents: Vec<String>,
+    /// Whether the commit changes the paths the walk is limited to, always true without paths
+    changed: bool,
+}
+
+impl Walk {
+    fn new(start: &str, paths: &[String]) -> Result<Walk, String> {
+        let commit = commit_util::read_commit(start)?;
+        let mut walk = Walk {
+            paths: paths.to_vec(),
+            queue: BinaryHeap::new(),
+            queued: HashMap::new(),
+            found: HashSet::new(),
+            order: 0,
+        };
+        walk.queue
+            .push((commit.committer.timestamp, 0, start.to_string()));
+        walk.queued.insert(start.to_string(), commit);
+        walk.found.insert(start.to_string());
+        Ok(walk)
+    }
+
+    fn step(&mut self, hash: String, commit: Commit) -> Result<Step, String> {
+        let (parents, changed) = if self.paths.is_empty() {
+            (commit.parents.clone(), true)
+        } else {
+            match same_paths_parent(&commit, &self.paths)? {
+                Some(parent) => (vec![parent], false),
+                None => (commit.parents.clone(), true),
+            }
+        };
+
+        for parent in parents.iter() {
+            if self.found.insert(parent.clone()) {
+                let parent_commit = commit_util::read_commit(parent)?;
+                // Commits found later get a lower priority when dates are equal
+                self.order -= 1;
+                self.queue.push((
+                    parent_commit.committer.timestamp,
+                    self.order,
+                    parent.clone(),
+                ));
+                self.queued.insert(parent.clone(), parent_commit);
+            }
+        }
+
+        // A root commit changes the paths if it has any of them
+        let changed = changed
+            && (self.paths.is_empty()
+                || !commit.parents.is_empty()
+                || self
+                    .paths
+                    .iter()
+                    .any(|p| ls_tree::find_path(&commit.tree, p).is_ok()));
+        Ok(Step {
+            hash,
+            commit,
+            parents,
+            changed,
+        })
+    }
+}
+
+impl Iterator for Walk {
+    type Item = Result<Step, String>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (_, _, hash) = self.queue.pop()?;
+        let commit = self.queued.remove(&hash)?;
+        Some(self.step(hash, commit))
+    }
+}
+
+/// Returns the first parent whose content at every one of the paths is the same as the commit's
+fn same_paths_parent(commit: &Commit, paths: &[String]) -> Result<Option<String>, String> {
+    let lookup = |tree: &str, path: &str| ls_tree::find_path(tree, path).ok().map(|e| e.hash);
+
+    for parent in commit.parents.iter() {
+        let parent_tree = commit_util::read_commit(parent)?.tree;
+        let same = paths
+            .iter()
+            .all(|p| lookup(&commit.tree, p) == lookup(&parent_tree, p));
+        if same {
+            return Ok(Some(parent.clone()));
+        }
+    }
+    Ok(None)
+}
+
+/// Formats a commit as `<abbrev> <subject>`
+fn format_oneline(hash: &str, commit: &Commit) -> String {
+    format!("{} {}\n", &hash[..7], commit.subject())
+}
+
+/// Formats a commit with its hash, parents, author, date and indented message
+fn format_full(hash: &str, commit: &Commit) -> String {
+    let mut output = format!("commit {}\n", hash);
+    if commit.parents.len() > 1 {
+        let parents: Vec<&str> = commit.parents.iter().map(|p| &p[..7]).collect();
+        output += &format!("Merge: {}\n", parents.join(" "));
+    }
+    let author = commit.author_text();
+    output += &format!("Author: {} <{}>\n", author.name, author.email);
+    output += &format!(
+        "Date:   {}\n\n",
+        commit_util::format_date(author.timestamp, &author.timezone)
+    );
+    for line in commit.message_text().trim_end().lines() {
+        output += &format!("    {}\n", line);
+    }
+    output
+}
+
+/// Draws the commit graph, one column for each line of history being followed
+#[derive(Debug, Default)]
+struct Graph {
+    /// The commit expected next in each column
+    columns: Vec<String>,
+}
+
+impl Graph {
+    /// Renders a commit's row, its text, and the lines joining it to its parents
+    ///
+    /// With `separate`, a line without a commit is drawn first to separate it from the previous one
+    fn render(&mut self, hash: &str, parents: &[String], text: &str, separate: bool) -> String {
+        let before = self.columns.clone();
+        let index = match self.columns.iter().position(|c| c == hash) {
+            Some(i) => i,
+            None => {
+                self.columns.push(hash.to_string());
+                self.columns.len() - 1
+            }
+        };
+        let star_row = row(self.columns.len(), |i| if i == index { '*' } else { '|' });
+        let connector = self.advance(index, parents);
+
+        let width = 2 * before.len().max(self.columns.len()).max(1);
+        let pad = |s: String| format!("{:<width$}", s, width = width);
+
+        let mut output = String::new();
+        if separate {
+            output += &format!("{}\n", pad(row(before.len(), |_| '|')));
+        }
+
+        let mut lines = text.lines();
+        output += &format!("{}{}\n", pad(star_row), lines.next().unwrap_or(""));
+        if let Some(connector) = connector {
+            output += &format!("{}{}\n", pad(connector), lines.next().unwrap_or(""));
+        }
+        let prefix = pad(row(self.columns.len(), |_| '|'));
+        for line in lines {
+            output += &format!("{}{}\n", prefix, line);
+        }
+        output
+    }
+
+    /// Moves a commit that isn't shown on to its parents without drawing anything
+    fn skip(&mut self, hash: &str, parents: &[String]) {
+        if let Some(index) = self.columns.iter().position(|c| c == hash) {
+            self.advance(index, parents);
+        }
+    }
+
+    /// Replaces the commit in column `index` with its parents
+    ///
+    /// Returns the line drawing any columns that were opened or closed
+    fn advance(&mut self, index: usize, parents: &[String]) -> Option<String> {
+        let width = self.columns.len();
+
+        // Parents already followed by another column join it instead of getting a new one
+        let new_parents: Vec<String> = parents
+            .iter()
+            .filter(|p| !self.columns.contains(p))
+            .cloned()
+            .collect();
+
+        if new_parents.len() > 1 {
+            // A merge opens new columns to the right of the commit
+            let added = new_parents.len() - 1;
+            self.columns.splice(index..index + 1, new_parents);
+
+            let mut line = vec![' '; 2 * (width + added) - 1];
+            for c in line.iter_mut().step_by(2).take(index + 1) {
+                *c = '|';
+            }
+            for column in index + 1..width + added {
+                line[2 * column - 1] = '\\';
+            }
+            return Some(line.into_iter().collect());
+        }
+        if !new_parents.is_empty() {
+            self.columns.splice(index..index + 1, new_parents);
+            return None;
+        }
+
+        // The column ends, either at a root commit or by joining the column of its parent.
+        // When joining, the rightmost of the two columns is the one that ends.
+        let (removed, first_moved) = match parents
+            .first()
+            .and_then(|p| self.columns.iter().position(|c| c == p))
+        {
+            Some(other) if other > index => {
+                self.columns[index] = self.columns[other].clone();
+                (other, other)
+            }
+            Some(other) => (index, index.max(other + 1)),
+            None => (index, index + 1),
+        };
+        self.columns.remove(removed);
+
+        if first_moved >= width {
+            return None;
+        }
+        let mut line = vec![' '; 2 * width - 2];
+        for c in line.iter_mut().step_by(2).take(removed) {
+            *c = '|';
+        }
+        for column in first_moved..width {
+            line[2 * column - 1] = '/';
+        }
+        Some(line.into_iter().collect::<String>().trim_end().to_string())
+    }
+}
+
+/// Draws one character per column, separated by spaces
+fn row(columns: usize, cell: impl Fn(usize) -> char) -> String {
+    let cells: Vec<String> = (0..columns).map(|i| cell(i).to_string()).collect();
+    cells.join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn to_args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|a| a.to_string()).collect()
+    }
+
+    #[test]
+    fn test_parse_args() {
+        let options =
+            parse_args(&to_args(&["--oneline", "-n", "3", "master", "--", "src/"])).unwrap();
+        assert_eq!(
+            LogOptions {
+                rev: Some("master".to_string()),
+                max_count: Some(3),
+                oneline: true,
+                graph: false,
+                paths: vec!["src".to_string()],
+            },
+            options
+        );
+    }
+
+    #[test]
+    fn test_parse_args_short_count() {
+        assert_eq!(Some(5), parse_args(&to_args(&["-5"])).unwrap().max_count);
+        assert!(parse_args(&to_args(&["-n", "x"])).is_err());
+    }
+
+    #[test]
+    fn test_graph_merge() {
+        let a = "a".repeat(40);
+        let b = "b".repeat(40);
+        let c = "c".repeat(40);
+        let d = "d".repeat(40);
+        let mut graph = Graph::default();
+        let mut output = String::new();
+        output += &graph.render(&a, &[b.clone(), c.clone()], "merge", false);
+        output += &graph.render(&b, std::slice::from_ref(&d), "ours", false);
+        output += &graph.render(&c, std::slice::from_ref(&d), "theirs", false);
+        output += &graph.render(&d, &[], "base", false);
+        assert_eq!(
+            "*   merge\n|\\  \n* | ours\n| * theirs\n|/  \n* base\n",
+            output
+        );
+    }
+
+    #[test]
+    fn test_graph_skipped_commits() {
+        let hashes: Vec<String> = ["a", "b", "c", "d"].iter().map(|h| h.repeat(40)).collect();
+        let mut graph = Graph::default();
+        let mut output = String::new();
+        output += &graph.render(&hashes[0], &hashes[1..2], "first", false);
+        graph.skip(&hashes[1], &hashes[2..3]);
+        graph.skip(&hashes[2], &hashes[3..4]);
+        output += &graph.render(&hashes[3], &[], "last", false);
+        assert_eq!("* first\n* last\n", output);
+    }
+}
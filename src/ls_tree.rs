@@ -4,9 +4,7 @@ use std::string::String;
 
 /// Returns the contents of a [`Tree`] object
 pub fn ls_tree(object_hash: &str) -> String {
-    let git_object = object_util::read_object_file(object_hash);
-
-    let decoded = object_util::decode_object(git_object);
+    let decoded = object_util::read_object_store(object_hash);
 
     format_tree(&decoded)
 }
@@ -15,17 +13,14 @@ pub fn ls_tree(object_hash: &str) -> String {
 ///
 /// If you have the object's hash, use [`ls_tree`] instead.
 pub fn format_tree(decoded: &[u8]) -> String {
-    let mut formatted_tree = String::new();
-
     // A tree is a zlib compressed file of a header and a list of file information
     // An object header is the type of object, a space, the size of the contents in bytes, then a null byte
     // File information is the permissions, space, the filename, null byte, the hex hash (20 bytes)
     let found_header = decoded.iter().enumerate().find(|x| x.1 == &0u8);
-    let header_end: usize;
-    match found_header {
-        Some((i, _)) => header_end = i,
+    let header_end = match found_header {
+        Some((i, _)) => i,
         None => return "fatal: invalid header".to_string(),
-    }
+    };
 
     let header = &decoded[..header_end];
 
@@ -34,55 +29,115 @@ pub fn format_tree(decoded: &[u8]) -> String {
         return "fatal: not a tree object".to_string();
     }
 
-    let mut permissions = Vec::<&str>::new();
-    let mut filenames = Vec::<&str>::new();
-    let mut hashes = Vec::<String>::new();
-
     // Add 1 to skip over the null byte we searched for earlier
-    let mut next_parse = &decoded[header_end + 1..];
-    while !next_parse.is_empty() {
-        let found_section = next_parse.iter().enumerate().find(|x| x.1 == &0u8);
-        let mut section_end: usize;
-        match found_section {
-            Some((i, _)) => section_end = i,
-            None => section_end = 0,
-        }
+    let entries = match parse_tree(&decoded[header_end + 1..]) {
+        Ok(entries) => entries,
+        Err(e) => return e,
+    };
 
-        if section_end == 0 {
-            break;
-        }
+    format_entries(&entries)
+}
 
-        let section = &next_parse[..section_end];
-        // Increase section_end past the null byte
-        // This slightly simplifies the math to read the hash
-        section_end += 1;
+/// Formats tree entries one per line, as `<mode> <type> <hash>\t<name>`
+pub fn format_entries(entries: &[TreeEntry]) -> String {
+    let mut formatted_tree = String::new();
+    for entry in entries.iter() {
+        formatted_tree += &format!(
+            "{:0>6} {} {}\t{}\n",
+            entry.mode,
+            entry.object_type(),
+            entry.hash,
+            entry.name
+        );
+    }
+
+    formatted_tree
+}
+
+/// Parses the content of a tree object (without the object header) into its entries
+///
+/// # Format
+///
+/// Each entry is the mode, a space, the name, a null byte, then the 20 byte hash
+pub fn parse_tree(content: &[u8]) -> Result<Vec<TreeEntry>, String> {
+    let invalid = || "fatal: invalid tree object".to_string();
+    let mut entries = Vec::new();
+
+    let mut next_parse = content;
+    while !next_parse.is_empty() {
+        let mode_end = next_parse
+            .iter()
+            .position(|x| x == &32u8)
+            .ok_or_else(invalid)?;
+        let name_end = next_parse
+            .iter()
+            .position(|x| x == &0u8)
+            .ok_or_else(invalid)?;
+        // Read next 20 bytes after the null byte to get the hash
+        if name_end < mode_end || next_parse.len() < name_end + 21 {
+            return Err(invalid());
+        }
 
-        // Permissions should be 6 bytes
-        permissions.push(str::from_utf8(&section[..6]).unwrap_or("000000"));
-        // Start at 7 to skip over the ' ' separator
-        filenames.push(str::from_utf8(&section[7..]).unwrap_or(""));
-        // Read next 20 bytes to get the hash
-        hashes.push(object_util::to_hex_string(
-            &next_parse[section_end..section_end + 20],
-        ));
+        entries.push(TreeEntry {
+            mode: str::from_utf8(&next_parse[..mode_end])
+                .unwrap_or("000000")
+                .to_string(),
+            name: String::from_utf8_lossy(&next_parse[mode_end + 1..name_end]).to_string(),
+            hash: object_util::to_hex_string(&next_parse[name_end + 1..name_end + 21]),
+        });
 
         // Start where we left off reading the hash
-        section_end += 20;
-        next_parse = &next_parse[section_end..];
+        next_parse = &next_parse[name_end + 21..];
     }
 
-    for i in 0..permissions.len() {
-        let git_object = object_util::read_object_file(&hashes[i]);
-        let decoded = object_util::decode_object(git_object);
-        let header = decoded.split(|num| num == &0u8).next().unwrap();
-        let header_type = object_util::get_header_type(header);
-        formatted_tree += &format!(
-            "{:0>6} {} {}\t{}\n",
-            permissions[i], header_type, hashes[i], filenames[i]
-        );
+    Ok(entries)
+}
+
+/// Looks up a path (e.g. `src/main.rs`) inside a tree, descending into subtrees
+///
+/// Returns the matching entry
+pub fn find_path(tree_hash: &str, path: &str) -> Result<TreeEntry, String> {
+    let not_found = || format!("fatal: path '{}' does not exist", path);
+
+    let mut tree = tree_hash.to_string();
+    let mut parts = path.split('/').filter(|p| !p.is_empty()).peekable();
+    while let Some(part) = parts.next() {
+        let (object_type, content) = object_util::read_object(&tree);
+        if object_type != object_util::Object::Tree {
+            return Err(not_found());
+        }
+
+        let entry = parse_tree(&content)?
+            .into_iter()
+            .find(|e| e.name == part)
+            .ok_or_else(not_found)?;
+
+        if parts.peek().is_none() {
+            return Ok(entry);
+        }
+        tree = entry.hash;
     }
 
-    formatted_tree
+    Err(not_found())
+}
+
+/// An entry in a tree object
+#[derive(Debug, Clone, PartialEq)]
+pub struct TreeEntry {
+    pub mode: String,
+    pub name: String,
+    pub hash: String,
+}
+
+impl TreeEntry {
+    /// Returns the type of object the entry points to, based on its mode
+    pub fn object_type(&self) -> object_util::Object {
+        match self.mode.as_str() {
+            "40000" | "040000" => object_util::Object::Tree,
+            "160000" => object_util::Object::Commit,
+            _ => object_util::Object::Blob,
+        }
+    }
 }
 
 #[cfg(test)]
@@ -94,4 +149,27 @@ mod tests {
         let decoded = [116u8, 114, 101, 101, 32, 48, 0];
         assert_eq!("", format_tree(&decoded));
     }
+
+    #[test]
+    fn test_parse_tree_with_subtree() {
+        let mut content = b"100644 a.txt\0".to_vec();
+        content.extend_from_slice(&[0x11; 20]);
+        content.extend_from_slice(b"40000 src\0");
+        content.extend_from_slice(&[0x22; 20]);
+
+        let entries = parse_tree(&content).unwrap();
+        assert_eq!(2, entries.len());
+        assert_eq!("100644", entries[0].mode);
+        assert_eq!("a.txt", entries[0].name);
+        assert_eq!("40000", entries[1].mode);
+        assert_eq!("src", entries[1].name);
+        assert_eq!("2".repeat(40), entries[1].hash);
+        assert_eq!(object_util::Object::Tree, entries[1].object_type());
+    }
+
+    #[test]
+    fn test_parse_tree_truncated_hash() {
+        let content = b"100644 a.txt\0\x11\x11".to_vec();
+        assert!(parse_tree(&content).is_err());
+    }
 }
@@ -1,14 +1,46 @@
 use std::env;
+use std::io;
 
 use update_index::update_index;
 
+mod add;
+mod branch;
+mod cache_tree_util;
 mod cat_file;
+mod check_ignore;
+mod checkout;
+mod commit;
+mod commit_tree;
+mod commit_util;
+mod config_util;
+mod delta_util;
+mod fsck;
 mod hash_object;
+mod ignore_util;
+mod index_pack;
 mod index_util;
 mod init;
+mod lock_util;
+mod log;
 mod ls_tree;
 mod object_util;
+mod pack_objects;
+mod pack_util;
+mod read_tree;
+mod ref_util;
+mod reflog;
+mod reflog_util;
+mod repack;
+mod resolve_undo_util;
+mod rev_parse;
+mod status;
+mod symbolic_ref;
+mod tag;
+mod tag_util;
+mod timezone_util;
 mod update_index;
+mod update_ref;
+mod verify_pack;
 mod write_tree;
 
 fn main() {
@@ -29,14 +61,27 @@ fn main() {
                 env::current_dir().unwrap().to_str().unwrap()
             )
         }
+        _ if command == "add" => {
+            let result =
+                add::parse_args(&args[2..]).and_then(|(paths, force)| add::add(&paths, force));
+            if let Err(e) = result {
+                println!("{}", e);
+            }
+        }
+        _ if command == "branch" => {
+            match branch::parse_args(&args[2..]).and_then(|a| branch::branch(&a)) {
+                Ok(output) => print!("{}", output),
+                Err(e) => println!("{}", e),
+            }
+        }
         _ if command == "cat-file" => {
             if args.len() >= 4 {
-                let params = cat_file::parse_args(&args[2], &args[3]);
-                match params {
-                    // cat_file output has newlines included, so don't reprint them here
-                    Ok((flag, hash)) => print!("{}", cat_file::cat_file(flag, &hash)),
-                    Err(e) => println!("{}", e),
-                };
+                let result = cat_file::parse_args(&args[2], &args[3]).and_then(|(flag, hash)| {
+                    cat_file::cat_file(flag, &hash, &mut io::stdout().lock())
+                });
+                if let Err(e) = result {
+                    println!("{}", e);
+                }
             } else {
                 println!("usage: cat-file (-t | -s | -p) <object>\n");
                 println!("    -t\t\tshow the object type");
@@ -44,6 +89,10 @@ fn main() {
                 println!("    -p\t\tPretty print object's contents");
             }
         }
+        _ if command == "fsck" => match fsck::parse_args(&args[2..]).and_then(|o| fsck::fsck(&o)) {
+            Ok(output) => print!("{}", output),
+            Err(e) => println!("{}", e),
+        },
         _ if command == "hash-object" => {
             if args.len() >= 4 {
                 if args[2] == "-w" {
@@ -56,13 +105,50 @@ fn main() {
                 println!("    -w\t\twrite the object into the object database");
             }
         }
+        _ if command == "index-pack" => {
+            match index_pack::parse_args(&args[2..]).and_then(|o| index_pack::index_pack(&o)) {
+                Ok(output) => print!("{}", output),
+                Err(e) => println!("{}", e),
+            }
+        }
+        _ if command == "log" => match log::parse_args(&args[2..]).and_then(|o| log::log(&o)) {
+            Ok(output) => print!("{}", output),
+            Err(e) => println!("{}", e),
+        },
         _ if command == "ls-tree" => {
             if args.len() >= 3 {
-                print!("{}", ls_tree::ls_tree(&args[2]))
+                let tree = rev_parse::rev_parse(&args[2])
+                    .and_then(|hash| rev_parse::peel(&hash, Some(object_util::Object::Tree)));
+                match tree {
+                    Ok(hash) => print!("{}", ls_tree::ls_tree(&hash)),
+                    Err(e) => println!("{}", e),
+                }
             } else {
                 println!("usage: ls-tree <object>\n");
             }
         }
+        _ if command == "pack-objects" => {
+            let result =
+                pack_objects::parse_args(&args[2..]).and_then(|o| pack_objects::pack_objects(&o));
+            match result {
+                Ok(output) => print!("{}", output),
+                Err(e) => println!("{}", e),
+            }
+        }
+        _ if command == "repack" => {
+            match repack::parse_args(&args[2..]).and_then(|o| repack::repack(&o)) {
+                Ok(output) => print!("{}", output),
+                Err(e) => println!("{}", e),
+            }
+        }
+        _ if command == "verify-pack" => {
+            let result =
+                verify_pack::parse_args(&args[2..]).and_then(|o| verify_pack::verify_pack(&o));
+            match result {
+                Ok(output) => print!("{}", output),
+                Err(e) => println!("{}", e),
+            }
+        }
         _ if command == "write-tree" => {
             if args.len() >= 3 {
                 if args[2] == "--missing-ok" {
@@ -75,17 +161,134 @@ fn main() {
                 println!("{}", write_tree::write_tree(false));
             }
         }
+        _ if command == "read-tree" => {
+            let result = read_tree::parse_args(&args[2..])
+                .and_then(|(trees, merge)| read_tree::read_tree(&trees, merge));
+            if let Err(e) = result {
+                println!("{}", e);
+            }
+        }
         _ if command == "update-index" => {
             if args.len() >= 4 {
                 if args[2] == "--add" {
                     if let Err(s) = update_index::add_to_index(&args[3]) {
                         println!("{}", s);
                     }
+                } else if args[2] == "--index-version" {
+                    if let Err(s) = update_index::set_index_version(&args[3]) {
+                        println!("{}", s);
+                    }
                 }
             } else if let Err(s) = update_index() {
                 println!("{}", s);
             }
         }
+        _ if command == "check-ignore" => match check_ignore::parse_args(&args[2..]) {
+            Ok((paths, verbose)) => print!("{}", check_ignore::check_ignore(&paths, verbose)),
+            Err(e) => println!("{}", e),
+        },
+        _ if command == "checkout" || command == "switch" => {
+            if args.len() >= 3 {
+                let result = checkout::parse_args(&args[2..], command == "switch")
+                    .and_then(|options| checkout::checkout(&options));
+                match result {
+                    Ok(message) => println!("{}", message),
+                    Err(e) => println!("{}", e),
+                }
+            } else if command == "switch" {
+                println!("usage: switch [-f] [--detach] [-c <new-branch>] <branch>\n");
+                println!("    -c <branch>\t\tcreate and switch to a new branch");
+                println!("    --detach\t\tdetach HEAD at named commit");
+                println!("    -f, --force\t\tthrow away local modifications");
+            } else {
+                println!("usage: checkout [-f] [--detach] [-b <new-branch>] <branch>\n");
+                println!("    -b <branch>\t\tcreate and checkout a new branch");
+                println!("    --detach\t\tdetach HEAD at named commit");
+                println!("    -f, --force\t\tthrow away local modifications");
+            }
+        }
+        _ if command == "commit" => {
+            if args.len() >= 3 {
+                match commit::parse_args(&args[2..]).and_then(|m| commit::commit(&m)) {
+                    Ok(summary) => println!("{}", summary),
+                    Err(e) => println!("{}", e),
+                }
+            } else {
+                println!("usage: commit -m <message>\n");
+                println!("    -m <message>\tcommit message");
+            }
+        }
+        _ if command == "commit-tree" => {
+            if args.len() >= 3 {
+                let result =
+                    commit_tree::parse_args(&args[2..]).and_then(|(tree, parents, message)| {
+                        commit_tree::commit_tree(&tree, &parents, &message)
+                    });
+                match result {
+                    Ok(hash) => println!("{}", hash),
+                    Err(e) => println!("{}", e),
+                }
+            } else {
+                println!("usage: commit-tree <tree> [(-p <parent>)...] [(-m <message>)...]\n");
+                println!("    -p <parent>\t\tid of a parent commit object");
+                println!("    -m <message>\tcommit message");
+            }
+        }
+        _ if command == "update-ref" => {
+            let result =
+                update_ref::parse_args(&args[2..]).and_then(|o| update_ref::update_ref(&o));
+            if let Err(e) = result {
+                println!("{}", e);
+            }
+        }
+        _ if command == "reflog" => {
+            match reflog::parse_args(&args[2..]).and_then(|a| reflog::reflog(&a)) {
+                Ok(output) => print!("{}", output),
+                Err(e) => println!("{}", e),
+            }
+        }
+        _ if command == "rev-parse" => {
+            if args.len() >= 3 {
+                for rev in args[2..].iter() {
+                    match rev_parse::rev_parse(rev) {
+                        Ok(hash) => println!("{}", hash),
+                        Err(e) => {
+                            println!("{}", e);
+                            break;
+                        }
+                    }
+                }
+            } else {
+                println!("usage: rev-parse <revision>...\n");
+            }
+        }
+        _ if command == "show-ref" => {
+            for (name, hash) in ref_util::list_refs("refs/") {
+                println!("{} {}", hash, name);
+            }
+        }
+        _ if command == "status" => match status::parse_args(&args[2..]).and_then(status::status) {
+            Ok(output) => print!("{}", output),
+            Err(e) => println!("{}", e),
+        },
+        _ if command == "symbolic-ref" => {
+            if args.len() >= 4 {
+                if let Err(e) = symbolic_ref::write_symbolic_ref(&args[2], &args[3]) {
+                    println!("{}", e);
+                }
+            } else if args.len() >= 3 {
+                match symbolic_ref::read_symbolic_ref(&args[2]) {
+                    Ok(target) => println!("{}", target),
+                    Err(e) => println!("{}", e),
+                }
+            } else {
+                println!("usage: symbolic-ref <name> [<ref>]\n");
+            }
+        }
+        _ if command == "tag" => match tag::parse_args(&args[2..]).and_then(|a| tag::tag(&a)) {
+            Ok(output) => print!("{}", output),
+            Err(e) => println!("{}", e),
+        },
         _ => println!("{} is not recognized as a valid command", command),
     }
 }
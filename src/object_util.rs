@@ -1,24 +1,37 @@
+use super::pack_util;
 use flate2::read::ZlibDecoder;
 use flate2::write::ZlibEncoder;
+use sha1::Sha1;
 use std::fmt;
 use std::fs;
-use std::io::Read;
-use std::io::Write;
+use std::fs::File;
+use std::io;
+use std::io::{BufReader, BufWriter, Read, Write};
 use std::path::Path;
+use std::process;
 use std::str;
 use std::string::String;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::vec::Vec;
 
+/// How much of an object is read or written at a time when streaming it
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Counts the temporary object files of this process, so each has its own name
+static TMP_OBJECT_COUNT: AtomicUsize = AtomicUsize::new(0);
+
 /// The possible types for a git object
 ///
 /// * Blob
 /// * Tree
 /// * Commit
-#[derive(Debug, PartialEq)]
+/// * Tag
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum Object {
     Blob,
     Tree,
     Commit,
+    Tag,
 }
 
 impl fmt::Display for Object {
@@ -27,6 +40,7 @@ impl fmt::Display for Object {
             Object::Blob => write!(f, "blob"),
             Object::Tree => write!(f, "tree"),
             Object::Commit => write!(f, "commit"),
+            Object::Tag => write!(f, "tag"),
         }
     }
 }
@@ -41,28 +55,20 @@ pub fn get_header_type(header: &[u8]) -> Object {
     let mut split = header.split(|c| c == &32u8);
     let header_type = split.next().expect("Invalid header");
 
-    let tmp = str::from_utf8(header_type).unwrap();
+    let tmp = str::from_utf8(header_type).expect("Invalid header");
 
-    match tmp {
-        _ if tmp == "blob" => Object::Blob,
-        _ if tmp == "tree" => Object::Tree,
-        _ if tmp == "commit" => Object::Commit,
-        _ => panic!("Invalid header"),
-    }
+    parse_type(tmp).expect("Invalid header")
 }
 
-/// Given a git header, returns the size of an object's contents
-///
-/// # Format
-///
-/// An object header is the type of object, a space, the size of the contents in bytes, then a null byte
-pub fn get_header_size(header: &[u8]) -> &str {
-    // space (' ') is 32(dec) in ascii
-    let mut split = header.split(|c| c == &32u8);
-    let _ = split.next().expect("Invalid header");
-    let header_size = split.next().expect("Invalid header");
-
-    str::from_utf8(header_size).unwrap()
+/// Returns the object type with the given name, e.g. `blob`
+pub fn parse_type(name: &str) -> Result<Object, String> {
+    match name {
+        "blob" => Ok(Object::Blob),
+        "tree" => Ok(Object::Tree),
+        "commit" => Ok(Object::Commit),
+        "tag" => Ok(Object::Tag),
+        _ => Err(format!("fatal: invalid object type \"{}\"", name)),
+    }
 }
 
 /// Given an object hash, return its relative path
@@ -109,6 +115,251 @@ pub fn write_object_file(object_hash: &str, store: &[u8]) {
     }
 }
 
+/// Returns the hash the content would have as an object of the given type
+pub fn hash_object(object_type: Object, content: &[u8]) -> String {
+    let mut hasher = Sha1::new();
+    hasher.update(format!("{} {}\0", object_type, content.len()).as_bytes());
+    hasher.update(content);
+    hasher.hexdigest()
+}
+
+/// Creates the store for an object, its header followed by its content
+///
+/// The content is kept as bytes, so any blob can be stored, not only text
+pub fn create_store(object_type: Object, content: &[u8]) -> Vec<u8> {
+    let mut store = format!("{} {}\0", object_type, content.len()).into_bytes();
+    store.extend_from_slice(content);
+    store
+}
+
+/// Hashes the content as an object of the given type and writes it to the object database
+///
+/// Returns the hash of the new object
+pub fn write_object(object_type: Object, content: &[u8]) -> String {
+    let store = create_store(object_type, content);
+    let hash = Sha1::from(&store).hexdigest();
+
+    write_object_file(&hash, &store);
+
+    hash
+}
+
+/// Hashes `size` bytes from the reader as an object of the given type, a chunk at a time
+///
+/// Returns the hash of the object
+pub fn hash_object_stream<R: Read>(
+    object_type: Object,
+    size: u64,
+    reader: R,
+) -> Result<String, String> {
+    copy_object(object_type, size, reader, &mut io::sink())
+}
+
+/// Hashes and compresses `size` bytes from the reader as an object of the given type,
+/// a chunk at a time so the whole object is never in memory
+///
+/// The object is written to a temporary file and moved into place once its hash is known.
+/// Returns the hash of the object
+pub fn write_object_stream<R: Read>(
+    object_type: Object,
+    size: u64,
+    reader: R,
+) -> Result<String, String> {
+    let count = TMP_OBJECT_COUNT.fetch_add(1, Ordering::Relaxed);
+    let mut tmp = TempObject {
+        path: format!("gitrs/objects/tmp_obj_{}_{}", process::id(), count),
+        kept: false,
+    };
+    let write_error = |e: io::Error| format!("error: unable to write {}: {}", tmp.path, e);
+
+    let file = File::create(&tmp.path).map_err(write_error)?;
+    let mut encoder = ZlibEncoder::new(BufWriter::new(file), flate2::Compression::default());
+    let hash = copy_object(object_type, size, reader, &mut encoder)?;
+    encoder
+        .finish()
+        .and_then(|mut out| out.flush())
+        .map_err(write_error)?;
+
+    let path = get_object_path(&hash);
+    if Path::new(&path).exists() {
+        return Ok(hash);
+    }
+    fs::create_dir_all(format!("gitrs/objects/{}", &hash[..2])).map_err(write_error)?;
+    fs::rename(&tmp.path, &path).map_err(write_error)?;
+    tmp.kept = true;
+    Ok(hash)
+}
+
+/// A temporary object file, removed when dropped unless it was moved into place
+struct TempObject {
+    path: String,
+    kept: bool,
+}
+
+impl Drop for TempObject {
+    fn drop(&mut self) {
+        if !self.kept {
+            let _ = fs::remove_file(&self.path);
+        }
+    }
+}
+
+/// Writes the header and then the content from the reader, hashing both on the way
+///
+/// Fails if the reader doesn't hold exactly `size` bytes, like a file changing as it is read
+fn copy_object<R: Read, W: Write>(
+    object_type: Object,
+    size: u64,
+    mut reader: R,
+    out: &mut W,
+) -> Result<String, String> {
+    let mut hasher = Sha1::new();
+    let header = format!("{} {}\0", object_type, size);
+    hasher.update(header.as_bytes());
+    out.write_all(header.as_bytes())
+        .map_err(|e| e.to_string())?;
+
+    let mut buffer = vec![0u8; CHUNK_SIZE];
+    let mut total = 0u64;
+    loop {
+        let n = reader.read(&mut buffer).map_err(|e| e.to_string())?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buffer[..n]);
+        out.write_all(&buffer[..n]).map_err(|e| e.to_string())?;
+        total += n as u64;
+    }
+
+    if total != size {
+        return Err(format!(
+            "error: expected {} bytes but read {}, the file changed while it was read",
+            size, total
+        ));
+    }
+    Ok(hasher.hexdigest())
+}
+
+/// An object whose content is read a chunk at a time
+pub struct ObjectReader {
+    pub object_type: Object,
+    /// The size of the content, without the header
+    pub size: u64,
+    content: Box<dyn Read>,
+}
+
+impl ObjectReader {
+    pub fn new(object_type: Object, size: u64, content: Box<dyn Read>) -> ObjectReader {
+        ObjectReader {
+            object_type,
+            size,
+            content,
+        }
+    }
+}
+
+impl Read for ObjectReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.content.read(buf)
+    }
+}
+
+/// Opens an object so its content can be read incrementally
+///
+/// Only the header is read up front. Loose objects and whole packed objects are
+/// inflated as they are read, deltas have to be rebuilt in memory first.
+pub fn open_object(object_hash: &str) -> Result<ObjectReader, String> {
+    let path = get_object_path(object_hash);
+    if !Path::new(&path).exists() {
+        return pack_util::open_packed_object(object_hash)?
+            .ok_or_else(|| format!("fatal: Not a valid object name {}", object_hash));
+    }
+
+    let file = File::open(&path).map_err(|e| format!("error: unable to open {}: {}", path, e))?;
+    let mut decoder = ZlibDecoder::new(BufReader::new(file));
+    let invalid = || format!("fatal: invalid object header {}", object_hash);
+
+    // The header is short, so it is read a byte at a time up to the null byte
+    let mut header = Vec::new();
+    let mut byte = [0u8; 1];
+    loop {
+        decoder.read_exact(&mut byte).map_err(|_| invalid())?;
+        if byte[0] == 0 {
+            break;
+        }
+        if header.len() > 32 {
+            return Err(invalid());
+        }
+        header.push(byte[0]);
+    }
+
+    let (object_type, size) = parse_header(&header).ok_or_else(invalid)?;
+    Ok(ObjectReader::new(
+        parse_type(object_type)?,
+        size,
+        Box::new(decoder),
+    ))
+}
+
+/// Given a git header without its null byte, returns the type name and the size of the contents
+///
+/// # Format
+///
+/// An object header is the type of object, a space, the size of the contents in bytes, then a null byte
+fn parse_header(header: &[u8]) -> Option<(&str, u64)> {
+    let header = str::from_utf8(header).ok()?;
+    let (object_type, size) = header.split_once(' ')?;
+    Some((object_type, size.parse().ok()?))
+}
+
+/// Returns true if the object is in the object database, loose or packed
+///
+/// Only full hashes are looked up, use `rev_parse` to expand abbreviated hashes first
+pub fn object_exists(object_hash: &str) -> bool {
+    is_valid_hash(object_hash)
+        && (Path::new(&get_object_path(object_hash)).exists()
+            || pack_util::has_packed_object(object_hash))
+}
+
+/// Returns the decoded store (header and content) of the given object
+///
+/// Loose objects are read first, then the packs are searched
+pub fn read_object_store(object_hash: &str) -> Vec<u8> {
+    let path = get_object_path(object_hash);
+    if Path::new(&path).exists() {
+        return decode_object(read_object_file(object_hash));
+    }
+
+    match pack_util::read_packed_object(object_hash) {
+        Ok(Some((object_type, content))) => {
+            let mut store = format!("{} {}\0", object_type, content.len()).into_bytes();
+            store.extend_from_slice(&content);
+            store
+        }
+        Ok(None) => panic!("Could not read object file: {}", path),
+        Err(e) => panic!("{}", e),
+    }
+}
+
+/// Returns the type and the content (without the header) of the given object
+pub fn read_object(object_hash: &str) -> (Object, Vec<u8>) {
+    let mut decoded = read_object_store(object_hash);
+    let header_end = decoded
+        .iter()
+        .position(|num| num == &0u8)
+        .expect("Invalid header");
+    let object_type = get_header_type(&decoded[..header_end]);
+    let content = decoded.split_off(header_end + 1);
+    (object_type, content)
+}
+
+/// Returns the type of the given object
+///
+/// Only the header is read
+pub fn read_object_type(object_hash: &str) -> Result<Object, String> {
+    open_object(object_hash).map(|object| object.object_type)
+}
+
 /// Decode/decompress a Zlib compressed byte sequence
 pub fn decode_object(object: Vec<u8>) -> Vec<u8> {
     let mut decoder = ZlibDecoder::new(&object[..]);
@@ -120,6 +371,11 @@ pub fn decode_object(object: Vec<u8>) -> Vec<u8> {
     decompressed
 }
 
+/// Returns true if the string is a full 40 character hex object hash
+pub fn is_valid_hash(object_hash: &str) -> bool {
+    object_hash.len() == 40 && object_hash.chars().all(|c| c.is_ascii_hexdigit())
+}
+
 /// Converts a u8 byte array to a string of hex bytes
 ///
 /// Single digits are zero padded
@@ -132,6 +388,19 @@ pub fn to_hex_string(bytes: &[u8]) -> String {
     hex_string
 }
 
+/// Converts a string of hex bytes to a u8 array
+///
+/// Returns `None` if the string isn't an even number of hex digits
+pub fn from_hex_string(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).ok())
+        .collect()
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -154,6 +423,12 @@ mod tests {
         assert_eq!(Object::Commit, get_header_type(&header));
     }
 
+    #[test]
+    fn test_get_header_type_with_tag() {
+        let header = [0x74, 0x61, 0x67, 32u8, 0x31, 0x35, 0x0];
+        assert_eq!(Object::Tag, get_header_type(&header));
+    }
+
     #[test]
     #[should_panic(expected = "Invalid header")]
     fn test_get_header_type_with_invalid_object() {
@@ -169,22 +444,72 @@ mod tests {
     }
 
     #[test]
-    fn test_get_header_size_when_valid() {
-        let header = [0x62, 0x6C, 0x6F, 0x62, 32u8, 0x31, 0x35, 0x0];
-        assert_eq!("15\0", get_header_size(&header))
+    fn test_parse_header_when_valid() {
+        let header = [0x62, 0x6C, 0x6F, 0x62, 32u8, 0x31, 0x35];
+        assert_eq!(Some(("blob", 15)), parse_header(&header));
     }
 
     #[test]
-    #[should_panic(expected = "Invalid header")]
-    fn test_get_header_size_with_short_header() {
+    fn test_parse_header_with_short_header() {
         let header = [0x62, 0x6C, 0x6F, 0x62];
-        let _ = get_header_size(&header);
+        assert_eq!(None, parse_header(&header));
+    }
+
+    #[test]
+    fn test_parse_header_with_extra_spaces() {
+        let header = [0x62, 0x6C, 0x6F, 0x62, 32u8, 32u8, 0x31, 0x35];
+        assert_eq!(None, parse_header(&header));
+    }
+
+    #[test]
+    fn test_is_valid_hash() {
+        assert!(is_valid_hash("4b825dc642cb6eb9a060e54bf8d69288fbee4904"));
+        assert!(!is_valid_hash("4b825dc"));
+        assert!(!is_valid_hash("zb825dc642cb6eb9a060e54bf8d69288fbee4904"));
+    }
+
+    #[test]
+    fn test_hash_object() {
+        assert_eq!(
+            "e69de29bb2d1d6434b8b29ae775ad8c2e48c5391",
+            hash_object(Object::Blob, b"")
+        );
+        assert_eq!(
+            "4b825dc642cb6eb9a060e54bf8d69288fbee4904",
+            hash_object(Object::Tree, b"")
+        );
+    }
+
+    #[test]
+    fn test_create_store_blob() {
+        let store = b"blob 12\0Hello, World".to_vec();
+        assert_eq!(store, create_store(Object::Blob, b"Hello, World"));
+    }
+
+    #[test]
+    fn test_create_store_binary_blob() {
+        let content = b"\xff\x00\xfe\x89PNG\r\n";
+        let store = create_store(Object::Blob, content);
+        assert_eq!(b"blob 9\0", &store[..7]);
+        assert_eq!(content, &store[7..]);
+        assert_eq!(
+            "8011e4dc51aa0074c21a543916b2d34d47a420e2",
+            hash_object(Object::Blob, content)
+        );
+        assert_eq!(
+            Ok(hash_object(Object::Blob, content)),
+            hash_object_stream(Object::Blob, content.len() as u64, &content[..])
+        );
     }
 
     #[test]
-    fn test_get_header_size_with_extra_spaces() {
-        let header = [0x62, 0x6C, 0x6F, 0x62, 32u8, 32u8, 0x31, 0x35, 0x0];
-        assert_eq!("", get_header_size(&header));
+    fn test_hash_object_stream() {
+        let content = vec![7u8; CHUNK_SIZE * 2 + 5];
+        assert_eq!(
+            Ok(hash_object(Object::Blob, &content)),
+            hash_object_stream(Object::Blob, content.len() as u64, content.as_slice())
+        );
+        assert!(hash_object_stream(Object::Blob, 3, &b"Hello, World"[..]).is_err());
     }
 
     #[test]
@@ -192,4 +517,11 @@ mod tests {
         let array = [1u8, 2u8, 3u8, 45u8];
         assert_eq!("0102032d", to_hex_string(&array));
     }
+
+    #[test]
+    fn test_from_hex_string() {
+        assert_eq!(Some(vec![1u8, 2u8, 3u8, 45u8]), from_hex_string("0102032D"));
+        assert_eq!(None, from_hex_string("123"));
+        assert_eq!(None, from_hex_string("zz"));
+    }
 }
@@ -0,0 +1,195 @@
+use super::commit_util;
+use super::index_util;
+use super::ls_tree;
+use super::object_util;
+use super::object_util::Object;
+use super::pack_util;
+use super::pack_util::DeltaOptions;
+use super::ref_util;
+use super::reflog_util;
+use super::tag_util;
+use std::collections::HashSet;
+use std::io;
+use std::io::BufRead;
+use std::path::Path;
+
+// https://git-scm.com/docs/git-pack-objects
+
+/// Options for `pack-objects`
+#[derive(Debug, Default, PartialEq)]
+pub struct PackObjectsOptions {
+    /// The pack is written as `<base_name>-<checksum>.pack`
+    pub base_name: String,
+    /// Packs every reachable object instead of the objects listed on stdin
+    pub all: bool,
+    pub delta: DeltaOptions,
+}
+
+/// Writes a pack holding the objects listed on stdin, one per line, or every reachable object
+///
+/// A line can give the path the object was found at after its hash, which helps find deltas
+///
+/// Returns the checksum naming the pack
+pub fn pack_objects(options: &PackObjectsOptions) -> Result<String, String> {
+    let objects = if options.all {
+        reachable_objects()?
+    } else {
+        let mut objects = Vec::new();
+        for line in io::stdin().lock().lines() {
+            let line = line.map_err(|e| e.to_string())?;
+            let (hash, name) = match line.find(' ') {
+                Some(i) => (&line[..i], &line[i + 1..]),
+                None => (line.as_str(), ""),
+            };
+            match hash {
+                "" => (),
+                _ if object_util::is_valid_hash(hash) => {
+                    objects.push((hash.to_string(), name.to_string()))
+                }
+                _ => {
+                    return Err(format!(
+                        "fatal: expected object ID, got garbage:\n {}",
+                        hash
+                    ))
+                }
+            }
+        }
+        objects
+    };
+
+    pack_util::write_pack(&options.base_name, &objects, options.delta)
+        .map(|name| format!("{}\n", name))
+}
+
+/// Parse arguments for `pack-objects`
+pub fn parse_args(args: &[String]) -> Result<PackObjectsOptions, String> {
+    let mut options = PackObjectsOptions::default();
+    let mut names = Vec::new();
+
+    for arg in args.iter() {
+        match arg.as_str() {
+            "--all" => options.all = true,
+            _ if options.delta.parse_arg(arg)? => (),
+            _ if arg.starts_with('-') => {
+                return Err(format!("{} is not recognized as a valid option", arg))
+            }
+            _ => names.push(arg.to_owned()),
+        }
+    }
+
+    match names.len() {
+        1 => {
+            options.base_name = names.remove(0);
+            Ok(options)
+        }
+        _ => {
+            Err("usage: pack-objects [--all] [--window=<n>] [--depth=<n>] <base-name>".to_string())
+        }
+    }
+}
+
+/// Returns every object reachable from HEAD, the refs, their reflogs and the index,
+/// along with the path each tree and blob was first found at
+///
+/// Commits come first, then the other objects in the order they were found
+pub fn reachable_objects() -> Result<Vec<(String, String)>, String> {
+    let mut tips = Vec::new();
+    if let Some(head) = ref_util::resolve_ref("HEAD")? {
+        tips.push(head);
+    }
+    let refs = ref_util::list_refs("refs/");
+    tips.extend(refs.iter().map(|(_, hash)| hash.clone()));
+    let mut tips: Vec<(String, String)> = tips.into_iter().map(|h| (h, String::new())).collect();
+
+    // Reflogs and the index can mention objects that have since been pruned, so those are skipped
+    let mut names: Vec<String> = refs.into_iter().map(|(name, _)| name).collect();
+    names.push("HEAD".to_string());
+    for name in names.iter() {
+        for entry in reflog_util::read_reflog(name) {
+            tips.extend(
+                [entry.old, entry.new]
+                    .iter()
+                    .filter(|hash| hash.as_str() != ref_util::NULL_HASH)
+                    .filter(|hash| object_util::object_exists(hash))
+                    .map(|hash| (hash.clone(), String::new())),
+            );
+        }
+    }
+    if Path::new("gitrs/index").exists() {
+        let (_, items) = index_util::parse_index()?;
+        tips.extend(
+            items
+                .into_iter()
+                .filter(|item| object_util::object_exists(&item.object_hash))
+                .map(|item| (item.object_hash, item.filename)),
+        );
+    }
+
+    let mut seen = HashSet::new();
+    let mut commits = Vec::new();
+    let mut others = Vec::new();
+    while let Some((hash, name)) = tips.pop() {
+        if !seen.insert(hash.clone()) {
+            continue;
+        }
+        if !object_util::object_exists(&hash) {
+            return Err(format!("fatal: unable to read {}", hash));
+        }
+
+        match object_util::read_object_type(&hash)? {
+            Object::Commit => {
+                let commit = commit_util::read_commit(&hash)?;
+                tips.push((commit.tree, String::new()));
+                tips.extend(commit.parents.into_iter().map(|p| (p, String::new())));
+                commits.push((hash, name));
+            }
+            Object::Tag => {
+                tips.push((tag_util::read_tag(&hash)?.object, String::new()));
+                others.push((hash, name));
+            }
+            Object::Tree => {
+                let (_, content) = object_util::read_object(&hash);
+                // Submodule commits live in another repository
+                tips.extend(
+                    ls_tree::parse_tree(&content)?
+                        .into_iter()
+                        .filter(|entry| entry.object_type() != Object::Commit)
+                        .map(|entry| match name.as_str() {
+                            "" => (entry.hash, entry.name),
+                            _ => (entry.hash, format!("{}/{}", name, entry.name)),
+                        }),
+                );
+                others.push((hash, name));
+            }
+            Object::Blob => others.push((hash, name)),
+        }
+    }
+
+    commits.append(&mut others);
+    Ok(commits)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn to_args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|a| a.to_string()).collect()
+    }
+
+    #[test]
+    fn test_parse_args() {
+        assert_eq!(
+            Ok(PackObjectsOptions {
+                base_name: "out/pack".to_string(),
+                all: true,
+                delta: DeltaOptions {
+                    window: 250,
+                    depth: 50,
+                },
+            }),
+            parse_args(&to_args(&["--all", "--window=250", "out/pack"]))
+        );
+        assert!(parse_args(&[]).is_err());
+    }
+}
@@ -0,0 +1,1119 @@
+use super::delta_util;
+use super::delta_util::DeltaIndex;
+use super::object_util;
+use super::object_util::{Object, ObjectReader};
+use flate2::read::ZlibDecoder;
+use flate2::write::ZlibEncoder;
+use flate2::Crc;
+use sha1::Sha1;
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::process;
+use std::rc::Rc;
+
+// https://git-scm.com/docs/pack-format
+
+pub const PACK_DIR: &str = "gitrs/objects/pack";
+
+/// The first 4 bytes of a `.pack` file
+const PACK_MAGIC: [u8; 4] = *b"PACK";
+
+/// The first 4 bytes of a version 2 or later `.idx` file
+const IDX_MAGIC: [u8; 4] = [0xff, b't', b'O', b'c'];
+
+/// Pack entry types, stored in bits 4-6 of the first byte of an entry
+pub const OBJ_COMMIT: u8 = 1;
+pub const OBJ_TREE: u8 = 2;
+pub const OBJ_BLOB: u8 = 3;
+pub const OBJ_TAG: u8 = 4;
+/// A delta against a base found a negative offset back in the same pack
+pub const OBJ_OFS_DELTA: u8 = 6;
+/// A delta against a base named by its hash
+pub const OBJ_REF_DELTA: u8 = 7;
+
+/// How many bytes of delta bases are kept in memory, like git's `core.deltaBaseCacheLimit`
+const DELTA_BASE_CACHE_LIMIT: usize = 96 * 1024 * 1024;
+
+/// The most that is reserved up front for a size read from a pack, which can't be trusted
+pub const MAX_PREALLOC: u64 = 1 << 20;
+
+/// Objects larger than this are stored whole without looking for deltas, like git's `core.bigFileThreshold`
+const BIG_FILE_THRESHOLD: u64 = 512 * 1024 * 1024;
+
+/// The longest delta chain that is followed, so a corrupt pack whose deltas loop is an error
+const MAX_DELTA_CHAIN: usize = 10_000;
+
+/// A parsed version 2 `.idx` file, mapping object hashes to offsets in its `.pack` file
+///
+/// # Format
+///
+/// * The magic number `\377tOc` and the version, 2
+/// * A fanout table of 256 counts, entry `n` is how many hashes start with a byte `<= n`
+/// * The sorted 20 byte hashes
+/// * The CRC32 of each packed entry
+/// * The 4 byte offset of each entry, or the index into a table of 8 byte offsets if the high bit is set
+/// * The 8 byte offsets
+/// * The checksum of the pack, then the checksum of the index
+#[derive(Debug)]
+pub struct PackIndex {
+    /// The `.pack` file the index is for
+    pub pack_path: String,
+    fanout: Vec<u32>,
+    hashes: Vec<u8>,
+    crcs: Vec<u32>,
+    offsets: Vec<u64>,
+    /// The checksum at the end of the `.pack` file
+    pub pack_checksum: Vec<u8>,
+}
+
+impl PackIndex {
+    /// Reads the index at `idx_path`, whose pack is next to it with a `.pack` extension
+    pub fn read(idx_path: &str) -> Result<PackIndex, String> {
+        let data =
+            fs::read(idx_path).map_err(|e| format!("error: unable to read {}: {}", idx_path, e))?;
+        let pack_path = format!("{}.pack", idx_path.trim_end_matches(".idx"));
+        PackIndex::parse(&data, &pack_path).map_err(|e| format!("error: {} in {}", e, idx_path))
+    }
+
+    /// Parses the content of a `.idx` file
+    pub fn parse(data: &[u8], pack_path: &str) -> Result<PackIndex, String> {
+        if data.len() < 8 + 256 * 4 + 40 || data[..4] != IDX_MAGIC {
+            return Err("not a version 2 pack index".to_string());
+        }
+        let version = read_u32(data, 4);
+        if version != 2 {
+            return Err(format!("unsupported pack index version {}", version));
+        }
+
+        let fanout: Vec<u32> = (0..256).map(|i| read_u32(data, 8 + i * 4)).collect();
+        let count = fanout[255] as usize;
+        if fanout.windows(2).any(|w| w[0] > w[1]) {
+            return Err("corrupt fanout table".to_string());
+        }
+
+        let hashes_start = 8 + 256 * 4;
+        let crcs_start = hashes_start + count * 20;
+        let offsets_start = crcs_start + count * 4;
+        let large_start = offsets_start + count * 4;
+        if data.len() < large_start + 40 {
+            return Err("pack index is too short".to_string());
+        }
+
+        let crcs = (0..count)
+            .map(|i| read_u32(data, crcs_start + i * 4))
+            .collect();
+        let mut offsets = Vec::with_capacity(count);
+        for i in 0..count {
+            let offset = read_u32(data, offsets_start + i * 4);
+            if offset & 0x8000_0000 == 0 {
+                offsets.push(offset as u64);
+                continue;
+            }
+            // The offset is too large for 31 bits, so it is in the table of 8 byte offsets
+            let large = large_start + (offset & 0x7fff_ffff) as usize * 8;
+            if large + 8 > data.len() - 40 {
+                return Err("corrupt large offset".to_string());
+            }
+            offsets.push((read_u32(data, large) as u64) << 32 | read_u32(data, large + 4) as u64);
+        }
+
+        Ok(PackIndex {
+            pack_path: pack_path.to_string(),
+            fanout,
+            hashes: data[hashes_start..crcs_start].to_vec(),
+            crcs,
+            offsets,
+            pack_checksum: data[data.len() - 40..data.len() - 20].to_vec(),
+        })
+    }
+
+    /// Returns the number of objects in the pack
+    pub fn len(&self) -> usize {
+        self.offsets.len()
+    }
+
+    /// Returns the hash of the nth object, in sorted order
+    pub fn hash(&self, n: usize) -> String {
+        object_util::to_hex_string(&self.hashes[n * 20..n * 20 + 20])
+    }
+
+    /// Returns the offset of the nth object in the pack
+    pub fn offset(&self, n: usize) -> u64 {
+        self.offsets[n]
+    }
+
+    /// Returns the CRC32 of the nth object's entry as stored in the pack
+    pub fn crc(&self, n: usize) -> u32 {
+        self.crcs[n]
+    }
+
+    /// Returns the position of an object in the index
+    ///
+    /// Only hashes starting with the same byte are searched, using the fanout table
+    pub fn find(&self, hash: &[u8]) -> Option<usize> {
+        let first = *hash.first()? as usize;
+        let mut low = if first == 0 {
+            0
+        } else {
+            self.fanout[first - 1] as usize
+        };
+        let mut high = self.fanout[first] as usize;
+
+        while low < high {
+            let mid = (low + high) / 2;
+            match self.hashes[mid * 20..mid * 20 + 20].cmp(hash) {
+                std::cmp::Ordering::Less => low = mid + 1,
+                std::cmp::Ordering::Greater => high = mid,
+                std::cmp::Ordering::Equal => return Some(mid),
+            }
+        }
+        None
+    }
+
+    /// Returns the hashes of the objects starting with a hex prefix
+    pub fn find_prefix(&self, prefix: &str) -> Vec<String> {
+        let first = match u8::from_str_radix(&prefix[..2.min(prefix.len())], 16) {
+            Ok(b) if prefix.len() >= 2 => b as usize,
+            _ => return Vec::new(),
+        };
+        let low = if first == 0 {
+            0
+        } else {
+            self.fanout[first - 1] as usize
+        };
+        (low..self.fanout[first] as usize)
+            .map(|i| self.hash(i))
+            .filter(|hash| hash.starts_with(prefix))
+            .collect()
+    }
+}
+
+/// An entry read from a pack, before any delta is applied
+#[derive(Debug)]
+pub struct PackEntry {
+    pub type_code: u8,
+    /// The base of a delta, either an offset in the same pack or a hash
+    pub base: Option<DeltaBase>,
+    /// The object content, or the delta instructions
+    pub data: Vec<u8>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum DeltaBase {
+    Offset(u64),
+    Hash(String),
+}
+
+/// A fully rebuilt object, shared between the cache and its callers
+type CachedObject = (Object, Rc<Vec<u8>>);
+
+/// Recently used delta bases, keyed by pack and offset
+#[derive(Default)]
+struct DeltaBaseCache {
+    entries: HashMap<(String, u64), CachedObject>,
+    /// Keys in the order they were added, so the oldest are removed first
+    order: VecDeque<(String, u64)>,
+    size: usize,
+}
+
+impl DeltaBaseCache {
+    fn get(&self, key: &(String, u64)) -> Option<CachedObject> {
+        self.entries.get(key).cloned()
+    }
+
+    fn insert(&mut self, key: (String, u64), object_type: Object, content: Rc<Vec<u8>>) {
+        if content.len() > DELTA_BASE_CACHE_LIMIT || self.entries.contains_key(&key) {
+            return;
+        }
+        self.size += content.len();
+        self.entries.insert(key.clone(), (object_type, content));
+        self.order.push_back(key);
+
+        while self.size > DELTA_BASE_CACHE_LIMIT {
+            match self.order.pop_front() {
+                Some(oldest) => {
+                    if let Some((_, content)) = self.entries.remove(&oldest) {
+                        self.size -= content.len();
+                    }
+                }
+                None => break,
+            }
+        }
+    }
+}
+
+thread_local! {
+    static PACK_INDEXES: RefCell<HashMap<String, Rc<PackIndex>>> = RefCell::new(HashMap::new());
+    static DELTA_BASE_CACHE: RefCell<DeltaBaseCache> = RefCell::new(DeltaBaseCache::default());
+}
+
+/// Returns the index of every pack in the object database
+///
+/// Indexes are parsed once and kept for the rest of the process
+pub fn pack_indexes() -> Vec<Rc<PackIndex>> {
+    let mut paths: Vec<String> = match fs::read_dir(PACK_DIR) {
+        Ok(entries) => entries
+            .flatten()
+            .map(|e| format!("{}/{}", PACK_DIR, e.file_name().to_string_lossy()))
+            .filter(|path| path.ends_with(".idx"))
+            .collect(),
+        Err(_) => return Vec::new(),
+    };
+    paths.sort();
+
+    PACK_INDEXES.with(|cache| {
+        let mut cache = cache.borrow_mut();
+        paths
+            .into_iter()
+            .filter_map(|path| {
+                if !cache.contains_key(&path) {
+                    // A broken index is skipped, like a missing object
+                    let index = PackIndex::read(&path).ok()?;
+                    cache.insert(path.clone(), Rc::new(index));
+                }
+                cache.get(&path).cloned()
+            })
+            .collect()
+    })
+}
+
+/// Returns true if the object is in any pack
+pub fn has_packed_object(object_hash: &str) -> bool {
+    match object_util::from_hex_string(object_hash) {
+        Some(hash) => pack_indexes().iter().any(|idx| idx.find(&hash).is_some()),
+        None => false,
+    }
+}
+
+/// Returns the hashes of packed objects starting with a hex prefix
+pub fn find_packed_prefix(prefix: &str) -> Vec<String> {
+    pack_indexes()
+        .iter()
+        .flat_map(|idx| idx.find_prefix(prefix))
+        .collect()
+}
+
+/// Reads an object from whichever pack holds it
+///
+/// Returns `Ok(None)` if no pack has the object
+pub fn read_packed_object(object_hash: &str) -> Result<Option<(Object, Vec<u8>)>, String> {
+    let hash = match object_util::from_hex_string(object_hash) {
+        Some(hash) => hash,
+        None => return Ok(None),
+    };
+
+    for idx in pack_indexes().iter() {
+        if let Some(n) = idx.find(&hash) {
+            let (object_type, content) = read_pack_object(idx, idx.offset(n))?;
+            return Ok(Some((object_type, content.as_ref().clone())));
+        }
+    }
+    Ok(None)
+}
+
+/// Reads the object at an offset in a pack, applying deltas until the full object is rebuilt
+///
+/// The chain of deltas is followed down to a whole object or a cached base,
+/// then the deltas are applied from the innermost one out.
+pub fn read_pack_object(idx: &PackIndex, offset: u64) -> Result<CachedObject, String> {
+    // The offset and data of each delta, outermost first
+    let mut deltas: Vec<(u64, Vec<u8>)> = Vec::new();
+    let mut current = offset;
+
+    // The base the deltas apply to, and its offset if it is in this pack
+    let (object_type, mut content, mut base_offset) = loop {
+        if deltas.len() > MAX_DELTA_CHAIN {
+            return Err(format!(
+                "error: delta chain too long at offset {} in {}",
+                offset, idx.pack_path
+            ));
+        }
+        if !deltas.is_empty() {
+            let key = (idx.pack_path.clone(), current);
+            if let Some((object_type, base)) = DELTA_BASE_CACHE.with(|c| c.borrow().get(&key)) {
+                break (object_type, base, None);
+            }
+        }
+
+        let entry = read_entry(&idx.pack_path, current)?;
+        let base_hash = match entry.base {
+            None => {
+                let object_type = type_from_code(entry.type_code)?;
+                break (object_type, Rc::new(entry.data), Some(current));
+            }
+            Some(DeltaBase::Offset(base_offset)) => {
+                deltas.push((current, entry.data));
+                current = base_offset;
+                continue;
+            }
+            Some(DeltaBase::Hash(hash)) => hash,
+        };
+        deltas.push((current, entry.data));
+
+        match object_util::from_hex_string(&base_hash).and_then(|h| idx.find(&h)) {
+            Some(n) => current = idx.offset(n),
+            // The base of a thin pack can be anywhere in the object database
+            None if object_util::object_exists(&base_hash) => {
+                let (object_type, base) = object_util::read_object(&base_hash);
+                break (object_type, Rc::new(base), None);
+            }
+            None => {
+                return Err(format!(
+                    "error: delta base {} is missing for the object at offset {} in {}",
+                    base_hash, current, idx.pack_path
+                ))
+            }
+        }
+    };
+
+    while let Some((delta_offset, delta)) = deltas.pop() {
+        if let Some(base_offset) = base_offset {
+            let key = (idx.pack_path.clone(), base_offset);
+            DELTA_BASE_CACHE.with(|c| c.borrow_mut().insert(key, object_type, content.clone()));
+        }
+        content = Rc::new(delta_util::apply_delta(&content, &delta)?);
+        base_offset = Some(delta_offset);
+    }
+    Ok((object_type, content))
+}
+
+/// Reads and inflates the entry at an offset in a pack
+pub fn read_entry(pack_path: &str, offset: u64) -> Result<PackEntry, String> {
+    let mut entry = open_entry(pack_path, offset)?;
+    let mut data = Vec::with_capacity(entry.size.min(MAX_PREALLOC) as usize);
+    let corrupt = |what: &str| format!("error: {} at offset {} in {}", what, offset, pack_path);
+    // One byte more than expected is enough to tell the size is wrong
+    (&mut entry.data)
+        .take(entry.size.saturating_add(1))
+        .read_to_end(&mut data)
+        .map_err(|_| corrupt("bad compressed data"))?;
+    if data.len() as u64 != entry.size {
+        return Err(corrupt("size mismatch"));
+    }
+
+    Ok(PackEntry {
+        type_code: entry.type_code,
+        base: entry.base,
+        data,
+    })
+}
+
+/// An entry whose data is inflated as it is read
+struct OpenEntry {
+    type_code: u8,
+    size: u64,
+    base: Option<DeltaBase>,
+    data: ZlibDecoder<io::Chain<io::Cursor<Vec<u8>>, BufReader<File>>>,
+}
+
+/// Reads the header of the entry at an offset in a pack, leaving the data to be read
+fn open_entry(pack_path: &str, offset: u64) -> Result<OpenEntry, String> {
+    let corrupt = |what: &str| format!("error: {} at offset {} in {}", what, offset, pack_path);
+
+    let mut file =
+        File::open(pack_path).map_err(|e| format!("error: unable to open {}: {}", pack_path, e))?;
+    file.seek(SeekFrom::Start(offset))
+        .map_err(|_| corrupt("bad offset"))?;
+    let mut reader = BufReader::new(file);
+
+    // The header and delta base take at most 10 + 20 bytes
+    let mut header = Vec::new();
+    (&mut reader)
+        .take(32)
+        .read_to_end(&mut header)
+        .map_err(|_| corrupt("truncated entry"))?;
+    let (type_code, size, mut used) =
+        parse_entry_header(&header).ok_or_else(|| corrupt("bad entry header"))?;
+
+    let base = match type_code {
+        OBJ_OFS_DELTA => {
+            let (distance, len) =
+                parse_ofs_distance(&header[used..]).ok_or_else(|| corrupt("bad delta offset"))?;
+            used += len;
+            let base_offset = offset
+                .checked_sub(distance)
+                .filter(|_| distance != 0)
+                .ok_or_else(|| corrupt("bad delta base offset"))?;
+            Some(DeltaBase::Offset(base_offset))
+        }
+        OBJ_REF_DELTA => {
+            let hash = header
+                .get(used..used + 20)
+                .ok_or_else(|| corrupt("truncated delta base"))?;
+            used += 20;
+            Some(DeltaBase::Hash(object_util::to_hex_string(hash)))
+        }
+        _ => None,
+    };
+
+    // Start inflating right after the header, from the bytes already read and then the file
+    let rest = header.split_off(used);
+    Ok(OpenEntry {
+        type_code,
+        size,
+        base,
+        data: ZlibDecoder::new(io::Cursor::new(rest).chain(reader)),
+    })
+}
+
+/// Opens an object from whichever pack holds it so its content can be read incrementally
+///
+/// A whole object is inflated as it is read, a delta is rebuilt in memory first.
+/// Returns `Ok(None)` if no pack has the object
+pub fn open_packed_object(object_hash: &str) -> Result<Option<ObjectReader>, String> {
+    let hash = match object_util::from_hex_string(object_hash) {
+        Some(hash) => hash,
+        None => return Ok(None),
+    };
+
+    for idx in pack_indexes().iter() {
+        if let Some(n) = idx.find(&hash) {
+            let entry = open_entry(&idx.pack_path, idx.offset(n))?;
+            if entry.base.is_none() {
+                let object_type = type_from_code(entry.type_code)?;
+                let content = Box::new(entry.data.take(entry.size));
+                return Ok(Some(ObjectReader::new(object_type, entry.size, content)));
+            }
+
+            let (object_type, content) = read_pack_object(idx, idx.offset(n))?;
+            let size = content.len() as u64;
+            let content = Box::new(io::Cursor::new(content.as_ref().clone()));
+            return Ok(Some(ObjectReader::new(object_type, size, content)));
+        }
+    }
+    Ok(None)
+}
+
+/// Returns the object type for a pack entry type that isn't a delta
+pub fn type_from_code(type_code: u8) -> Result<Object, String> {
+    match type_code {
+        OBJ_COMMIT => Ok(Object::Commit),
+        OBJ_TREE => Ok(Object::Tree),
+        OBJ_BLOB => Ok(Object::Blob),
+        OBJ_TAG => Ok(Object::Tag),
+        _ => Err(format!("error: invalid pack object type {}", type_code)),
+    }
+}
+
+/// Parses the header of a pack entry
+///
+/// The first byte holds a continuation bit, the 3 bit type and the low 4 bits of the size.
+/// Each following byte holds a continuation bit and 7 more bits of the size.
+///
+/// Returns the type, the size of the inflated data and the length of the header
+pub fn parse_entry_header(data: &[u8]) -> Option<(u8, u64, usize)> {
+    let first = *data.first()?;
+    let type_code = (first >> 4) & 0x7;
+    let mut size = (first & 0x0f) as u64;
+    let mut shift = 4;
+    let mut used = 1;
+
+    let mut byte = first;
+    while byte & 0x80 != 0 {
+        byte = *data.get(used)?;
+        if shift > 57 {
+            return None;
+        }
+        size |= ((byte & 0x7f) as u64) << shift;
+        shift += 7;
+        used += 1;
+    }
+    Some((type_code, size, used))
+}
+
+/// Parses how far back the base of an `OFS_DELTA` entry is
+///
+/// Each byte holds a continuation bit and 7 bits, and every continuation adds one
+/// so that each length encodes a distinct range.
+///
+/// Returns the distance and the number of bytes used
+pub fn parse_ofs_distance(data: &[u8]) -> Option<(u64, usize)> {
+    let mut byte = *data.first()?;
+    let mut distance = (byte & 0x7f) as u64;
+    let mut used = 1;
+    while byte & 0x80 != 0 {
+        byte = *data.get(used)?;
+        distance = ((distance + 1) << 7) | (byte & 0x7f) as u64;
+        used += 1;
+    }
+    Some((distance, used))
+}
+
+/// Where an object was written in a pack, used to build its `.idx`
+#[derive(Debug, Clone, PartialEq)]
+pub struct PackIndexEntry {
+    pub hash: Vec<u8>,
+    pub offset: u64,
+    /// The CRC32 of the whole entry as stored in the pack, header included
+    pub crc: u32,
+}
+
+/// How hard [`write_pack`] looks for deltas, like git's `--window` and `--depth`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DeltaOptions {
+    /// How many of the objects sorted just before an object are tried as its base
+    pub window: usize,
+    /// The longest chain of deltas allowed
+    pub depth: usize,
+}
+
+impl Default for DeltaOptions {
+    fn default() -> DeltaOptions {
+        DeltaOptions {
+            window: 10,
+            depth: 50,
+        }
+    }
+}
+
+impl DeltaOptions {
+    /// Applies `--window=<n>` or `--depth=<n>`
+    ///
+    /// Returns false if the argument is neither
+    pub fn parse_arg(&mut self, arg: &str) -> Result<bool, String> {
+        let (value, field) = if let Some(value) = arg.strip_prefix("--window=") {
+            (value, &mut self.window)
+        } else if let Some(value) = arg.strip_prefix("--depth=") {
+            (value, &mut self.depth)
+        } else {
+            return Ok(false);
+        };
+        *field = value
+            .parse()
+            .map_err(|_| format!("fatal: invalid number '{}' given to {}", value, arg))?;
+        Ok(true)
+    }
+}
+
+/// An object being packed
+///
+/// Its content isn't kept, it is read again while the object is in the delta window
+/// and when it is written
+struct PackObject {
+    hash: String,
+    object_type: Object,
+    name_hash: u32,
+    size: u64,
+}
+
+/// Writes a `.pack` and a matching `.idx` holding the objects, as `<base_name>-<checksum>.pack`
+///
+/// Each object is given with the path it was found at, which groups similar objects
+/// together when looking for deltas.
+///
+/// # Format
+///
+/// * The magic number `PACK`, the version, 2, and the number of objects
+/// * Each object as an entry header followed by its zlib compressed content,
+///   or by how far back its base is and the compressed delta
+/// * The checksum of everything before it
+///
+/// Returns the checksum of the pack, which names both files
+pub fn write_pack(
+    base_name: &str,
+    objects: &[(String, String)],
+    options: DeltaOptions,
+) -> Result<String, String> {
+    let mut seen = HashSet::new();
+    let mut packed = Vec::new();
+    for (hash, name) in objects.iter() {
+        if !seen.insert(hash) {
+            continue;
+        }
+        // Only the header is needed to sort the objects
+        let object = object_util::open_object(hash)
+            .map_err(|_| format!("fatal: unable to read {}", hash))?;
+        packed.push(PackObject {
+            hash: hash.to_owned(),
+            object_type: object.object_type,
+            name_hash: name_hash(name),
+            size: object.size,
+        });
+    }
+    let deltas = find_deltas(&packed, options);
+
+    // Write to a temporary file so a half written pack is never picked up,
+    // and remove it if anything goes wrong
+    let tmp_path = format!("{}-{}.tmp", base_name, process::id());
+    let (checksum, mut entries) = write_pack_file(&tmp_path, &packed, &deltas)
+        .and_then(|(checksum, entries)| {
+            let name = object_util::to_hex_string(&checksum);
+            fs::rename(&tmp_path, format!("{}-{}.pack", base_name, name))
+                .map_err(|e| format!("error: unable to write {}: {}", tmp_path, e))?;
+            Ok((checksum, entries))
+        })
+        .inspect_err(|_| {
+            let _ = fs::remove_file(&tmp_path);
+        })?;
+
+    // The index goes last, a pack is only used once its index exists
+    let name = object_util::to_hex_string(&checksum);
+    let idx_path = format!("{}-{}.idx", base_name, name);
+    let idx = build_index(&mut entries, &checksum);
+    let tmp_idx_path = format!("{}.tmp", idx_path);
+    fs::write(&tmp_idx_path, idx)
+        .and_then(|_| fs::rename(&tmp_idx_path, &idx_path))
+        .map_err(|e| {
+            let _ = fs::remove_file(&tmp_idx_path);
+            format!("error: unable to write {}: {}", idx_path, e)
+        })?;
+
+    Ok(name)
+}
+
+/// Writes the pack entries to `path`, bases before the deltas that use them
+///
+/// Returns the checksum of the pack and the index entries of the objects
+fn write_pack_file(
+    path: &str,
+    packed: &[PackObject],
+    deltas: &[Option<(usize, Vec<u8>)>],
+) -> Result<([u8; 20], Vec<PackIndexEntry>), String> {
+    let write_error = |e: std::io::Error| format!("error: unable to write {}: {}", path, e);
+    let file = File::create(path).map_err(write_error)?;
+    let mut writer = HashingWriter {
+        out: BufWriter::new(file),
+        hasher: Sha1::new(),
+        offset: 0,
+    };
+
+    let mut header = PACK_MAGIC.to_vec();
+    header.extend_from_slice(&2u32.to_be_bytes());
+    header.extend_from_slice(&(packed.len() as u32).to_be_bytes());
+    writer.write(&header).map_err(write_error)?;
+
+    let mut offsets: Vec<Option<u64>> = vec![None; packed.len()];
+    let mut entries = Vec::with_capacity(packed.len());
+    for i in 0..packed.len() {
+        // Bases are written before their deltas, which can only point backwards
+        let mut chain = Vec::new();
+        let mut next = Some(i);
+        while let Some(n) = next.filter(|n| offsets[*n].is_none()) {
+            chain.push(n);
+            next = deltas[n].as_ref().map(|(base, _)| *base);
+        }
+
+        for n in chain.into_iter().rev() {
+            let object = &packed[n];
+            let entry = match &deltas[n] {
+                Some((base, delta)) => {
+                    let base_offset = offsets[*base].expect("delta base is written first");
+                    encode_entry(OBJ_OFS_DELTA, Some(writer.offset - base_offset), delta)
+                }
+                None => {
+                    let content = read_content(&object.hash)?;
+                    encode_entry(type_code(object.object_type), None, &content)
+                }
+            };
+
+            let mut crc = Crc::new();
+            crc.update(&entry);
+            entries.push(PackIndexEntry {
+                hash: object_util::from_hex_string(&object.hash).unwrap_or_default(),
+                offset: writer.offset,
+                crc: crc.sum(),
+            });
+            offsets[n] = Some(writer.offset);
+            writer.write(&entry).map_err(write_error)?;
+        }
+    }
+
+    let checksum = writer.hasher.digest().bytes();
+    writer.out.write_all(&checksum).map_err(write_error)?;
+    writer.out.flush().map_err(write_error)?;
+    Ok((checksum, entries))
+}
+
+/// Reads the content of an object being packed
+fn read_content(hash: &str) -> Result<Vec<u8>, String> {
+    let mut content = Vec::new();
+    object_util::open_object(hash)
+        .and_then(|mut object| {
+            object
+                .read_to_end(&mut content)
+                .map_err(|e| format!("fatal: unable to read {}: {}", hash, e))
+        })
+        .map_err(|_| format!("fatal: unable to read {}", hash))?;
+    Ok(content)
+}
+
+/// Chooses a delta base for each object among the `window` objects sorted before it
+///
+/// Objects are sorted by type, name hash and size, largest first, so versions of the
+/// same file end up next to each other and deltas mostly remove data. Like git, a delta
+/// has to be less than half the size of the object, and less again the longer the chain
+/// it would extend.
+///
+/// Only the objects in the window are held in memory, indexed as delta bases,
+/// and objects over [`BIG_FILE_THRESHOLD`] are left out of the search.
+///
+/// Returns the base and the delta for each object stored as a delta
+fn find_deltas(objects: &[PackObject], options: DeltaOptions) -> Vec<Option<(usize, Vec<u8>)>> {
+    let mut deltas: Vec<Option<(usize, Vec<u8>)>> = objects.iter().map(|_| None).collect();
+    if options.window == 0 || options.depth == 0 {
+        return deltas;
+    }
+
+    let mut order: Vec<usize> = (0..objects.len())
+        .filter(|&n| objects[n].size <= BIG_FILE_THRESHOLD)
+        .collect();
+    order.sort_by_key(|&n| {
+        let object = &objects[n];
+        std::cmp::Reverse((type_code(object.object_type), object.name_hash, object.size))
+    });
+
+    let mut depths = vec![0; objects.len()];
+    let mut indexes: HashMap<usize, DeltaIndex> = HashMap::new();
+    for (n, &target) in order.iter().enumerate() {
+        // An object that can't be read is stored whole, writing it reports the error
+        let content = match read_content(&objects[target].hash) {
+            Ok(content) => content,
+            Err(_) => continue,
+        };
+        let mut best: Option<(usize, Vec<u8>)> = None;
+
+        for &base in order[n.saturating_sub(options.window)..n].iter().rev() {
+            let index = match indexes.get(&base) {
+                Some(index) => index,
+                None => continue,
+            };
+            let base_size = objects[base].size as usize;
+            if objects[base].object_type != objects[target].object_type
+                || depths[base] >= options.depth
+                || content.len() < base_size / 32
+            {
+                continue;
+            }
+
+            let max_size = match &best {
+                Some((_, delta)) => delta.len() - 1,
+                None => (content.len() / 2).saturating_sub(20),
+            };
+            let max_size = max_size * (options.depth - depths[base]) / options.depth;
+            if max_size == 0 || content.len().saturating_sub(base_size) >= max_size {
+                continue;
+            }
+
+            if let Some(delta) = delta_util::create_delta(index, &content, max_size) {
+                best = Some((base, delta));
+            }
+        }
+
+        if let Some((base, _)) = &best {
+            depths[target] = depths[*base] + 1;
+        }
+        deltas[target] = best;
+        // Objects at the end of a full chain can't be a base
+        if depths[target] < options.depth {
+            indexes.insert(target, DeltaIndex::new(content));
+        }
+        // The oldest object is about to leave the window
+        if n >= options.window {
+            indexes.remove(&order[n - options.window]);
+        }
+    }
+    deltas
+}
+
+/// Hashes a path so that files with the same name sort together, like git's `pack_name_hash`
+///
+/// The last characters count the most, so `a/Makefile` and `b/Makefile` hash close together
+fn name_hash(name: &str) -> u32 {
+    name.bytes()
+        .filter(|c| !c.is_ascii_whitespace())
+        .fold(0u32, |hash, c| (hash >> 2).wrapping_add((c as u32) << 24))
+}
+
+/// Tracks the checksum and length of everything written to a pack
+struct HashingWriter {
+    out: BufWriter<File>,
+    hasher: Sha1,
+    offset: u64,
+}
+
+impl HashingWriter {
+    fn write(&mut self, data: &[u8]) -> std::io::Result<()> {
+        self.hasher.update(data);
+        self.offset += data.len() as u64;
+        self.out.write_all(data)
+    }
+}
+
+/// Builds the content of a version 2 `.idx` file, sorting the entries by hash
+pub fn build_index(entries: &mut [PackIndexEntry], pack_checksum: &[u8]) -> Vec<u8> {
+    entries.sort_by(|a, b| a.hash.cmp(&b.hash));
+
+    let mut idx = IDX_MAGIC.to_vec();
+    idx.extend_from_slice(&2u32.to_be_bytes());
+    for byte in 0..256 {
+        let count = entries
+            .iter()
+            .filter(|e| e.hash[0] as usize <= byte)
+            .count();
+        idx.extend_from_slice(&(count as u32).to_be_bytes());
+    }
+    for entry in entries.iter() {
+        idx.extend_from_slice(&entry.hash);
+    }
+    for entry in entries.iter() {
+        idx.extend_from_slice(&entry.crc.to_be_bytes());
+    }
+
+    // Offsets that don't fit in 31 bits go in a second table of 8 byte offsets
+    let mut large_offsets = Vec::new();
+    for entry in entries.iter() {
+        if entry.offset < 0x8000_0000 {
+            idx.extend_from_slice(&(entry.offset as u32).to_be_bytes());
+        } else {
+            let n = (large_offsets.len() / 8) as u32;
+            idx.extend_from_slice(&(0x8000_0000 | n).to_be_bytes());
+            large_offsets.extend_from_slice(&entry.offset.to_be_bytes());
+        }
+    }
+    idx.extend_from_slice(&large_offsets);
+    idx.extend_from_slice(pack_checksum);
+
+    let checksum = Sha1::from(&idx).digest().bytes();
+    idx.extend_from_slice(&checksum);
+    idx
+}
+
+/// Checks the checksum at the end of a `.pack` or `.idx` file against the rest of the file
+///
+/// The file is hashed a block at a time rather than read into memory.
+/// Returns the checksum
+pub fn verify_checksum(path: &str) -> Result<Vec<u8>, String> {
+    let read_error = |e: std::io::Error| format!("error: unable to read {}: {}", path, e);
+    let mut file = File::open(path).map_err(read_error)?;
+    let len = file.metadata().map_err(read_error)?.len();
+    if len < 20 {
+        return Err(format!("error: {} is too short", path));
+    }
+
+    let mut hasher = Sha1::new();
+    let mut buffer = vec![0u8; 64 * 1024];
+    let mut remaining = len - 20;
+    while remaining > 0 {
+        let want = remaining.min(buffer.len() as u64) as usize;
+        let n = file.read(&mut buffer[..want]).map_err(read_error)?;
+        if n == 0 {
+            return Err(format!("error: {} is truncated", path));
+        }
+        hasher.update(&buffer[..n]);
+        remaining -= n as u64;
+    }
+
+    let mut checksum = vec![0u8; 20];
+    file.read_exact(&mut checksum).map_err(read_error)?;
+    if hasher.digest().bytes()[..] != checksum[..] {
+        return Err(format!("error: {} SHA1 checksum mismatch", path));
+    }
+    Ok(checksum)
+}
+
+/// Returns the pack entry type for an object type
+pub fn type_code(object_type: Object) -> u8 {
+    match object_type {
+        Object::Commit => OBJ_COMMIT,
+        Object::Tree => OBJ_TREE,
+        Object::Blob => OBJ_BLOB,
+        Object::Tag => OBJ_TAG,
+    }
+}
+
+/// Encodes a whole pack entry, the header followed by the compressed data
+///
+/// A delta is stored `distance` bytes after its base
+fn encode_entry(type_code: u8, distance: Option<u64>, data: &[u8]) -> Vec<u8> {
+    let mut entry = encode_entry_header(type_code, data.len() as u64);
+    if let Some(distance) = distance {
+        entry.extend(encode_ofs_distance(distance));
+    }
+    let mut encoder = ZlibEncoder::new(entry, flate2::Compression::default());
+    // Writing to a Vec can't fail
+    let _ = encoder.write_all(data);
+    entry = encoder.finish().unwrap_or_default();
+    entry
+}
+
+/// Encodes the header of a pack entry, the reverse of [`parse_entry_header`]
+pub fn encode_entry_header(type_code: u8, size: u64) -> Vec<u8> {
+    let mut header = vec![(type_code << 4) | (size & 0x0f) as u8];
+    let mut size = size >> 4;
+    while size != 0 {
+        *header.last_mut().unwrap() |= 0x80;
+        header.push((size & 0x7f) as u8);
+        size >>= 7;
+    }
+    header
+}
+
+/// Encodes how far back the base of an `OFS_DELTA` entry is, the reverse of [`parse_ofs_distance`]
+pub fn encode_ofs_distance(mut distance: u64) -> Vec<u8> {
+    let mut bytes = vec![(distance & 0x7f) as u8];
+    distance >>= 7;
+    while distance != 0 {
+        distance -= 1;
+        bytes.push(0x80 | (distance & 0x7f) as u8);
+        distance >>= 7;
+    }
+    bytes.reverse();
+    bytes
+}
+
+fn read_u32(data: &[u8], pos: usize) -> u32 {
+    u32::from_be_bytes([data[pos], data[pos + 1], data[pos + 2], data[pos + 3]])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn compress(data: &[u8]) -> Vec<u8> {
+        let mut encoder = ZlibEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    /// Writes a pack holding one entry to a temp file and returns its index
+    fn one_entry_pack(name: &str, header: Vec<u8>, hash: &[u8]) -> PackIndex {
+        let mut pack = b"PACK\0\0\0\x02\0\0\0\x01".to_vec();
+        pack.extend(header);
+        pack.extend(compress(&[0, 0]));
+        let checksum = Sha1::from(&pack).digest().bytes();
+        pack.extend_from_slice(&checksum);
+
+        let path = std::env::temp_dir().join(format!("gitrs-{}-{}.pack", name, process::id()));
+        let path = path.to_str().unwrap();
+        fs::write(path, &pack).unwrap();
+        let mut entries = vec![PackIndexEntry {
+            hash: hash.to_vec(),
+            offset: 12,
+            crc: 0,
+        }];
+        PackIndex::parse(&build_index(&mut entries, &checksum), path).unwrap()
+    }
+
+    #[test]
+    fn test_read_pack_object_delta_loops() {
+        // An OFS_DELTA can't be its own base
+        let mut header = encode_entry_header(OBJ_OFS_DELTA, 2);
+        header.extend(encode_ofs_distance(0));
+        let idx = one_entry_pack("ofs-loop", header, &[1; 20]);
+        let error = read_pack_object(&idx, 12).err().unwrap();
+        assert!(error.starts_with("error: bad delta base offset at offset 12"));
+        fs::remove_file(&idx.pack_path).unwrap();
+
+        // A REF_DELTA naming its own hash loops until the chain is too long
+        let mut header = encode_entry_header(OBJ_REF_DELTA, 2);
+        header.extend_from_slice(&[2; 20]);
+        let idx = one_entry_pack("ref-loop", header, &[2; 20]);
+        let error = read_pack_object(&idx, 12).err().unwrap();
+        assert!(error.starts_with("error: delta chain too long at offset 12"));
+        fs::remove_file(&idx.pack_path).unwrap();
+    }
+
+    #[test]
+    fn test_parse_entry_header() {
+        // A blob of 15 bytes fits in one byte
+        assert_eq!(Some((OBJ_BLOB, 15, 1)), parse_entry_header(&[0x3f]));
+        // A commit of 300 bytes: 300 = 0b1_0010_1100
+        assert_eq!(
+            Some((OBJ_COMMIT, 300, 2)),
+            parse_entry_header(&[0x9c, 0x12])
+        );
+        assert_eq!(None, parse_entry_header(&[0x9c]));
+    }
+
+    #[test]
+    fn test_parse_ofs_distance() {
+        assert_eq!(Some((0x45, 1)), parse_ofs_distance(&[0x45]));
+        // Two bytes start at 128: ((1 + 1) << 7) | 0 = 256
+        assert_eq!(Some((256, 2)), parse_ofs_distance(&[0x81, 0x00]));
+    }
+
+    #[test]
+    fn test_encode_ofs_distance_round_trip() {
+        for distance in [0u64, 127, 128, 16511, 16512, 1 << 35].iter() {
+            let bytes = encode_ofs_distance(*distance);
+            assert_eq!(Some((*distance, bytes.len())), parse_ofs_distance(&bytes));
+        }
+    }
+
+    #[test]
+    fn test_name_hash_groups_by_file_name() {
+        let a = name_hash("src/Makefile");
+        let b = name_hash("doc/Makefile");
+        assert_eq!(a >> 16, b >> 16);
+        assert_ne!(a >> 24, name_hash("src/main.rs") >> 24);
+    }
+
+    #[test]
+    fn test_encode_entry_header_round_trip() {
+        for size in [0u64, 15, 16, 300, 1 << 40].iter() {
+            let header = encode_entry_header(OBJ_TREE, *size);
+            assert_eq!(
+                Some((OBJ_TREE, *size, header.len())),
+                parse_entry_header(&header)
+            );
+        }
+    }
+
+    #[test]
+    fn test_build_index_round_trip() {
+        let mut entries = vec![
+            PackIndexEntry {
+                hash: vec![0x4c; 20],
+                offset: 3 << 31,
+                crc: 7,
+            },
+            PackIndexEntry {
+                hash: vec![0x01; 20],
+                offset: 12,
+                crc: 9,
+            },
+        ];
+        let idx = build_index(&mut entries, &[0u8; 20]);
+
+        let parsed = PackIndex::parse(&idx, "test.pack").unwrap();
+        assert_eq!("01".repeat(20), parsed.hash(0));
+        assert_eq!(Some(1), parsed.find(&[0x4c; 20]));
+        assert_eq!(3 << 31, parsed.offset(1));
+        assert_eq!(7, parsed.crc(1));
+        assert_eq!(2, parsed.len());
+    }
+
+    #[test]
+    fn test_pack_index_find() {
+        let hashes = [[0x00u8; 20], [0x4bu8; 20], [0x4cu8; 20]];
+        let mut data = IDX_MAGIC.to_vec();
+        data.extend_from_slice(&2u32.to_be_bytes());
+        for i in 0..256 {
+            let count = hashes.iter().filter(|h| h[0] as usize <= i).count() as u32;
+            data.extend_from_slice(&count.to_be_bytes());
+        }
+        for hash in hashes.iter() {
+            data.extend_from_slice(hash);
+        }
+        for crc in [1u32, 2, 3].iter() {
+            data.extend_from_slice(&crc.to_be_bytes());
+        }
+        // The last offset lives in the large offset table
+        for offset in [12u32, 40, 0x8000_0000].iter() {
+            data.extend_from_slice(&offset.to_be_bytes());
+        }
+        data.extend_from_slice(&(1u64 << 33).to_be_bytes());
+        data.extend_from_slice(&[0u8; 40]);
+
+        let idx = PackIndex::parse(&data, "test.pack").unwrap();
+        assert_eq!(Some(1), idx.find(&[0x4b; 20]));
+        assert_eq!(None, idx.find(&[0x4d; 20]));
+        assert_eq!(40, idx.offset(1));
+        assert_eq!(1 << 33, idx.offset(2));
+        assert_eq!(vec!["4c".repeat(20)], idx.find_prefix("4c4c"));
+    }
+}
@@ -0,0 +1,334 @@
+use super::index_util;
+use super::index_util::IndexFile;
+use super::ls_tree;
+use super::object_util;
+use super::object_util::Object;
+use super::rev_parse;
+use std::collections::BTreeMap;
+
+// https://git-scm.com/docs/git-read-tree
+
+/// Reads tree information into the index
+///
+/// Without `merge` the index is replaced by the single tree.
+/// With `merge`, one tree keeps the stat data of unchanged entries,
+/// two trees switch the index from the first tree to the second,
+/// and three trees do a three-way merge of `base`, `ours` and `theirs`,
+/// recording conflicts as stages 1-3.
+///
+/// # Arguments
+///
+/// * `tree_ishes` - The trees (or commits) to read, empty for `--empty`
+/// * `merge` - Merge the trees into the current index instead of replacing it
+pub fn read_tree(tree_ishes: &[String], merge: bool) -> Result<(), String> {
+    let mut trees = Vec::new();
+    for tree_ish in tree_ishes.iter() {
+        let hash = rev_parse::rev_parse(tree_ish)?;
+        let tree = rev_parse::peel(&hash, Some(Object::Tree))
+            .map_err(|_| format!("fatal: failed to unpack tree object {}", tree_ish))?;
+        trees.push(read_tree_entries(&tree)?);
+    }
+
+    let (locked_index, current) = index_util::lock_index()?;
+    let items = if !merge {
+        match trees.len() {
+            0 => Vec::new(),
+            1 => trees.remove(0).into_values().collect(),
+            _ => return Err("fatal: multiple trees require -m".to_string()),
+        }
+    } else {
+        let index = to_map(current);
+        match trees.len() {
+            1 => one_way_merge(index, trees.remove(0)),
+            2 => two_way_merge(index, trees.remove(0), trees.remove(0))?,
+            3 => three_way_merge(trees.remove(0), trees.remove(0), trees.remove(0)),
+            _ => return Err("fatal: -m requires between 1 and 3 trees".to_string()),
+        }
+    };
+
+    index_util::write_index(locked_index, items)
+}
+
+/// Parse arguments for `read_tree`
+///
+/// Returns the trees to read and whether to merge them
+pub fn parse_args(args: &[String]) -> Result<(Vec<String>, bool), String> {
+    let mut trees = Vec::new();
+    let mut merge = false;
+    let mut empty = false;
+
+    for arg in args.iter() {
+        match arg.as_str() {
+            "-m" => merge = true,
+            "--empty" => empty = true,
+            _ if arg.starts_with('-') => {
+                return Err(format!("{} is not recognized as a valid option", arg))
+            }
+            _ => trees.push(arg.to_owned()),
+        }
+    }
+
+    if empty && !trees.is_empty() {
+        return Err("fatal: passing trees as arguments contradicts --empty".to_string());
+    }
+    if !empty && trees.is_empty() {
+        return Err("usage: read-tree [-m] (--empty | <tree-ish>...)".to_string());
+    }
+    if trees.len() > 3 {
+        return Err("fatal: at most 3 trees can be read at once".to_string());
+    }
+
+    Ok((trees, merge))
+}
+
+/// Walks a tree and its subtrees, returning an index entry for every file, keyed by path
+pub fn read_tree_entries(tree_hash: &str) -> Result<BTreeMap<String, IndexFile>, String> {
+    let mut entries = BTreeMap::new();
+    collect_tree_entries(tree_hash, "", &mut entries)?;
+    Ok(entries)
+}
+
+fn collect_tree_entries(
+    tree_hash: &str,
+    prefix: &str,
+    entries: &mut BTreeMap<String, IndexFile>,
+) -> Result<(), String> {
+    if !object_util::object_exists(tree_hash) {
+        return Err(format!("fatal: unable to read tree {}", tree_hash));
+    }
+
+    let (object_type, content) = object_util::read_object(tree_hash);
+    if object_type != Object::Tree {
+        return Err(format!("fatal: {} is not a tree", tree_hash));
+    }
+
+    for entry in ls_tree::parse_tree(&content)? {
+        let path = format!("{}{}", prefix, entry.name);
+        if !verify_path(&entry.name) {
+            return Err(format!("error: invalid path '{}'", path));
+        }
+        match entry.mode.as_str() {
+            "40000" | "040000" => {
+                collect_tree_entries(&entry.hash, &format!("{}/", path), entries)?
+            }
+            "100644" | "100755" | "120000" | "160000" => {
+                let mode = u32::from_str_radix(&entry.mode, 8).unwrap_or(0o100644);
+                let item = IndexFile::new(&path, mode, &entry.hash, 0);
+                entries.insert(path, item);
+            }
+            _ => {
+                return Err(format!(
+                    "fatal: unsupported mode {} for {}",
+                    entry.mode, path
+                ))
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Returns true if a tree entry name is safe to use as a path component, like git's `verify_path`
+///
+/// Empty names, `.`, `..` and names containing `/` or NUL could point outside the working tree,
+/// and `gitrs` or `.git` in any case would write into a repository
+pub fn verify_path(name: &str) -> bool {
+    !(name.is_empty()
+        || name == "."
+        || name == ".."
+        || name.contains(['/', '\0'])
+        || name.eq_ignore_ascii_case("gitrs")
+        || name.eq_ignore_ascii_case(".git"))
+}
+
+/// Keys index entries by path
+///
+/// Only stage 0 entries are kept, unmerged entries are replaced by the merge
+pub fn to_map(items: Vec<IndexFile>) -> BTreeMap<String, IndexFile> {
+    items
+        .into_iter()
+        .filter(|item| item.stage == 0)
+        .map(|item| (item.filename.clone(), item))
+        .collect()
+}
+
+/// Returns true if both entries have the same content and mode, or are both missing
+fn same(a: Option<&IndexFile>, b: Option<&IndexFile>) -> bool {
+    match (a, b) {
+        (Some(a), Some(b)) => a.object_hash == b.object_hash && a.mode == b.mode,
+        (None, None) => true,
+        _ => false,
+    }
+}
+
+/// Reads a single tree, keeping the stat data of index entries that didn't change
+fn one_way_merge(
+    index: BTreeMap<String, IndexFile>,
+    tree: BTreeMap<String, IndexFile>,
+) -> Vec<IndexFile> {
+    tree.into_iter()
+        .map(|(path, item)| match index.get(&path) {
+            Some(current) if same(Some(current), Some(&item)) => current.clone(),
+            _ => item,
+        })
+        .collect()
+}
+
+/// Moves the index from tree `head` to tree `merge`, keeping local changes to paths that didn't change
+pub fn two_way_merge(
+    index: BTreeMap<String, IndexFile>,
+    head: BTreeMap<String, IndexFile>,
+    merge: BTreeMap<String, IndexFile>,
+) -> Result<Vec<IndexFile>, String> {
+    let mut paths: Vec<&String> = index
+        .keys()
+        .chain(head.keys())
+        .chain(merge.keys())
+        .collect();
+    paths.sort();
+    paths.dedup();
+
+    let mut items = Vec::new();
+    for path in paths {
+        let (i, h, m) = (index.get(path), head.get(path), merge.get(path));
+
+        let result = if same(h, m) {
+            i
+        } else if same(i, h) || same(i, m) {
+            m
+        } else {
+            return Err(format!(
+                "error: Entry '{}' would be overwritten by merge. Cannot merge.",
+                path
+            ));
+        };
+
+        if let Some(item) = result {
+            items.push(item.clone());
+        }
+    }
+    Ok(items)
+}
+
+/// Merges `ours` and `theirs` using `base` as their common ancestor
+///
+/// Paths changed on only one side take that side, paths changed on both sides become
+/// a conflict with the base, ours and theirs versions in stages 1, 2 and 3
+fn three_way_merge(
+    base: BTreeMap<String, IndexFile>,
+    ours: BTreeMap<String, IndexFile>,
+    theirs: BTreeMap<String, IndexFile>,
+) -> Vec<IndexFile> {
+    let mut paths: Vec<&String> = base
+        .keys()
+        .chain(ours.keys())
+        .chain(theirs.keys())
+        .collect();
+    paths.sort();
+    paths.dedup();
+
+    let mut items = Vec::new();
+    for path in paths {
+        let (o, a, b) = (base.get(path), ours.get(path), theirs.get(path));
+
+        let result = if same(a, b) || same(o, b) {
+            a
+        } else if same(o, a) {
+            b
+        } else {
+            for (stage, item) in [(1, o), (2, a), (3, b)].iter() {
+                if let Some(item) = item {
+                    items.push(IndexFile {
+                        stage: *stage,
+                        ..(*item).clone()
+                    });
+                }
+            }
+            continue;
+        };
+
+        if let Some(item) = result {
+            items.push(item.clone());
+        }
+    }
+    items
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tree(entries: &[(&str, &str)]) -> BTreeMap<String, IndexFile> {
+        entries
+            .iter()
+            .map(|(path, hash)| (path.to_string(), IndexFile::new(path, 0o100644, hash, 0)))
+            .collect()
+    }
+
+    #[test]
+    fn test_parse_args_empty() {
+        let args = vec!["--empty".to_string()];
+        assert_eq!(Ok((Vec::new(), false)), parse_args(&args));
+    }
+
+    #[test]
+    fn test_parse_args_empty_with_tree() {
+        let args = vec!["--empty".to_string(), "HEAD".to_string()];
+        assert!(parse_args(&args).is_err());
+    }
+
+    #[test]
+    fn test_verify_path() {
+        assert!(verify_path("main.rs"));
+        assert!(verify_path(".gitignore"));
+        assert!(verify_path("gitrs.txt"));
+        for name in [
+            "", ".", "..", "a/b", "a\0b", "gitrs", "GitRS", ".git", ".GIT",
+        ] {
+            assert!(!verify_path(name), "{:?} should be rejected", name);
+        }
+    }
+
+    #[test]
+    fn test_two_way_merge_keeps_local_changes() {
+        let index = tree(&[("a", "local"), ("b", "1")]);
+        let head = tree(&[("a", "1"), ("b", "1")]);
+        let merge = tree(&[("a", "1"), ("b", "2")]);
+
+        let items = two_way_merge(index, head, merge).unwrap();
+        assert_eq!("local", items[0].object_hash);
+        assert_eq!("2", items[1].object_hash);
+    }
+
+    #[test]
+    fn test_two_way_merge_refuses_to_overwrite() {
+        let index = tree(&[("a", "local")]);
+        let head = tree(&[("a", "1")]);
+        let merge = tree(&[("a", "2")]);
+
+        assert!(two_way_merge(index, head, merge).is_err());
+    }
+
+    #[test]
+    fn test_three_way_merge() {
+        let base = tree(&[("same", "1"), ("ours", "1"), ("theirs", "1"), ("both", "1")]);
+        let ours = tree(&[("same", "1"), ("ours", "2"), ("theirs", "1"), ("both", "2")]);
+        let theirs = tree(&[("same", "1"), ("ours", "1"), ("theirs", "3"), ("both", "3")]);
+
+        let items = three_way_merge(base, ours, theirs);
+        let summary: Vec<(&str, &str, u8)> = items
+            .iter()
+            .map(|i| (i.filename.as_str(), i.object_hash.as_str(), i.stage))
+            .collect();
+        assert_eq!(
+            vec![
+                ("both", "1", 1),
+                ("both", "2", 2),
+                ("both", "3", 3),
+                ("ours", "2", 0),
+                ("same", "1", 0),
+                ("theirs", "3", 0),
+            ],
+            summary
+        );
+    }
+}
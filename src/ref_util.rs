@@ -0,0 +1,442 @@
+use super::lock_util::LockFile;
+use super::object_util;
+use super::reflog_util;
+use std::fs;
+use std::path::Path;
+
+// https://git-scm.com/book/en/v2/Git-Internals-Git-References
+
+const GIT_DIR: &str = "gitrs";
+const PACKED_REFS_PATH: &str = "gitrs/packed-refs";
+
+/// A hash made of all zeros, used to say a ref must not exist yet
+pub const NULL_HASH: &str = "0000000000000000000000000000000000000000";
+
+/// How many symbolic refs will be followed before giving up
+const MAX_SYMREF_DEPTH: usize = 5;
+
+/// The raw value stored in a ref
+#[derive(Debug, PartialEq)]
+pub enum RefValue {
+    /// Points directly at an object, e.g. `refs/heads/master`
+    Direct(String),
+    /// Points at another ref, e.g. `HEAD` containing `ref: refs/heads/master`
+    Symbolic(String),
+}
+
+/// Reads the value of a ref without following symbolic refs
+///
+/// Loose refs are checked first, then `packed-refs`
+pub fn read_ref(name: &str) -> Option<RefValue> {
+    if let Ok(content) = fs::read_to_string(ref_path(name)) {
+        return parse_ref_content(&content);
+    }
+
+    read_packed_refs()
+        .into_iter()
+        .find(|(r, _)| r == name)
+        .map(|(_, hash)| RefValue::Direct(hash))
+}
+
+/// Follows symbolic refs until a ref that points at an object (or doesn't exist yet)
+///
+/// Returns the name of that final ref, e.g. `HEAD` resolves to `refs/heads/master`
+pub fn resolve_symbolic(name: &str) -> Result<String, String> {
+    let mut current = name.to_string();
+    for _ in 0..MAX_SYMREF_DEPTH {
+        match read_ref(&current) {
+            Some(RefValue::Symbolic(target)) => current = target,
+            _ => return Ok(current),
+        }
+    }
+    Err(format!(
+        "fatal: too many levels of symbolic refs at {}",
+        name
+    ))
+}
+
+/// Returns the object a ref points to, following symbolic refs
+///
+/// Returns `None` if the ref (or the branch a symbolic ref points to) doesn't exist
+pub fn resolve_ref(name: &str) -> Result<Option<String>, String> {
+    let target = resolve_symbolic(name)?;
+    match read_ref(&target) {
+        Some(RefValue::Direct(hash)) => Ok(Some(hash)),
+        _ => Ok(None),
+    }
+}
+
+/// Expands a short ref name to the full name of an existing ref
+///
+/// Uses the same order as git: `<name>`, `refs/<name>`, `refs/tags/<name>`,
+/// `refs/heads/<name>`, `refs/remotes/<name>`, `refs/remotes/<name>/HEAD`
+pub fn dwim_ref(name: &str) -> Option<String> {
+    let candidates = [
+        name.to_string(),
+        format!("refs/{}", name),
+        format!("refs/tags/{}", name),
+        format!("refs/heads/{}", name),
+        format!("refs/remotes/{}", name),
+        format!("refs/remotes/{}/HEAD", name),
+    ];
+
+    candidates
+        .iter()
+        .filter(|c| is_valid_ref_name(c))
+        .find(|c| read_ref(c).is_some())
+        .map(|c| c.to_owned())
+}
+
+/// Resolves a full object hash or a ref name to an object hash
+///
+/// A full hash is only accepted if the object exists
+pub fn resolve_name(name: &str) -> Result<String, String> {
+    let hash = name.to_lowercase();
+    if object_util::object_exists(&hash) {
+        return Ok(hash);
+    }
+
+    let not_found = || format!("fatal: not a valid object name {}", name);
+    let full_name = dwim_ref(name).ok_or_else(not_found)?;
+    resolve_ref(&full_name)?.ok_or_else(not_found)
+}
+
+/// Points a ref at a new object, following symbolic refs
+///
+/// If `old` is given, the ref is only updated if it currently points at `old`.
+/// Use [`NULL_HASH`] as `old` to require that the ref doesn't exist yet.
+/// The `message` is recorded in the reflog as the reason for the update.
+pub fn update_ref(name: &str, new: &str, old: Option<&str>, message: &str) -> Result<(), String> {
+    if !object_util::is_valid_hash(new) {
+        return Err(format!("fatal: {}: not a valid SHA1", new));
+    }
+
+    let target = resolve_symbolic(name)?;
+    update_ref_no_deref(&target, new, old, message)
+}
+
+/// Points a ref at a new object, replacing it if it is a symbolic ref
+///
+/// Used to detach `HEAD`. Otherwise behaves like [`update_ref`]
+pub fn update_ref_no_deref(
+    name: &str,
+    new: &str,
+    old: Option<&str>,
+    message: &str,
+) -> Result<(), String> {
+    if !object_util::is_valid_hash(new) {
+        return Err(format!("fatal: {}: not a valid SHA1", new));
+    }
+    if !is_valid_ref_name(name) {
+        return Err(format!("fatal: invalid ref name: {}", name));
+    }
+
+    let lock = lock_ref(name)?;
+    check_old_value(name, old)?;
+    let previous = resolve_ref(name)?.unwrap_or_else(|| NULL_HASH.to_string());
+    lock.commit(format!("{}\n", new).as_bytes())?;
+
+    reflog_util::append_reflog(name, &previous, new, message)?;
+    // Moving the current branch moves HEAD too
+    if name != "HEAD" && resolve_symbolic("HEAD")? == name {
+        reflog_util::append_reflog("HEAD", &previous, new, message)?;
+    }
+    Ok(())
+}
+
+/// Makes `name` a symbolic ref pointing at `target`, e.g. `HEAD` to `refs/heads/master`
+pub fn write_symbolic_ref(name: &str, target: &str) -> Result<(), String> {
+    if !is_valid_ref_name(name) || !target.starts_with("refs/") || !is_valid_ref_name(target) {
+        return Err(format!("fatal: refusing to point {} at {}", name, target));
+    }
+
+    let lock = lock_ref(name)?;
+    lock.commit(format!("ref: {}\n", target).as_bytes())
+}
+
+/// Deletes a ref, both the loose file and any `packed-refs` entry
+///
+/// If `old` is given, the ref is only deleted if it currently points at `old`
+pub fn delete_ref(name: &str, old: Option<&str>) -> Result<(), String> {
+    let target = resolve_symbolic(name)?;
+    if read_ref(&target).is_none() {
+        return Err(format!("error: ref {} does not exist", target));
+    }
+
+    let lock = lock_ref(&target)?;
+    check_old_value(&target, old)?;
+
+    if read_packed_refs().iter().any(|(r, _)| *r == target) {
+        let packed_lock = lock_ref("packed-refs")?;
+        let content = fs::read_to_string(PACKED_REFS_PATH).unwrap_or_default();
+        packed_lock.commit(remove_packed_ref(&content, &target).as_bytes())?;
+    }
+
+    let path = ref_path(&target);
+    if Path::new(&path).exists() {
+        fs::remove_file(&path).map_err(|e| format!("error: could not delete {}: {}", target, e))?;
+    }
+    let log = reflog_util::log_path(&target);
+    if Path::new(&log).exists() {
+        fs::remove_file(&log).map_err(|e| format!("error: could not delete {}: {}", log, e))?;
+    }
+    drop(lock);
+    Ok(())
+}
+
+/// Renames a ref, moving its reflog with it
+///
+/// Fails if `new` already exists, unless `force` is set
+pub fn rename_ref(old: &str, new: &str, force: bool, message: &str) -> Result<(), String> {
+    if !is_valid_ref_name(new) {
+        return Err(format!("fatal: invalid ref name: {}", new));
+    }
+    let hash = match read_ref(old) {
+        Some(RefValue::Direct(hash)) => hash,
+        _ => return Err(format!("error: ref {} does not exist", old)),
+    };
+    let existing = match read_ref(new) {
+        Some(RefValue::Direct(hash)) if force => hash,
+        Some(_) => return Err(format!("fatal: ref {} already exists", new)),
+        None => NULL_HASH.to_string(),
+    };
+
+    if old == new {
+        return Ok(());
+    }
+
+    // The new ref is written before the old one is deleted, so a failure can't lose the ref
+    let lock = lock_ref(new)?;
+    check_old_value(new, Some(&existing))?;
+    let log = fs::read(reflog_util::log_path(old)).ok();
+    lock.commit(format!("{}\n", hash).as_bytes())?;
+
+    // Deleting the old ref removes its reflog, so keep its entries for the new one
+    if let Some(log) = log {
+        let new_log = reflog_util::log_path(new);
+        if let Some(parent) = Path::new(&new_log).parent() {
+            fs::create_dir_all(parent).map_err(|e| e.to_string())?;
+        }
+        LockFile::lock(&new_log)?.commit(&log)?;
+    }
+    reflog_util::append_reflog(new, &existing, &hash, message)?;
+    delete_ref(old, Some(&hash))
+}
+
+/// Returns every ref starting with `prefix` and the object it points to, sorted by name
+///
+/// Loose refs take priority over `packed-refs` entries with the same name
+pub fn list_refs(prefix: &str) -> Vec<(String, String)> {
+    let mut refs = Vec::new();
+    collect_loose_refs("refs", &mut refs);
+
+    for (name, hash) in read_packed_refs() {
+        if !refs.iter().any(|(r, _)| *r == name) {
+            refs.push((name, hash));
+        }
+    }
+
+    let mut refs: Vec<(String, String)> = refs
+        .into_iter()
+        .filter(|(r, _)| r.starts_with(prefix))
+        .collect();
+    refs.sort();
+    refs
+}
+
+/// Checks that a ref name is well formed
+///
+/// A subset of the rules from `git check-ref-format`
+pub fn is_valid_ref_name(name: &str) -> bool {
+    !name.is_empty()
+        && !name.starts_with('/')
+        && !name.ends_with('/')
+        && !name.ends_with('.')
+        && !name.ends_with(".lock")
+        && !name.contains("..")
+        && !name.contains("//")
+        && !name.contains("@{")
+        && !name
+            .chars()
+            .any(|c| c.is_ascii_control() || " ~^:?*[\\".contains(c))
+        && !name.split('/').any(|part| part.starts_with('.'))
+}
+
+/// Reads the `packed-refs` file as a list of ref names and hashes
+///
+/// Peeled lines (starting with `^`) and comments are skipped
+fn read_packed_refs() -> Vec<(String, String)> {
+    match fs::read_to_string(PACKED_REFS_PATH) {
+        Ok(content) => parse_packed_refs(&content),
+        Err(_) => Vec::new(),
+    }
+}
+
+/// Parses the content of a `packed-refs` file
+///
+/// # Format
+///
+/// Each line is a hash, a space, then the ref name.
+/// A line starting with `^` is the peeled value of the tag above it.
+fn parse_packed_refs(content: &str) -> Vec<(String, String)> {
+    let mut refs = Vec::new();
+    for line in content.lines() {
+        if line.starts_with('#') || line.starts_with('^') {
+            continue;
+        }
+        let mut split = line.splitn(2, ' ');
+        if let (Some(hash), Some(name)) = (split.next(), split.next()) {
+            if object_util::is_valid_hash(hash) {
+                refs.push((name.to_string(), hash.to_string()));
+            }
+        }
+    }
+    refs
+}
+
+/// Returns the content of a `packed-refs` file without a ref
+///
+/// The ref's peeled `^` line goes with it, every other line is kept as is
+fn remove_packed_ref(content: &str, name: &str) -> String {
+    let mut removing = false;
+    let mut kept = String::new();
+    for line in content.lines() {
+        if line.starts_with('^') && removing {
+            continue;
+        }
+        removing = line.split_once(' ').map(|(_, r)| r) == Some(name) && !line.starts_with('#');
+        if !removing {
+            kept += line;
+            kept.push('\n');
+        }
+    }
+    kept
+}
+
+/// Parses the content of a loose ref file
+fn parse_ref_content(content: &str) -> Option<RefValue> {
+    let content = content.trim();
+    match content.strip_prefix("ref:") {
+        Some(target) => Some(RefValue::Symbolic(target.trim().to_string())),
+        None if object_util::is_valid_hash(content) => Some(RefValue::Direct(content.to_string())),
+        None => None,
+    }
+}
+
+/// Recursively collects the loose refs under `gitrs/<dir>`
+fn collect_loose_refs(dir: &str, refs: &mut Vec<(String, String)>) {
+    let entries = match fs::read_dir(ref_path(dir)) {
+        Ok(entries) => entries,
+        Err(_) => return,
+    };
+
+    for entry in entries.flatten() {
+        let name = format!("{}/{}", dir, entry.file_name().to_string_lossy());
+        if entry.path().is_dir() {
+            collect_loose_refs(&name, refs);
+        } else if let Some(RefValue::Direct(hash)) = read_ref(&name) {
+            refs.push((name, hash));
+        }
+    }
+}
+
+/// Checks the current value of a ref against the expected old value
+fn check_old_value(name: &str, old: Option<&str>) -> Result<(), String> {
+    let old = match old {
+        Some(o) => o,
+        None => return Ok(()),
+    };
+
+    let current = match read_ref(name) {
+        Some(RefValue::Direct(hash)) => hash,
+        _ => NULL_HASH.to_string(),
+    };
+
+    if current != old {
+        if old == NULL_HASH {
+            return Err(format!(
+                "fatal: cannot lock ref '{}': reference already exists",
+                name
+            ));
+        }
+        return Err(format!(
+            "fatal: cannot lock ref '{}': is at {} but expected {}",
+            name, current, old
+        ));
+    }
+    Ok(())
+}
+
+/// Returns the path of a ref in the gitrs directory
+fn ref_path(name: &str) -> String {
+    format!("{}/{}", GIT_DIR, name)
+}
+
+/// Locks a ref for writing by holding `<ref>.lock`, creating its directory if needed
+fn lock_ref(name: &str) -> Result<LockFile, String> {
+    let path = ref_path(name);
+    if let Some(parent) = Path::new(&path).parent() {
+        fs::create_dir_all(parent).map_err(|e| e.to_string())?;
+    }
+    LockFile::lock(&path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_ref_content_symbolic() {
+        assert_eq!(
+            Some(RefValue::Symbolic("refs/heads/master".to_string())),
+            parse_ref_content("ref: refs/heads/master\n")
+        );
+    }
+
+    #[test]
+    fn test_parse_ref_content_direct() {
+        assert_eq!(
+            Some(RefValue::Direct(
+                "4b825dc642cb6eb9a060e54bf8d69288fbee4904".to_string()
+            )),
+            parse_ref_content("4b825dc642cb6eb9a060e54bf8d69288fbee4904\n")
+        );
+        assert_eq!(None, parse_ref_content("garbage"));
+    }
+
+    #[test]
+    fn test_parse_packed_refs() {
+        let content = "# pack-refs with: peeled fully-peeled sorted \n\
+                       4b825dc642cb6eb9a060e54bf8d69288fbee4904 refs/heads/master\n\
+                       0123456789012345678901234567890123456789 refs/tags/v1.0\n\
+                       ^9876543210987654321098765432109876543210\n";
+        let refs = parse_packed_refs(content);
+        assert_eq!(2, refs.len());
+        assert_eq!("refs/heads/master", refs[0].0);
+        assert_eq!("0123456789012345678901234567890123456789", refs[1].1);
+    }
+
+    #[test]
+    fn test_remove_packed_ref() {
+        let content = "# pack-refs with: peeled fully-peeled sorted \n\
+                       1111111111111111111111111111111111111111 refs/tags/a\n\
+                       ^2222222222222222222222222222222222222222\n\
+                       3333333333333333333333333333333333333333 refs/tags/b\n\
+                       ^4444444444444444444444444444444444444444\n";
+        assert_eq!(
+            "# pack-refs with: peeled fully-peeled sorted \n\
+             3333333333333333333333333333333333333333 refs/tags/b\n\
+             ^4444444444444444444444444444444444444444\n",
+            remove_packed_ref(content, "refs/tags/a")
+        );
+    }
+
+    #[test]
+    fn test_is_valid_ref_name() {
+        assert!(is_valid_ref_name("refs/heads/feature/x"));
+        assert!(!is_valid_ref_name("refs/heads/a..b"));
+        assert!(!is_valid_ref_name("refs/heads/a b"));
+        assert!(!is_valid_ref_name("refs/heads/.hidden"));
+        assert!(!is_valid_ref_name("refs/heads/x.lock"));
+    }
+}
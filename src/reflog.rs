@@ -0,0 +1,267 @@
+use super::commit_util;
+use super::ref_util;
+use super::reflog_util;
+use super::reflog_util::ReflogEntry;
+use std::collections::BTreeMap;
+
+// https://git-scm.com/docs/git-reflog
+
+/// Entries older than this many days are expired by default
+const DEFAULT_EXPIRE_DAYS: u64 = 90;
+/// Entries no longer reachable from the ref are expired after this many days by default
+const DEFAULT_EXPIRE_UNREACHABLE_DAYS: u64 = 30;
+
+/// What `reflog` should do
+#[derive(Debug, PartialEq)]
+pub enum ReflogAction {
+    /// Shows the reflog of a ref, newest entry first
+    Show(String),
+    /// Removes old entries from the reflogs of the refs, or of every ref with `all`
+    Expire {
+        refs: Vec<String>,
+        all: bool,
+        /// Entries made before this time are removed, `None` keeps them
+        expire: Option<u64>,
+        /// Entries made before this time are removed if the ref can no longer reach them
+        expire_unreachable: Option<u64>,
+    },
+    /// Deletes single entries given as `<ref>@{<n>}`
+    Delete(Vec<String>),
+}
+
+/// Shows, expires or deletes reflog entries
+///
+/// Returns the output to show
+pub fn reflog(action: &ReflogAction) -> Result<String, String> {
+    match action {
+        ReflogAction::Show(name) => Ok(show_reflog(name)),
+        ReflogAction::Expire {
+            refs,
+            all,
+            expire,
+            expire_unreachable,
+        } => {
+            let mut refs = refs.iter().map(|r| full_ref_name(r)).collect::<Vec<_>>();
+            if *all {
+                refs.push("HEAD".to_string());
+                refs.extend(
+                    ref_util::list_refs("refs/")
+                        .into_iter()
+                        .map(|(name, _)| name)
+                        .filter(|name| reflog_util::has_reflog(name)),
+                );
+            }
+            for name in refs.iter() {
+                expire_reflog(name, *expire, *expire_unreachable)?;
+            }
+            Ok(String::new())
+        }
+        ReflogAction::Delete(entries) => delete_entries(entries).map(|_| String::new()),
+    }
+}
+
+/// Parse arguments for `reflog`
+pub fn parse_args(args: &[String]) -> Result<ReflogAction, String> {
+    let (subcommand, args) = match args.first().map(|a| a.as_str()) {
+        Some("show") | Some("expire") | Some("delete") => (args[0].as_str(), &args[1..]),
+        _ => ("show", args),
+    };
+
+    match subcommand {
+        "show" => match args {
+            [] => Ok(ReflogAction::Show("HEAD".to_string())),
+            [name] if !name.starts_with('-') => Ok(ReflogAction::Show(name.to_owned())),
+            _ => Err("usage: reflog [show] [<ref>]".to_string()),
+        },
+        "expire" => {
+            let now = commit_util::now();
+            let mut refs = Vec::new();
+            let mut all = false;
+            let mut expire = now.checked_sub(DEFAULT_EXPIRE_DAYS * 86400);
+            let mut expire_unreachable = now.checked_sub(DEFAULT_EXPIRE_UNREACHABLE_DAYS * 86400);
+
+            for arg in args.iter() {
+                match arg.as_str() {
+                    "--all" => all = true,
+                    _ if arg.starts_with("--expire=") => {
+                        expire = parse_expiry(&arg["--expire=".len()..], now)?
+                    }
+                    _ if arg.starts_with("--expire-unreachable=") => {
+                        expire_unreachable =
+                            parse_expiry(&arg["--expire-unreachable=".len()..], now)?
+                    }
+                    _ if arg.starts_with('-') => {
+                        return Err(format!("{} is not recognized as a valid option", arg))
+                    }
+                    _ => refs.push(arg.to_owned()),
+                }
+            }
+            // An entry that is old enough to expire is also old enough if unreachable
+            expire_unreachable = match (expire, expire_unreachable) {
+                (Some(e), Some(u)) => Some(e.max(u)),
+                (e, u) => e.or(u),
+            };
+
+            if refs.is_empty() && !all {
+                return Err("fatal: no reflog specified to expire".to_string());
+            }
+            Ok(ReflogAction::Expire {
+                refs,
+                all,
+                expire,
+                expire_unreachable,
+            })
+        }
+        _ => {
+            if args.is_empty() {
+                return Err("fatal: no reflog specified to delete".to_string());
+            }
+            Ok(ReflogAction::Delete(args.to_vec()))
+        }
+    }
+}
+
+/// Parses the time given to `--expire`, where `never` means nothing expires
+/// and `all` or `now` means everything does
+fn parse_expiry(value: &str, now: u64) -> Result<Option<u64>, String> {
+    match value {
+        "never" | "false" => Ok(None),
+        "all" | "now" => Ok(Some(u64::MAX)),
+        _ => commit_util::parse_approxidate(value, now)
+            .map(Some)
+            .ok_or_else(|| format!("fatal: invalid timestamp '{}' given to --expire", value)),
+    }
+}
+
+/// Expands a short ref name to the ref its reflog belongs to
+fn full_ref_name(name: &str) -> String {
+    if name == "HEAD" {
+        return name.to_string();
+    }
+    ref_util::dwim_ref(name).unwrap_or_else(|| name.to_string())
+}
+
+/// Lists the entries of a reflog, newest first, as `<abbrev> <name>@{<n>}: <message>`
+fn show_reflog(name: &str) -> String {
+    reflog_util::read_reflog(&full_ref_name(name))
+        .iter()
+        .rev()
+        .enumerate()
+        .map(|(i, entry)| {
+            format!(
+                "{} {}@{{{}}}: {}\n",
+                &entry.new[..7],
+                name,
+                i,
+                entry.message
+            )
+        })
+        .collect()
+}
+
+/// Removes the entries made before `expire`, and the unreachable ones made before `expire_unreachable`
+fn expire_reflog(
+    name: &str,
+    expire: Option<u64>,
+    expire_unreachable: Option<u64>,
+) -> Result<(), String> {
+    if !reflog_util::has_reflog(name) {
+        return Ok(());
+    }
+    let tip = ref_util::resolve_ref(name)?;
+    let reachable = |entry: &ReflogEntry| match &tip {
+        Some(tip) => commit_util::is_ancestor(&entry.new, tip).unwrap_or(false),
+        None => false,
+    };
+
+    let entries: Vec<ReflogEntry> = reflog_util::read_reflog(name)
+        .into_iter()
+        .filter(|entry| {
+            let time = entry.committer.timestamp;
+            let expired = expire.is_some_and(|e| time < e)
+                || (expire_unreachable.is_some_and(|e| time < e) && !reachable(entry));
+            !expired
+        })
+        .collect();
+
+    reflog_util::write_reflog(name, &entries)
+}
+
+/// Deletes the entries given as `<ref>@{<n>}`
+fn delete_entries(specs: &[String]) -> Result<(), String> {
+    let mut by_ref: BTreeMap<String, Vec<usize>> = BTreeMap::new();
+    for spec in specs.iter() {
+        let invalid = || format!("error: not a reflog: {}", spec);
+        let start = spec.rfind("@{").ok_or_else(invalid)?;
+        let n = spec[start + 2..]
+            .strip_suffix('}')
+            .and_then(|n| n.parse::<usize>().ok())
+            .ok_or_else(invalid)?;
+        let name = match &spec[..start] {
+            "" => "HEAD".to_string(),
+            name => full_ref_name(name),
+        };
+        by_ref.entry(name).or_default().push(n);
+    }
+
+    for (name, indexes) in by_ref.iter() {
+        let mut entries = reflog_util::read_reflog(name);
+        let len = entries.len();
+        for n in indexes.iter() {
+            if *n >= len {
+                return Err(format!("error: reflog for '{}' has no entry {}", name, n));
+            }
+        }
+
+        // Entries are numbered from the newest
+        let mut i = len;
+        entries.retain(|_| {
+            i -= 1;
+            !indexes.contains(&i)
+        });
+        reflog_util::write_reflog(name, &entries)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn to_args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|a| a.to_string()).collect()
+    }
+
+    #[test]
+    fn test_parse_args_show() {
+        assert_eq!(Ok(ReflogAction::Show("HEAD".to_string())), parse_args(&[]));
+        assert_eq!(
+            Ok(ReflogAction::Show("master".to_string())),
+            parse_args(&to_args(&["show", "master"]))
+        );
+    }
+
+    #[test]
+    fn test_parse_args_expire() {
+        assert_eq!(
+            Ok(ReflogAction::Expire {
+                refs: Vec::new(),
+                all: true,
+                expire: Some(u64::MAX),
+                expire_unreachable: Some(u64::MAX),
+            }),
+            parse_args(&to_args(&["expire", "--expire=now", "--all"]))
+        );
+    }
+
+    #[test]
+    fn test_parse_expiry() {
+        assert_eq!(Ok(None), parse_expiry("never", 1000));
+        assert_eq!(Ok(Some(u64::MAX)), parse_expiry("all", 1000000));
+        assert_eq!(
+            Ok(Some(1000000 - 2 * 86400)),
+            parse_expiry("2.days.ago", 1000000)
+        );
+        assert!(parse_expiry("someday", 1000000).is_err());
+    }
+}
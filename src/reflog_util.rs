@@ -0,0 +1,158 @@
+use super::commit_util;
+use super::commit_util::Signature;
+use super::config_util;
+use super::lock_util::LockFile;
+use super::timezone_util;
+use std::fs;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::Path;
+
+// https://git-scm.com/docs/git-reflog
+
+/// One change to a ref, a line of `gitrs/logs/<ref>`
+///
+/// # Format
+///
+/// `<old> <new> <name> <<email>> <timestamp> <timezone>\t<message>`
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReflogEntry {
+    pub old: String,
+    pub new: String,
+    pub committer: Signature,
+    pub message: String,
+}
+
+impl ReflogEntry {
+    /// Formats the entry as a line of a reflog, including the trailing newline
+    pub fn to_line(&self) -> String {
+        format!(
+            "{} {} {}\t{}\n",
+            self.old, self.new, self.committer, self.message
+        )
+    }
+}
+
+/// Returns the path of the reflog for a ref
+pub fn log_path(name: &str) -> String {
+    format!("gitrs/logs/{}", name)
+}
+
+/// Returns true if the ref has a reflog
+pub fn has_reflog(name: &str) -> bool {
+    Path::new(&log_path(name)).is_file()
+}
+
+/// Returns true if updates to the ref should be logged
+///
+/// Like git's `core.logAllRefUpdates`, by default `HEAD`, branches, remote-tracking
+/// branches and notes are logged, along with any ref that already has a reflog.
+/// `false` only logs refs that already have a reflog, `always` logs every ref.
+pub fn should_log(name: &str) -> bool {
+    if has_reflog(name) {
+        return true;
+    }
+    match config_util::get_value("core.logAllRefUpdates").as_deref() {
+        Some("false") => false,
+        Some("always") => true,
+        _ => {
+            name == "HEAD"
+                || ["refs/heads/", "refs/remotes/", "refs/notes/"]
+                    .iter()
+                    .any(|prefix| name.starts_with(prefix))
+        }
+    }
+}
+
+/// Appends an entry to the reflog of a ref, if updates to it are logged
+///
+/// The message is squashed onto one line
+pub fn append_reflog(name: &str, old: &str, new: &str, message: &str) -> Result<(), String> {
+    if !should_log(name) {
+        return Ok(());
+    }
+
+    // The identity is not required, git falls back to a blank one too
+    let committer = commit_util::get_signature("COMMITTER").unwrap_or_else(|_| {
+        let now = commit_util::now();
+        Signature {
+            name: String::new(),
+            email: String::new(),
+            timestamp: now,
+            timezone: timezone_util::local_timezone(now),
+        }
+    });
+    let entry = ReflogEntry {
+        old: old.to_string(),
+        new: new.to_string(),
+        committer,
+        message: message.split_whitespace().collect::<Vec<&str>>().join(" "),
+    };
+
+    let path = log_path(name);
+    if let Some(parent) = Path::new(&path).parent() {
+        fs::create_dir_all(parent).map_err(|e| e.to_string())?;
+    }
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&path)
+        .map_err(|e| format!("error: unable to append to {}: {}", path, e))?;
+    file.write_all(entry.to_line().as_bytes())
+        .map_err(|e| format!("error: unable to append to {}: {}", path, e))
+}
+
+/// Reads the reflog of a ref, oldest entry first
+///
+/// Returns an empty list if the ref has no reflog. Malformed lines are skipped
+pub fn read_reflog(name: &str) -> Vec<ReflogEntry> {
+    match fs::read_to_string(log_path(name)) {
+        Ok(content) => content.lines().filter_map(parse_reflog_line).collect(),
+        Err(_) => Vec::new(),
+    }
+}
+
+/// Replaces the reflog of a ref with the given entries, holding `<log>.lock` while it is written
+pub fn write_reflog(name: &str, entries: &[ReflogEntry]) -> Result<(), String> {
+    let content: String = entries.iter().map(|e| e.to_line()).collect();
+    LockFile::lock(&log_path(name))?.commit(content.as_bytes())
+}
+
+/// Parses a line of a reflog
+fn parse_reflog_line(line: &str) -> Option<ReflogEntry> {
+    let (info, message) = match line.find('\t') {
+        Some(i) => (&line[..i], &line[i + 1..]),
+        None => (line, ""),
+    };
+    if info.len() < 82 {
+        return None;
+    }
+
+    Some(ReflogEntry {
+        old: info[..40].to_string(),
+        new: info[41..81].to_string(),
+        committer: commit_util::parse_signature(&info[82..]).ok()?,
+        message: message.to_string(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_reflog_line_round_trip() {
+        let line = "0000000000000000000000000000000000000000 \
+                    0123456789012345678901234567890123456789 \
+                    A U Thor <author@example.com> 1112911993 -0700\tcommit (initial): First\n";
+        let entry = parse_reflog_line(line.trim_end()).unwrap();
+        assert_eq!("0123456789012345678901234567890123456789", entry.new);
+        assert_eq!("commit (initial): First", entry.message);
+        assert_eq!(line, entry.to_line());
+    }
+
+    #[test]
+    fn test_parse_reflog_line_too_short() {
+        assert_eq!(None, parse_reflog_line("0000 1234 A <a@x> 1 +0000\tmsg"));
+    }
+}
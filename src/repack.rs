@@ -0,0 +1,131 @@
+use super::object_util;
+use super::pack_objects;
+use super::pack_util;
+use super::pack_util::DeltaOptions;
+use std::fs;
+
+// https://git-scm.com/docs/git-repack
+
+/// Options for `repack`
+#[derive(Debug, Default, PartialEq)]
+pub struct RepackOptions {
+    /// Packs every reachable object into one pack, instead of only the loose ones
+    pub all: bool,
+    /// Removes the packs and loose objects made redundant by the new pack
+    pub delete: bool,
+    pub delta: DeltaOptions,
+}
+
+/// Packs the reachable objects
+///
+/// Without `all`, only loose objects are packed, into a new pack next to the existing ones.
+/// With `all` and `delete`, the new pack replaces every existing pack.
+///
+/// Returns the output to show
+pub fn repack(options: &RepackOptions) -> Result<String, String> {
+    let mut objects = pack_objects::reachable_objects()?;
+    if !options.all {
+        objects.retain(|(hash, _)| !pack_util::has_packed_object(hash));
+    }
+    if objects.is_empty() {
+        return Ok("Nothing new to pack.\n".to_string());
+    }
+
+    fs::create_dir_all(pack_util::PACK_DIR).map_err(|e| e.to_string())?;
+    let base_name = format!("{}/pack", pack_util::PACK_DIR);
+    let name = pack_util::write_pack(&base_name, &objects, options.delta)?;
+
+    if options.delete {
+        if options.all {
+            remove_packs_except(&name)?;
+        }
+        prune_packed()?;
+    }
+    Ok(String::new())
+}
+
+/// Parse arguments for `repack`
+pub fn parse_args(args: &[String]) -> Result<RepackOptions, String> {
+    let mut options = RepackOptions::default();
+
+    for arg in args.iter() {
+        match arg.as_str() {
+            "-a" => options.all = true,
+            "-d" => options.delete = true,
+            "-ad" | "-da" => {
+                options.all = true;
+                options.delete = true;
+            }
+            _ if options.delta.parse_arg(arg)? => (),
+            _ => return Err(format!("{} is not recognized as a valid option", arg)),
+        }
+    }
+    Ok(options)
+}
+
+/// Removes every pack but the one with the given checksum
+fn remove_packs_except(name: &str) -> Result<(), String> {
+    let keep = format!("pack-{}.", name);
+    let entries = fs::read_dir(pack_util::PACK_DIR).map_err(|e| e.to_string())?;
+
+    for entry in entries.flatten() {
+        let file_name = entry.file_name().to_string_lossy().into_owned();
+        let is_pack = file_name.ends_with(".pack") || file_name.ends_with(".idx");
+        if is_pack && !file_name.starts_with(&keep) {
+            fs::remove_file(entry.path())
+                .map_err(|e| format!("error: unable to remove {}: {}", file_name, e))?;
+        }
+    }
+    Ok(())
+}
+
+/// Removes the loose objects that are also in a pack, like `git prune-packed`
+fn prune_packed() -> Result<(), String> {
+    let entries = fs::read_dir("gitrs/objects").map_err(|e| e.to_string())?;
+
+    for dir in entries.flatten() {
+        let dir_name = dir.file_name().to_string_lossy().into_owned();
+        if dir_name.len() != 2 || !dir.path().is_dir() {
+            continue;
+        }
+        for object in fs::read_dir(dir.path())
+            .map_err(|e| e.to_string())?
+            .flatten()
+        {
+            let hash = format!("{}{}", dir_name, object.file_name().to_string_lossy());
+            if object_util::is_valid_hash(&hash) && pack_util::has_packed_object(&hash) {
+                fs::remove_file(object.path())
+                    .map_err(|e| format!("error: unable to remove {}: {}", hash, e))?;
+            }
+        }
+        // Only succeeds once the directory is empty
+        let _ = fs::remove_dir(dir.path());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn to_args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|a| a.to_string()).collect()
+    }
+
+    #[test]
+    fn test_parse_args() {
+        assert_eq!(
+            Ok(RepackOptions {
+                all: true,
+                delete: true,
+                delta: DeltaOptions {
+                    window: 10,
+                    depth: 4,
+                },
+            }),
+            parse_args(&to_args(&["-a", "-d", "--depth=4"]))
+        );
+        assert_eq!(Ok(RepackOptions::default()), parse_args(&[]));
+        assert!(parse_args(&to_args(&["-x"])).is_err());
+    }
+}
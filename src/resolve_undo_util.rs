@@ -0,0 +1,91 @@
+use super::object_util;
+use std::str;
+
+// https://git-scm.com/docs/index-format#_resolve_undo
+
+/// A `REUC` index extension record, the conflicted versions of a path from before it was resolved
+#[derive(Debug, Clone, PartialEq)]
+pub struct ResolveUndo {
+    pub path: String,
+    /// The mode and hash of the base, ours and theirs versions, if the path had that stage
+    pub stages: [Option<(u32, String)>; 3],
+}
+
+/// Parses the content of a `REUC` extension
+///
+/// # Format
+///
+/// Each record is the NUL-terminated path, the mode of each of the three stages in ASCII octal,
+/// each NUL-terminated and 0 for a missing stage, then the 20 byte hash of each stage that isn't missing
+pub fn parse(data: &[u8]) -> Result<Vec<ResolveUndo>, String> {
+    let invalid = || "error: resolve-undo is corrupt".to_string();
+    let mut records = Vec::new();
+    let mut pos = 0;
+
+    let next_field = |pos: &mut usize| -> Result<&str, String> {
+        let end = data[*pos..]
+            .iter()
+            .position(|b| *b == 0)
+            .ok_or_else(invalid)?;
+        let field = str::from_utf8(&data[*pos..*pos + end]).map_err(|_| invalid())?;
+        *pos += end + 1;
+        Ok(field)
+    };
+
+    while pos < data.len() {
+        let path = next_field(&mut pos)?.to_string();
+        let mut modes = [0u32; 3];
+        for mode in modes.iter_mut() {
+            *mode = u32::from_str_radix(next_field(&mut pos)?, 8).map_err(|_| invalid())?;
+        }
+
+        let mut stages = [None, None, None];
+        for (stage, mode) in stages.iter_mut().zip(modes.iter()) {
+            if *mode != 0 {
+                let hash = data.get(pos..pos + 20).ok_or_else(invalid)?;
+                *stage = Some((*mode, object_util::to_hex_string(hash)));
+                pos += 20;
+            }
+        }
+        records.push(ResolveUndo { path, stages });
+    }
+    Ok(records)
+}
+
+/// Returns the content of a `REUC` extension holding the records, the reverse of [`parse`]
+pub fn to_bytes(records: &[ResolveUndo]) -> Vec<u8> {
+    let mut data = Vec::new();
+    for record in records.iter() {
+        data.extend_from_slice(record.path.as_bytes());
+        data.push(0);
+        for stage in record.stages.iter() {
+            let mode = stage.as_ref().map(|(mode, _)| *mode).unwrap_or(0);
+            data.extend_from_slice(format!("{:o}\0", mode).as_bytes());
+        }
+        for (_, hash) in record.stages.iter().flatten() {
+            data.append(&mut object_util::from_hex_string(hash).unwrap_or_default());
+        }
+    }
+    data
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_round_trip() {
+        let records = vec![ResolveUndo {
+            path: "c".to_string(),
+            stages: [
+                None,
+                Some((0o100644, "1".repeat(40))),
+                Some((0o100755, "2".repeat(40))),
+            ],
+        }];
+        let data = to_bytes(&records);
+        assert!(data.starts_with(b"c\x000\x00100644\x00100755\x00"));
+        assert_eq!(Ok(records), parse(&data));
+        assert!(parse(&data[..data.len() - 1]).is_err());
+    }
+}
@@ -0,0 +1,323 @@
+use super::commit_util;
+use super::config_util;
+use super::index_util;
+use super::ls_tree;
+use super::object_util;
+use super::object_util::Object;
+use super::pack_util;
+use super::ref_util;
+use super::reflog_util;
+use super::tag_util;
+use std::fs;
+
+// https://git-scm.com/docs/gitrevisions
+
+/// The shortest abbreviated hash that will be looked up
+const MIN_ABBREV: usize = 4;
+
+/// Resolves a revision to the hash of the object it names
+///
+/// Supported syntax:
+///
+/// * `<sha1>` - A full or abbreviated (4+ characters) object hash
+/// * `<refname>` - A ref such as `master`, `HEAD`, `tags/v1.0`, or `@` for `HEAD`
+/// * `<refname>@{upstream}`, `@{u}` - The branch a branch is tracking
+/// * `<refname>@{<n>}`, `@{<n>}` - The value of a ref n changes ago, from its reflog
+/// * `<refname>@{<date>}` - The value of a ref at a date, e.g. `master@{yesterday}`
+/// * `<rev>^<n>`, `<rev>^` - The nth (default first) parent of a commit
+/// * `<rev>~<n>`, `<rev>~` - The nth generation first parent ancestor
+/// * `<rev>^{<type>}`, `<rev>^{}` - Peel the object until it is of the given type
+/// * `<rev>:<path>` - The object at the path in the tree of a revision
+/// * `:<path>` - The object at the path in the index
+pub fn rev_parse(rev: &str) -> Result<String, String> {
+    let bad_revision = || format!("fatal: bad revision '{}'", rev);
+
+    if let Some(i) = find_path_separator(rev) {
+        let (tree_ish, path) = (&rev[..i], &rev[i + 1..]);
+        if tree_ish.is_empty() {
+            return lookup_index_path(path);
+        }
+        let tree = peel(&rev_parse(tree_ish)?, Some(Object::Tree))?;
+        if path.is_empty() {
+            return Ok(tree);
+        }
+        return ls_tree::find_path(&tree, path).map(|e| e.hash);
+    }
+
+    let base_end = rev
+        .char_indices()
+        .find(|(_, c)| *c == '^' || *c == '~')
+        .map(|(i, _)| i)
+        .unwrap_or(rev.len());
+    let mut hash = resolve_base(&rev[..base_end]).map_err(|e| e.unwrap_or_else(bad_revision))?;
+
+    let mut rest = &rev[base_end..];
+    while let Some(operator) = rest.chars().next() {
+        rest = &rest[operator.len_utf8()..];
+
+        if operator == '^' && rest.starts_with('{') {
+            let end = rest.find('}').ok_or_else(bad_revision)?;
+            let target = match &rest[1..end] {
+                "" => None,
+                "commit" => Some(Object::Commit),
+                "tree" => Some(Object::Tree),
+                "blob" => Some(Object::Blob),
+                "tag" => Some(Object::Tag),
+                "object" => {
+                    rest = &rest[end + 1..];
+                    continue;
+                }
+                _ => return Err(bad_revision()),
+            };
+            hash = peel(&hash, target).map_err(|_| bad_revision())?;
+            rest = &rest[end + 1..];
+            continue;
+        }
+
+        let digits_end = rest
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(rest.len());
+        let count = match &rest[..digits_end] {
+            "" => 1,
+            digits => digits.parse::<usize>().map_err(|_| bad_revision())?,
+        };
+        rest = &rest[digits_end..];
+
+        hash = match operator {
+            '^' => nth_parent(&hash, count).ok_or_else(bad_revision)?,
+            '~' => nth_ancestor(&hash, count).ok_or_else(bad_revision)?,
+            _ => return Err(bad_revision()),
+        };
+    }
+
+    Ok(hash)
+}
+
+/// Resolves an abbreviated hash by searching the object database for a unique match
+pub fn resolve_prefix(prefix: &str) -> Result<String, String> {
+    let prefix = prefix.to_lowercase();
+    if prefix.len() < MIN_ABBREV
+        || prefix.len() > 40
+        || !prefix.chars().all(|c| c.is_ascii_hexdigit())
+    {
+        return Err(format!("fatal: not a valid object name {}", prefix));
+    }
+
+    let dir = format!("gitrs/objects/{}", &prefix[..2]);
+    let mut matches: Vec<String> = match fs::read_dir(&dir) {
+        Ok(entries) => entries
+            .flatten()
+            .map(|e| format!("{}{}", &prefix[..2], e.file_name().to_string_lossy()))
+            .filter(|hash| hash.starts_with(&prefix) && object_util::is_valid_hash(hash))
+            .collect(),
+        Err(_) => Vec::new(),
+    };
+    for hash in pack_util::find_packed_prefix(&prefix) {
+        if !matches.contains(&hash) {
+            matches.push(hash);
+        }
+    }
+
+    match matches.len() {
+        0 => Err(format!("fatal: not a valid object name {}", prefix)),
+        1 => Ok(matches.remove(0)),
+        _ => Err(format!("error: short SHA1 {} is ambiguous", prefix)),
+    }
+}
+
+/// Peels an object until it is of the target type
+///
+/// A tag peels to the object it tags and a commit peels to its tree.
+/// With no target, tags are peeled until the object isn't a tag.
+pub fn peel(object_hash: &str, target: Option<Object>) -> Result<String, String> {
+    let mut hash = object_hash.to_string();
+    loop {
+        let object_type = object_util::read_object_type(&hash)?;
+        let done = match target {
+            Some(t) => t == object_type,
+            None => object_type != Object::Tag,
+        };
+        if done {
+            return Ok(hash);
+        }
+
+        hash = match object_type {
+            Object::Tag => tag_util::read_tag(&hash)?.object,
+            Object::Commit if target == Some(Object::Tree) => commit_util::read_commit(&hash)?.tree,
+            _ => {
+                return Err(format!(
+                    "fatal: {} cannot be peeled to a {}",
+                    object_hash,
+                    target.unwrap_or(Object::Tag)
+                ))
+            }
+        };
+    }
+}
+
+/// Resolves the part of a revision before any `^` or `~` operators
+///
+/// Returns `Err(None)` if the name couldn't be found, so the caller can report the whole revision
+fn resolve_base(base: &str) -> Result<String, Option<String>> {
+    if base.is_empty() {
+        return Err(None);
+    }
+    if base == "@" {
+        return resolve_ref_name("HEAD");
+    }
+
+    if let Some(i) = base.find("@{") {
+        let name = if i == 0 { "HEAD" } else { &base[..i] };
+        let spec = base[i + 2..].strip_suffix('}').ok_or(None)?;
+        return match spec {
+            "upstream" | "u" => resolve_ref_name(&upstream_ref(name).map_err(Some)?),
+            _ => {
+                // `@{<n>}` on its own means the current branch rather than HEAD
+                let full_name = match &base[..i] {
+                    "" => ref_util::resolve_symbolic("HEAD").map_err(Some)?,
+                    name => ref_util::dwim_ref(name).ok_or(None)?,
+                };
+                resolve_reflog(&full_name, spec)
+            }
+        };
+    }
+
+    if let Ok(hash) = ref_util::resolve_name(base) {
+        return Ok(hash);
+    }
+
+    resolve_prefix(base).map_err(|e| {
+        if e.contains("ambiguous") {
+            Some(e)
+        } else {
+            None
+        }
+    })
+}
+
+fn resolve_ref_name(name: &str) -> Result<String, Option<String>> {
+    match ref_util::resolve_ref(name) {
+        Ok(Some(hash)) => Ok(hash),
+        Ok(None) => Err(None),
+        Err(e) => Err(Some(e)),
+    }
+}
+
+/// Resolves `<ref>@{<n>}`, the value of a ref n changes ago, or `<ref>@{<date>}`, its value at a date
+fn resolve_reflog(name: &str, spec: &str) -> Result<String, Option<String>> {
+    let entries = reflog_util::read_reflog(name);
+    let short_name = name.trim_start_matches("refs/heads/");
+
+    if let Ok(n) = spec.parse::<usize>() {
+        if n == 0 && entries.is_empty() {
+            return resolve_ref_name(name);
+        }
+        return match entries.len().checked_sub(n + 1) {
+            Some(i) => Ok(entries[i].new.clone()),
+            None => Err(Some(format!(
+                "fatal: log for '{}' only has {} entries",
+                short_name,
+                entries.len()
+            ))),
+        };
+    }
+
+    let date = commit_util::parse_approxidate(spec, commit_util::now()).ok_or(None)?;
+    match entries.iter().rev().find(|e| e.committer.timestamp <= date) {
+        Some(entry) => Ok(entry.new.clone()),
+        // Before the reflog starts, use the oldest value we know about
+        None => match entries.first() {
+            Some(first) if first.old != ref_util::NULL_HASH => Ok(first.old.clone()),
+            Some(first) => Ok(first.new.clone()),
+            None => Err(Some(format!("fatal: log for '{}' is empty", short_name))),
+        },
+    }
+}
+
+/// Returns the remote tracking ref for a branch, using `branch.<name>.remote` and `branch.<name>.merge`
+fn upstream_ref(name: &str) -> Result<String, String> {
+    let full_name =
+        ref_util::resolve_symbolic(&ref_util::dwim_ref(name).unwrap_or_else(|| name.to_string()))?;
+    let branch = full_name
+        .strip_prefix("refs/heads/")
+        .ok_or_else(|| "fatal: HEAD does not point to a branch".to_string())?;
+
+    let no_upstream = || format!("fatal: no upstream configured for branch '{}'", branch);
+    let remote =
+        config_util::get_value(&format!("branch.{}.remote", branch)).ok_or_else(no_upstream)?;
+    let merge =
+        config_util::get_value(&format!("branch.{}.merge", branch)).ok_or_else(no_upstream)?;
+
+    // A remote of "." means the upstream is a local branch
+    if remote == "." {
+        return Ok(merge);
+    }
+    let merge_branch = merge.strip_prefix("refs/heads/").unwrap_or(&merge);
+    Ok(format!("refs/remotes/{}/{}", remote, merge_branch))
+}
+
+/// Returns the nth parent of a commit, where the 0th parent is the commit itself
+fn nth_parent(object_hash: &str, n: usize) -> Option<String> {
+    let commit_hash = peel(object_hash, Some(Object::Commit)).ok()?;
+    if n == 0 {
+        return Some(commit_hash);
+    }
+    let commit = commit_util::read_commit(&commit_hash).ok()?;
+    commit.parents.get(n - 1).cloned()
+}
+
+/// Follows the first parent n times
+fn nth_ancestor(object_hash: &str, n: usize) -> Option<String> {
+    let mut hash = peel(object_hash, Some(Object::Commit)).ok()?;
+    for _ in 0..n {
+        hash = nth_parent(&hash, 1)?;
+    }
+    Some(hash)
+}
+
+/// Returns the hash of a path in the index (stage 0)
+fn lookup_index_path(path: &str) -> Result<String, String> {
+    let (_header, items) = index_util::parse_index()?;
+    items
+        .into_iter()
+        .find(|item| item.filename == path)
+        .map(|item| item.object_hash)
+        .ok_or_else(|| format!("fatal: path '{}' does not exist in the index", path))
+}
+
+/// Finds the `:` separating a revision from a path, ignoring any inside `^{...}` or `@{...}`
+fn find_path_separator(rev: &str) -> Option<usize> {
+    let mut depth = 0;
+    for (i, c) in rev.char_indices() {
+        match c {
+            '{' => depth += 1,
+            '}' => depth -= 1,
+            ':' if depth == 0 => return Some(i),
+            _ => (),
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_find_path_separator() {
+        assert_eq!(Some(4), find_path_separator("HEAD:src/main.rs"));
+        assert_eq!(Some(0), find_path_separator(":README.md"));
+        assert_eq!(None, find_path_separator("HEAD~2"));
+        assert_eq!(Some(11), find_path_separator("HEAD^{tree}:a"));
+    }
+
+    #[test]
+    fn test_resolve_prefix_too_short() {
+        assert!(resolve_prefix("4b8").is_err());
+    }
+
+    #[test]
+    fn test_resolve_prefix_not_hex() {
+        assert!(resolve_prefix("master").is_err());
+    }
+}
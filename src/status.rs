@@ -0,0 +1,441 @@
+use super::commit_util;
+use super::ignore_util::IgnoreRules;
+use super::index_util;
+use super::index_util::IndexFile;
+use super::read_tree;
+use super::ref_util;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+
+// https://git-scm.com/docs/git-status
+
+/// The output formats for `status`
+#[derive(Debug, PartialEq)]
+pub enum StatusFormat {
+    Long,
+    PorcelainV1,
+    PorcelainV2,
+}
+
+/// A tracked path that differs between HEAD, the index, or the working tree
+#[derive(Debug)]
+struct Change {
+    path: String,
+    /// The index compared to HEAD: ' ' (unchanged), 'A', 'M' or 'D'
+    staged: char,
+    /// The working tree compared to the index: ' ' (unchanged), 'M' or 'D'
+    unstaged: char,
+    head: Option<IndexFile>,
+    index: Option<IndexFile>,
+}
+
+/// A path with merge conflicts, with its base, ours and theirs entries (stages 1, 2 and 3)
+#[derive(Debug)]
+struct Unmerged {
+    path: String,
+    stages: [Option<IndexFile>; 3],
+}
+
+/// Everything `status` reports
+#[derive(Debug, Default)]
+struct Status {
+    /// The current branch, or `None` if HEAD is detached
+    branch: Option<String>,
+    /// The current commit, or `None` on an unborn branch
+    head: Option<String>,
+    changes: Vec<Change>,
+    unmerged: Vec<Unmerged>,
+    untracked: Vec<String>,
+}
+
+/// Shows the differences between HEAD, the index and the working tree, and any untracked files
+pub fn status(format: StatusFormat) -> Result<String, String> {
+    let status = collect_status()?;
+    Ok(match format {
+        StatusFormat::Long => format_long(&status),
+        StatusFormat::PorcelainV1 => format_porcelain_v1(&status),
+        StatusFormat::PorcelainV2 => format_porcelain_v2(&status),
+    })
+}
+
+/// Parse arguments for `status`
+pub fn parse_args(args: &[String]) -> Result<StatusFormat, String> {
+    match args.first().map(|a| a.as_str()) {
+        None => Ok(StatusFormat::Long),
+        Some("--porcelain") | Some("--porcelain=v1") => Ok(StatusFormat::PorcelainV1),
+        Some("--porcelain=v2") => Ok(StatusFormat::PorcelainV2),
+        Some(arg) => Err(format!("{} is not recognized as a valid option", arg)),
+    }
+}
+
+fn collect_status() -> Result<Status, String> {
+    let head_ref = ref_util::resolve_symbolic("HEAD")?;
+    let branch = head_ref.strip_prefix("refs/heads/").map(|b| b.to_string());
+    let head = ref_util::resolve_ref("HEAD")?;
+
+    let head_tree = match &head {
+        Some(h) => read_tree::read_tree_entries(&commit_util::read_commit(h)?.tree)?,
+        None => BTreeMap::new(),
+    };
+
+    let (_header, items) = index_util::parse_index()?;
+    let tracked: BTreeSet<String> = items.iter().map(|i| i.filename.clone()).collect();
+
+    let mut unmerged: BTreeMap<String, [Option<IndexFile>; 3]> = BTreeMap::new();
+    for item in items.iter().filter(|i| i.stage != 0) {
+        let stages = unmerged
+            .entry(item.filename.clone())
+            .or_insert([None, None, None]);
+        stages[(item.stage - 1) as usize] = Some(item.clone());
+    }
+    let index = read_tree::to_map(items);
+
+    let mut paths: Vec<&String> = head_tree.keys().chain(index.keys()).collect();
+    paths.sort();
+    paths.dedup();
+
+    let mut changes = Vec::new();
+    for path in paths {
+        if unmerged.contains_key(path) {
+            continue;
+        }
+        let head_entry = head_tree.get(path);
+        let index_entry = index.get(path);
+        // An intent-to-add entry is only a new file in the working tree, nothing is staged yet
+        let staged_entry = index_entry.filter(|i| !i.intent_to_add);
+
+        let staged = match (head_entry, staged_entry) {
+            (None, Some(_)) => 'A',
+            (Some(_), None) => 'D',
+            (Some(h), Some(i)) if h.object_hash != i.object_hash || h.mode != i.mode => 'M',
+            _ => ' ',
+        };
+        let unstaged = match index_entry {
+            // The working tree file of a skip-worktree entry isn't looked at
+            Some(i) if i.skip_worktree => ' ',
+            // A symlink is looked at itself, even when what it points to is gone
+            Some(i) if fs::symlink_metadata(&i.filename).is_err() => 'D',
+            Some(i) if i.intent_to_add => 'A',
+            Some(i) if !index_util::matches_worktree(i) => 'M',
+            _ => ' ',
+        };
+
+        if staged != ' ' || unstaged != ' ' {
+            changes.push(Change {
+                path: path.clone(),
+                staged,
+                unstaged,
+                head: head_entry.cloned(),
+                index: index_entry.cloned(),
+            });
+        }
+    }
+
+    let rules = IgnoreRules::load();
+    let mut untracked = Vec::new();
+    collect_untracked("", &tracked, &rules, &mut untracked);
+    untracked.sort();
+
+    Ok(Status {
+        branch,
+        head,
+        changes,
+        unmerged: unmerged
+            .into_iter()
+            .map(|(path, stages)| Unmerged { path, stages })
+            .collect(),
+        untracked,
+    })
+}
+
+/// Walks the working tree for files that aren't in the index or ignored
+///
+/// A directory without any tracked files is listed once as `dir/`.
+/// The `gitrs` directory and any `.git` directory are always skipped
+fn collect_untracked(
+    dir: &str,
+    tracked: &BTreeSet<String>,
+    rules: &IgnoreRules,
+    untracked: &mut Vec<String>,
+) {
+    let read_path = if dir.is_empty() { "." } else { dir };
+    let entries = match fs::read_dir(read_path) {
+        Ok(entries) => entries,
+        Err(_) => return,
+    };
+
+    for entry in entries.flatten() {
+        let name = entry.file_name().to_string_lossy().to_string();
+        let path = if dir.is_empty() {
+            name.clone()
+        } else {
+            format!("{}/{}", dir, name)
+        };
+        // Symlinks to directories are not followed
+        let is_dir = entry.file_type().map(|t| t.is_dir()).unwrap_or(false);
+
+        if name == ".git" || (dir.is_empty() && name == "gitrs") {
+            continue;
+        }
+        if rules.is_ignored(&path, is_dir) {
+            continue;
+        }
+
+        if is_dir {
+            let prefix = format!("{}/", path);
+            if tracked.iter().any(|t| t.starts_with(&prefix)) {
+                collect_untracked(&path, tracked, rules, untracked);
+            } else {
+                let mut inner = Vec::new();
+                collect_untracked(&path, tracked, rules, &mut inner);
+                if !inner.is_empty() {
+                    untracked.push(prefix);
+                }
+            }
+        } else if !tracked.contains(&path) {
+            untracked.push(path);
+        }
+    }
+}
+
+fn format_long(status: &Status) -> String {
+    let mut output = match &status.branch {
+        Some(b) => format!("On branch {}\n", b),
+        None => format!(
+            "HEAD detached at {}\n",
+            status.head.as_deref().map(|h| &h[..7]).unwrap_or("")
+        ),
+    };
+    if status.head.is_none() {
+        output += "\nNo commits yet\n";
+    }
+
+    let staged: Vec<&Change> = status.changes.iter().filter(|c| c.staged != ' ').collect();
+    if !staged.is_empty() {
+        output += "\nChanges to be committed:\n";
+        for change in staged.iter() {
+            let label = match change.staged {
+                'A' => "new file:",
+                'D' => "deleted:",
+                _ => "modified:",
+            };
+            output += &format!("\t{:<12}{}\n", label, change.path);
+        }
+    }
+
+    if !status.unmerged.is_empty() {
+        output += "\nUnmerged paths:\n";
+        for unmerged in status.unmerged.iter() {
+            let label = match unmerged_code(unmerged) {
+                "DD" => "both deleted:",
+                "AU" => "added by us:",
+                "UD" => "deleted by them:",
+                "UA" => "added by them:",
+                "DU" => "deleted by us:",
+                "AA" => "both added:",
+                _ => "both modified:",
+            };
+            output += &format!("\t{:<17}{}\n", label, unmerged.path);
+        }
+    }
+
+    let unstaged: Vec<&Change> = status
+        .changes
+        .iter()
+        .filter(|c| c.unstaged != ' ')
+        .collect();
+    if !unstaged.is_empty() {
+        output += "\nChanges not staged for commit:\n";
+        for change in unstaged.iter() {
+            let label = match change.unstaged {
+                'A' => "new file:",
+                'D' => "deleted:",
+                _ => "modified:",
+            };
+            output += &format!("\t{:<12}{}\n", label, change.path);
+        }
+    }
+
+    if !status.untracked.is_empty() {
+        output += "\nUntracked files:\n";
+        for path in status.untracked.iter() {
+            output += &format!("\t{}\n", path);
+        }
+    }
+
+    // Only summarize when nothing will be committed
+    if staged.is_empty() && status.unmerged.is_empty() {
+        output += if !unstaged.is_empty() {
+            "\nno changes added to commit\n"
+        } else if !status.untracked.is_empty() {
+            "\nnothing added to commit but untracked files present\n"
+        } else {
+            "\nnothing to commit, working tree clean\n"
+        };
+    }
+    output
+}
+
+fn format_porcelain_v1(status: &Status) -> String {
+    let mut output = String::new();
+    for change in status.changes.iter() {
+        output += &format!("{}{} {}\n", change.staged, change.unstaged, change.path);
+    }
+    for unmerged in status.unmerged.iter() {
+        output += &format!("{} {}\n", unmerged_code(unmerged), unmerged.path);
+    }
+    for path in status.untracked.iter() {
+        output += &format!("?? {}\n", path);
+    }
+    output
+}
+
+fn format_porcelain_v2(status: &Status) -> String {
+    let mut output = String::new();
+    for change in status.changes.iter() {
+        let worktree_mode = match &change.index {
+            Some(_) if change.unstaged == 'D' => "000000".to_string(),
+            Some(i) => entry_mode(Some(i)),
+            None => "000000".to_string(),
+        };
+        // An intent-to-add entry has nothing staged, so it shows no index mode or hash
+        let index = change.index.as_ref().filter(|i| !i.intent_to_add);
+        output += &format!(
+            "1 {}{} N... {} {} {} {} {} {}\n",
+            v2_code(change.staged),
+            v2_code(change.unstaged),
+            entry_mode(change.head.as_ref()),
+            entry_mode(index),
+            worktree_mode,
+            entry_hash(change.head.as_ref()),
+            entry_hash(index),
+            change.path
+        );
+    }
+    for unmerged in status.unmerged.iter() {
+        let [base, ours, theirs] = &unmerged.stages;
+        let worktree_mode = if fs::symlink_metadata(&unmerged.path).is_ok() {
+            "100644"
+        } else {
+            "000000"
+        };
+        output += &format!(
+            "u {} N... {} {} {} {} {} {} {} {}\n",
+            unmerged_code(unmerged),
+            entry_mode(base.as_ref()),
+            entry_mode(ours.as_ref()),
+            entry_mode(theirs.as_ref()),
+            worktree_mode,
+            entry_hash(base.as_ref()),
+            entry_hash(ours.as_ref()),
+            entry_hash(theirs.as_ref()),
+            unmerged.path
+        );
+    }
+    for path in status.untracked.iter() {
+        output += &format!("? {}\n", path);
+    }
+    output
+}
+
+/// Porcelain v2 uses `.` instead of a space for unchanged
+fn v2_code(code: char) -> char {
+    if code == ' ' {
+        '.'
+    } else {
+        code
+    }
+}
+
+fn entry_mode(entry: Option<&IndexFile>) -> String {
+    match entry {
+        Some(e) => format!("{:o}", e.mode),
+        None => "000000".to_string(),
+    }
+}
+
+fn entry_hash(entry: Option<&IndexFile>) -> String {
+    match entry {
+        Some(e) => e.object_hash.clone(),
+        None => ref_util::NULL_HASH.to_string(),
+    }
+}
+
+/// Returns the two letter status of a conflict based on which stages are present
+fn unmerged_code(unmerged: &Unmerged) -> &'static str {
+    match (
+        unmerged.stages[0].is_some(),
+        unmerged.stages[1].is_some(),
+        unmerged.stages[2].is_some(),
+    ) {
+        (true, false, false) => "DD",
+        (false, true, false) => "AU",
+        (true, true, false) => "UD",
+        (false, false, true) => "UA",
+        (true, false, true) => "DU",
+        (false, true, true) => "AA",
+        _ => "UU",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn change(path: &str, staged: char, unstaged: char) -> Change {
+        Change {
+            path: path.to_string(),
+            staged,
+            unstaged,
+            head: None,
+            index: Some(IndexFile::new(
+                path,
+                0o100644,
+                "4b825dc642cb6eb9a060e54bf8d69288fbee4904",
+                0,
+            )),
+        }
+    }
+
+    #[test]
+    fn test_parse_args() {
+        assert_eq!(Ok(StatusFormat::Long), parse_args(&[]));
+        assert_eq!(
+            Ok(StatusFormat::PorcelainV2),
+            parse_args(&["--porcelain=v2".to_string()])
+        );
+        assert!(parse_args(&["--porcelain=v3".to_string()]).is_err());
+    }
+
+    #[test]
+    fn test_format_porcelain_v1() {
+        let status = Status {
+            changes: vec![change("a", 'A', ' '), change("b", ' ', 'M')],
+            untracked: vec!["c".to_string()],
+            ..Status::default()
+        };
+        assert_eq!("A  a\n M b\n?? c\n", format_porcelain_v1(&status));
+    }
+
+    #[test]
+    fn test_format_porcelain_v2() {
+        let status = Status {
+            changes: vec![change("a", 'A', ' ')],
+            ..Status::default()
+        };
+        assert_eq!(
+            "1 A. N... 000000 100644 100644 0000000000000000000000000000000000000000 4b825dc642cb6eb9a060e54bf8d69288fbee4904 a\n",
+            format_porcelain_v2(&status)
+        );
+    }
+
+    #[test]
+    fn test_unmerged_code() {
+        let entry = Some(IndexFile::new("a", 0o100644, "1", 1));
+        let unmerged = Unmerged {
+            path: "a".to_string(),
+            stages: [entry.clone(), entry.clone(), entry],
+        };
+        assert_eq!("UU", unmerged_code(&unmerged));
+    }
+}
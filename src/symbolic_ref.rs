@@ -0,0 +1,14 @@
+use super::ref_util;
+
+/// Returns the ref a symbolic ref points to, e.g. `refs/heads/master` for `HEAD`
+pub fn read_symbolic_ref(name: &str) -> Result<String, String> {
+    match ref_util::read_ref(name) {
+        Some(ref_util::RefValue::Symbolic(target)) => Ok(target),
+        _ => Err(format!("fatal: ref {} is not a symbolic ref", name)),
+    }
+}
+
+/// Points a symbolic ref at another ref
+pub fn write_symbolic_ref(name: &str, target: &str) -> Result<(), String> {
+    ref_util::write_symbolic_ref(name, target)
+}
@@ -0,0 +1,235 @@
+use super::commit_tree;
+use super::commit_util;
+use super::ignore_util;
+use super::object_util;
+use super::object_util::Object;
+use super::ref_util;
+use super::rev_parse;
+use super::tag_util;
+use super::tag_util::Tag;
+
+// https://git-scm.com/docs/git-tag
+
+/// What `tag` should do
+#[derive(Debug, PartialEq)]
+pub enum TagAction {
+    /// Creates a tag pointing at `target`, annotated if there is a message
+    Create {
+        name: String,
+        target: String,
+        message: Option<String>,
+        force: bool,
+    },
+    /// Lists the tags matching the pattern, or every tag
+    List(Option<String>),
+    /// Deletes the named tags
+    Delete(Vec<String>),
+}
+
+/// Creates, lists or deletes tags
+///
+/// Lightweight tags are refs under `refs/tags` pointing straight at an object.
+/// Annotated tags point at a tag object holding the tagger, the message and the tagged object.
+///
+/// Returns the output to show
+pub fn tag(action: &TagAction) -> Result<String, String> {
+    match action {
+        TagAction::Create {
+            name,
+            target,
+            message,
+            force,
+        } => create_tag(name, target, message.as_deref(), *force).map(|_| String::new()),
+        TagAction::List(pattern) => Ok(list_tags(pattern.as_deref())),
+        TagAction::Delete(names) => delete_tags(names),
+    }
+}
+
+/// Parse arguments for `tag`
+pub fn parse_args(args: &[String]) -> Result<TagAction, String> {
+    let mut list = false;
+    let mut delete = false;
+    let mut annotate = false;
+    let mut force = false;
+    let mut messages = Vec::new();
+    let mut names = Vec::new();
+
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "-l" | "--list" => list = true,
+            "-d" | "--delete" => delete = true,
+            "-a" | "--annotate" => annotate = true,
+            "-f" | "--force" => force = true,
+            "-m" => match iter.next() {
+                Some(message) => messages.push(message.to_owned()),
+                None => return Err("error: switch `m' requires a value".to_string()),
+            },
+            _ if arg.starts_with('-') => {
+                return Err(format!("{} is not recognized as a valid option", arg))
+            }
+            _ => names.push(arg.to_owned()),
+        }
+    }
+
+    if list && delete {
+        return Err("fatal: -l and -d cannot be used together".to_string());
+    }
+    if delete {
+        if names.is_empty() {
+            return Err("fatal: tag name required".to_string());
+        }
+        return Ok(TagAction::Delete(names));
+    }
+    if list || names.is_empty() {
+        if names.len() > 1 {
+            return Err("fatal: only one pattern can be given with -l".to_string());
+        }
+        return Ok(TagAction::List(names.pop()));
+    }
+    if names.len() > 2 {
+        return Err("fatal: too many arguments".to_string());
+    }
+    if annotate && messages.is_empty() {
+        return Err("fatal: no tag message given, use -m <message>".to_string());
+    }
+
+    let target = names.get(1).cloned().unwrap_or_else(|| "HEAD".to_string());
+    Ok(TagAction::Create {
+        name: names.remove(0),
+        target,
+        // A message always makes an annotated tag, like git
+        message: if messages.is_empty() {
+            None
+        } else {
+            Some(commit_tree::join_messages(&messages))
+        },
+        force,
+    })
+}
+
+/// Creates `refs/tags/<name>`, writing a tag object first if there is a message
+fn create_tag(name: &str, target: &str, message: Option<&str>, force: bool) -> Result<(), String> {
+    let ref_name = format!("refs/tags/{}", name);
+    if !ref_util::is_valid_ref_name(&ref_name) {
+        return Err(format!("fatal: '{}' is not a valid tag name", name));
+    }
+    let old = ref_util::resolve_ref(&ref_name)?;
+    if old.is_some() && !force {
+        return Err(format!("fatal: tag '{}' already exists", name));
+    }
+
+    let object = rev_parse::rev_parse(target)?;
+    let message_for_reflog = reflog_message(&object)?;
+    let hash = match message {
+        Some(message) => tag_util::write_tag(&Tag {
+            object_type: object_util::read_object_type(&object)?,
+            object,
+            tag: name.to_string(),
+            tagger: Some(commit_util::get_signature("COMMITTER")?),
+            extra_headers: Vec::new(),
+            message: message.as_bytes().to_vec(),
+        }),
+        None => object,
+    };
+
+    let expected = old.unwrap_or_else(|| ref_util::NULL_HASH.to_string());
+    ref_util::update_ref(&ref_name, &hash, Some(&expected), &message_for_reflog)
+}
+
+/// Describes the tagged object for the reflog, e.g. `tag: tagging 44a8fb7 (subject, 2005-04-07)`
+fn reflog_message(object: &str) -> Result<String, String> {
+    let description = match object_util::read_object_type(object)? {
+        Object::Commit => match commit_util::read_commit(object) {
+            Ok(commit) => format!(
+                "{}, {}",
+                commit.subject(),
+                commit_util::format_short_date(commit.committer.timestamp)
+            ),
+            Err(_) => "commit object".to_string(),
+        },
+        Object::Tree => "tree object".to_string(),
+        Object::Blob => "blob object".to_string(),
+        Object::Tag => "other tag object".to_string(),
+    };
+    Ok(format!("tag: tagging {} ({})", &object[..7], description))
+}
+
+/// Returns the names of the tags matching a glob pattern, one per line
+fn list_tags(pattern: Option<&str>) -> String {
+    ref_util::list_refs("refs/tags/")
+        .into_iter()
+        .map(|(name, _)| name.trim_start_matches("refs/tags/").to_string())
+        .filter(|name| pattern.is_none_or(|p| ignore_util::fnmatch(p, name)))
+        .map(|name| format!("{}\n", name))
+        .collect()
+}
+
+/// Deletes each tag, continuing past tags that don't exist
+///
+/// Returns an error listing the missing tags if there were any
+fn delete_tags(names: &[String]) -> Result<String, String> {
+    let mut output = String::new();
+    let mut errors = Vec::new();
+
+    for name in names.iter() {
+        let ref_name = format!("refs/tags/{}", name);
+        match ref_util::resolve_ref(&ref_name)? {
+            Some(hash) => {
+                ref_util::delete_ref(&ref_name, Some(&hash))?;
+                output += &format!("Deleted tag '{}' (was {})\n", name, &hash[..7]);
+            }
+            None => errors.push(format!("error: tag '{}' not found.", name)),
+        }
+    }
+
+    if errors.is_empty() {
+        Ok(output)
+    } else {
+        Err(output + &errors.join("\n"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn to_args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|a| a.to_string()).collect()
+    }
+
+    #[test]
+    fn test_parse_args_annotated() {
+        assert_eq!(
+            Ok(TagAction::Create {
+                name: "v1.0".to_string(),
+                target: "HEAD~1".to_string(),
+                message: Some("Release\n".to_string()),
+                force: false,
+            }),
+            parse_args(&to_args(&["-a", "-m", "Release", "v1.0", "HEAD~1"]))
+        );
+    }
+
+    #[test]
+    fn test_parse_args_annotate_requires_message() {
+        assert!(parse_args(&to_args(&["-a", "v1.0"])).is_err());
+    }
+
+    #[test]
+    fn test_parse_args_list() {
+        assert_eq!(Ok(TagAction::List(None)), parse_args(&[]));
+        assert_eq!(
+            Ok(TagAction::List(Some("v1.*".to_string()))),
+            parse_args(&to_args(&["-l", "v1.*"]))
+        );
+    }
+
+    #[test]
+    fn test_parse_args_delete() {
+        assert_eq!(
+            Ok(TagAction::Delete(vec!["a".to_string(), "b".to_string()])),
+            parse_args(&to_args(&["-d", "a", "b"]))
+        );
+    }
+}
@@ -0,0 +1,143 @@
+use super::commit_util;
+use super::commit_util::Signature;
+use super::object_util;
+use super::object_util::Object;
+
+/// The contents of an annotated tag object
+#[derive(Debug, Clone, PartialEq)]
+pub struct Tag {
+    /// The object being tagged
+    pub object: String,
+    pub object_type: Object,
+    /// The name of the tag
+    pub tag: String,
+    /// Very old tags have no tagger
+    pub tagger: Option<Signature>,
+    /// Any other headers in the order they appear
+    pub extra_headers: Vec<(String, String)>,
+    /// The raw message, including any signature
+    pub message: Vec<u8>,
+}
+
+impl Tag {
+    /// Serializes the tag into the content of a tag object (without the object header)
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut content = format!(
+            "object {}\ntype {}\ntag {}\n",
+            self.object, self.object_type, self.tag
+        );
+        if let Some(tagger) = &self.tagger {
+            content += &format!("tagger {}\n", tagger);
+        }
+        content += &commit_util::format_headers(&self.extra_headers);
+        content += "\n";
+
+        let mut bytes = content.into_bytes();
+        bytes.extend_from_slice(&self.message);
+        bytes
+    }
+}
+
+/// Parses the content of a tag object (without the object header)
+///
+/// # Format
+///
+/// Like a commit, a tag is a list of `key value` header lines, a blank line, then the message
+pub fn parse_tag(content: &[u8]) -> Result<Tag, String> {
+    let invalid = || "fatal: invalid tag object".to_string();
+    let (headers, message) = commit_util::split_headers(content);
+    let headers = String::from_utf8_lossy(headers);
+
+    let mut object = None;
+    let mut object_type = None;
+    let mut tag = None;
+    let mut tagger = None;
+    let mut extra_headers = Vec::new();
+
+    for (key, value) in commit_util::parse_headers(&headers).ok_or_else(invalid)? {
+        match key.as_str() {
+            "object" if object.is_none() => object = Some(value),
+            "type" if object_type.is_none() => {
+                object_type = Some(object_util::parse_type(&value).map_err(|_| invalid())?)
+            }
+            "tag" if tag.is_none() => tag = Some(value),
+            "tagger" if tagger.is_none() => tagger = Some(commit_util::parse_signature(&value)?),
+            _ => extra_headers.push((key, value)),
+        }
+    }
+
+    match (object, object_type, tag) {
+        (Some(object), Some(object_type), Some(tag)) if object_util::is_valid_hash(&object) => {
+            Ok(Tag {
+                object,
+                object_type,
+                tag,
+                tagger,
+                extra_headers,
+                message: message.to_vec(),
+            })
+        }
+        _ => Err(invalid()),
+    }
+}
+
+/// Reads and parses the given tag from the object database
+pub fn read_tag(object_hash: &str) -> Result<Tag, String> {
+    if !object_util::object_exists(object_hash) {
+        return Err(format!("fatal: not a valid object name {}", object_hash));
+    }
+
+    let (object_type, content) = object_util::read_object(object_hash);
+    if object_type != Object::Tag {
+        return Err(format!("fatal: {} is not a tag", object_hash));
+    }
+    parse_tag(&content)
+}
+
+/// Writes the tag to the object database
+///
+/// Returns the hash of the new tag object
+pub fn write_tag(tag: &Tag) -> String {
+    object_util::write_object(Object::Tag, &tag.to_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SIGNED_TAG: &[u8] = b"object 0123456789012345678901234567890123456789\n\
+type commit\n\
+tag v1.0\n\
+tagger A U Thor <author@example.com> 1112911993 -0700\n\
+\n\
+Version 1.0\n\
+-----BEGIN PGP SIGNATURE-----\n\
+\n\
+iQEzBAABCAAdFiEE\n\
+-----END PGP SIGNATURE-----\n";
+
+    #[test]
+    fn test_parse_tag() {
+        let tag = parse_tag(SIGNED_TAG).unwrap();
+        assert_eq!("0123456789012345678901234567890123456789", tag.object);
+        assert_eq!(Object::Commit, tag.object_type);
+        assert_eq!("v1.0", tag.tag);
+        assert_eq!(1112911993, tag.tagger.as_ref().unwrap().timestamp);
+        assert!(tag.message.starts_with(b"Version 1.0\n-----BEGIN"));
+        assert_eq!(SIGNED_TAG, &tag.to_bytes()[..]);
+    }
+
+    #[test]
+    fn test_parse_tag_without_tagger() {
+        let content =
+            b"object 0123456789012345678901234567890123456789\ntype tree\ntag old\n\nOld\n";
+        let tag = parse_tag(content).unwrap();
+        assert_eq!(None, tag.tagger);
+        assert_eq!(&content[..], &tag.to_bytes()[..]);
+    }
+
+    #[test]
+    fn test_parse_tag_missing_object() {
+        assert!(parse_tag(b"type commit\ntag v1.0\n\nmessage\n").is_err());
+    }
+}
@@ -0,0 +1,384 @@
+use super::commit_util;
+use std::env;
+use std::fs;
+
+// https://www.rfc-editor.org/rfc/rfc8536 (TZif)
+// https://pubs.opengroup.org/onlinepubs/9699919799/basedefs/V1_chap08.html (TZ)
+
+const ZONEINFO_DIR: &str = "/usr/share/zoneinfo";
+
+/// Returns the local timezone at a time as git writes it, e.g. `-0700`
+///
+/// Like the C library, the zone comes from `TZ`, which is either the name of a zoneinfo file
+/// such as `Europe/Paris` or a POSIX rule such as `CET-1CEST,M3.5.0,M10.5.0/3`,
+/// and falls back to `/etc/localtime`. Anything that can't be read is treated as UTC.
+pub fn local_timezone(timestamp: u64) -> String {
+    let offset = local_offset(timestamp as i64).unwrap_or(0);
+    let sign = if offset < 0 { '-' } else { '+' };
+    let minutes = offset.abs() / 60;
+    format!("{}{:02}{:02}", sign, minutes / 60, minutes % 60)
+}
+
+/// Returns the offset from UTC in seconds of the local timezone at a time
+fn local_offset(timestamp: i64) -> Option<i64> {
+    let tz = match env::var("TZ") {
+        Ok(tz) => tz,
+        Err(_) => return tzif_offset(&fs::read("/etc/localtime").ok()?, timestamp),
+    };
+
+    let name = tz.strip_prefix(':').unwrap_or(&tz);
+    if name.is_empty() {
+        return Some(0);
+    }
+    let path = if name.starts_with('/') {
+        name.to_string()
+    } else {
+        format!("{}/{}", ZONEINFO_DIR, name)
+    };
+    match fs::read(path) {
+        Ok(data) => tzif_offset(&data, timestamp),
+        Err(_) => posix_offset(name, timestamp),
+    }
+}
+
+/// Looks up the offset at a time in the content of a TZif file
+///
+/// # Format
+///
+/// * A 44 byte header: `TZif`, the version, 15 unused bytes, then six 32-bit counts
+/// * The transition times, the index of the local time type each switches to,
+///   the local time types (a 32-bit offset, a DST flag and an abbreviation index),
+///   then the abbreviations, leap seconds and indicators, which aren't needed
+/// * From version 2, the same again with 64-bit times, then a POSIX rule on its own line
+///   for times after the last transition
+fn tzif_offset(data: &[u8], timestamp: i64) -> Option<i64> {
+    let (version, counts) = parse_tzif_header(data)?;
+    let v1_len = 44 + block_len(&counts, 4);
+
+    if version == 0 {
+        return block_offset(&data[44..], &counts, 4, timestamp).map(|(offset, _)| offset);
+    }
+
+    let data = data.get(v1_len..)?;
+    let (_, counts) = parse_tzif_header(data)?;
+    let block = &data[44..];
+    match block_offset(block, &counts, 8, timestamp)? {
+        (offset, false) => Some(offset),
+        // After the last transition the footer's rule applies
+        (offset, true) => {
+            let footer = String::from_utf8_lossy(&block[block_len(&counts, 8)..]);
+            let rule = footer.trim_start_matches('\n').split('\n').next();
+            Some(
+                rule.and_then(|r| posix_offset(r, timestamp))
+                    .unwrap_or(offset),
+            )
+        }
+    }
+}
+
+/// Returns the version (0 for the first) and the counts of a TZif header:
+/// UT indicators, standard/wall indicators, leap seconds, transitions, local time types
+/// and abbreviation bytes
+fn parse_tzif_header(data: &[u8]) -> Option<(u8, [usize; 6])> {
+    if data.len() < 44 || &data[..4] != b"TZif" {
+        return None;
+    }
+    let version = if data[4] == 0 { 0 } else { data[4] - b'0' };
+
+    let mut counts = [0; 6];
+    for (i, count) in counts.iter_mut().enumerate() {
+        let bytes = &data[20 + i * 4..24 + i * 4];
+        *count = u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as usize;
+    }
+    Some((version, counts))
+}
+
+/// Returns the length of a TZif data block with times of `time_size` bytes
+fn block_len(counts: &[usize; 6], time_size: usize) -> usize {
+    let [isut, isstd, leap, time, types, chars] = *counts;
+    time * time_size + time + types * 6 + chars + leap * (time_size + 4) + isstd + isut
+}
+
+/// Finds the local time type in effect at a time in a TZif data block
+///
+/// Returns its offset, and whether the time is after the last transition
+fn block_offset(
+    block: &[u8],
+    counts: &[usize; 6],
+    time_size: usize,
+    timestamp: i64,
+) -> Option<(i64, bool)> {
+    let [_, _, _, time_count, type_count, _] = *counts;
+    if block.len() < block_len(counts, time_size) || type_count == 0 {
+        return None;
+    }
+
+    let times = &block[..time_count * time_size];
+    let indexes = &block[time_count * time_size..time_count * (time_size + 1)];
+    let types = &block[time_count * (time_size + 1)..][..type_count * 6];
+
+    let transition_time = |i: usize| -> i64 {
+        let bytes = &times[i * time_size..(i + 1) * time_size];
+        match time_size {
+            4 => i32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as i64,
+            _ => i64::from_be_bytes([
+                bytes[0], bytes[1], bytes[2], bytes[3], bytes[4], bytes[5], bytes[6], bytes[7],
+            ]),
+        }
+    };
+    // Before the first transition, the first local time type applies
+    let passed = (0..time_count)
+        .take_while(|&i| transition_time(i) <= timestamp)
+        .count();
+    let type_index = match passed {
+        0 => 0,
+        n => indexes[n - 1] as usize,
+    };
+
+    let local_type = types.get(type_index * 6..type_index * 6 + 4)?;
+    let offset = i32::from_be_bytes([local_type[0], local_type[1], local_type[2], local_type[3]]);
+    Some((offset as i64, time_count == 0 || passed == time_count))
+}
+
+/// Returns the offset at a time for a POSIX TZ rule, e.g. `EST5EDT,M3.2.0,M11.1.0`
+///
+/// # Format
+///
+/// `std offset [dst [offset] [,start[/time],end[/time]]]`, where names are letters or quoted
+/// in `<>` and offsets are `[+-]hh[:mm[:ss]]` west of UTC. Only `Mm.w.d` dates are supported,
+/// other daylight saving rules fall back to standard time.
+fn posix_offset(rule: &str, timestamp: i64) -> Option<i64> {
+    let rest = skip_zone_name(rule)?;
+    let (std_offset, rest) = parse_duration(rest)?;
+    let std_offset = -std_offset;
+    if rest.is_empty() {
+        return Some(std_offset);
+    }
+
+    let rest = skip_zone_name(rest)?;
+    let (dst_offset, rest) = match parse_duration(rest) {
+        Some((offset, rest)) => (-offset, rest),
+        None => (std_offset + 3600, rest),
+    };
+    let (start, end) = match rest.strip_prefix(',').and_then(|r| r.split_once(',')) {
+        Some(rules) => rules,
+        None => return Some(std_offset),
+    };
+
+    let (year, _, _) = commit_util::civil_from_days((timestamp + std_offset).div_euclid(86400));
+    let (start, end) = match (
+        transition_time(start, year, std_offset),
+        transition_time(end, year, dst_offset),
+    ) {
+        (Some(start), Some(end)) => (start, end),
+        _ => return Some(std_offset),
+    };
+
+    // In the southern hemisphere daylight saving time spans the new year
+    let dst = if start < end {
+        start <= timestamp && timestamp < end
+    } else {
+        !(end <= timestamp && timestamp < start)
+    };
+    Some(if dst { dst_offset } else { std_offset })
+}
+
+/// Skips the name of a zone in a POSIX TZ rule, returning the rest
+fn skip_zone_name(rule: &str) -> Option<&str> {
+    if let Some(quoted) = rule.strip_prefix('<') {
+        return quoted.split_once('>').map(|(_, rest)| rest);
+    }
+    let len = rule
+        .find(|c: char| !c.is_ascii_alphabetic())
+        .unwrap_or(rule.len());
+    match len {
+        0 => None,
+        _ => Some(&rule[len..]),
+    }
+}
+
+/// Parses `[+-]hh[:mm[:ss]]` into seconds, returning the rest
+///
+/// Like the C library, at most 167 hours are accepted, the most a rule's time can move a transition
+fn parse_duration(text: &str) -> Option<(i64, &str)> {
+    let (sign, text) = match text.strip_prefix('-') {
+        Some(text) => (-1, text),
+        None => (1, text.strip_prefix('+').unwrap_or(text)),
+    };
+    let len = text
+        .find(|c: char| !c.is_ascii_digit() && c != ':')
+        .unwrap_or(text.len());
+    if len == 0 {
+        return None;
+    }
+
+    let parts: Vec<&str> = text[..len].split(':').collect();
+    if parts.len() > 3 {
+        return None;
+    }
+    let mut seconds = 0i64;
+    for (part, unit) in parts.iter().zip([3600, 60, 1]) {
+        seconds = seconds.checked_add(part.parse::<i64>().ok()?.checked_mul(unit)?)?;
+    }
+    if seconds > 167 * 3600 {
+        return None;
+    }
+    Some((sign * seconds, &text[len..]))
+}
+
+/// Returns the time a daylight saving rule such as `M3.5.0/3` happens in a year
+///
+/// The rule is in the local time of `offset`, which is in effect just before it happens
+fn transition_time(rule: &str, year: i64, offset: i64) -> Option<i64> {
+    let (date, time) = match rule.split_once('/') {
+        Some((date, time)) => (date, parse_duration(time)?.0),
+        None => (rule, 2 * 3600),
+    };
+
+    let fields: Vec<i64> = date
+        .strip_prefix('M')?
+        .split('.')
+        .map(|f| f.parse().ok())
+        .collect::<Option<_>>()?;
+    let (month, week, weekday) = match fields[..] {
+        [m, w, d] if (1..=12).contains(&m) && (1..=5).contains(&w) && (0..=6).contains(&d) => {
+            (m, w, d)
+        }
+        _ => return None,
+    };
+
+    // The first matching weekday of the month, then the given week, where 5 means the last
+    let first = commit_util::days_from_civil(year, month, 1);
+    let mut day = first + (weekday - (first + 4).rem_euclid(7)).rem_euclid(7) + (week - 1) * 7;
+    let next_month = match month {
+        12 => commit_util::days_from_civil(year + 1, 1, 1),
+        _ => commit_util::days_from_civil(year, month + 1, 1),
+    };
+    while day >= next_month {
+        day -= 7;
+    }
+
+    Some(day * 86400 + time - offset)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_posix_offset() {
+        let rule = "CET-1CEST,M3.5.0,M10.5.0/3";
+        // 2005-01-15 and 2005-07-15
+        assert_eq!(Some(3600), posix_offset(rule, 1105747200));
+        assert_eq!(Some(7200), posix_offset(rule, 1121385600));
+        // Daylight saving time starts at 2005-03-27 01:00 UTC
+        assert_eq!(Some(3600), posix_offset(rule, 1111885199));
+        assert_eq!(Some(7200), posix_offset(rule, 1111885200));
+
+        assert_eq!(Some(-7 * 3600), posix_offset("MST7", 1112911993));
+        assert_eq!(
+            Some(-7 * 3600),
+            posix_offset("PST8PDT,M3.2.0,M11.1.0", 1112911993)
+        );
+        assert_eq!(
+            Some(5 * 3600 + 1800),
+            posix_offset("<+0530>-5:30", 1112911993)
+        );
+        assert_eq!(None, posix_offset("5", 1112911993));
+    }
+
+    #[test]
+    fn test_posix_offset_southern_hemisphere() {
+        let rule = "AEST-10AEDT,M10.1.0,M4.1.0/3";
+        assert_eq!(Some(11 * 3600), posix_offset(rule, 1105747200));
+        assert_eq!(Some(10 * 3600), posix_offset(rule, 1121385600));
+        // Daylight saving time ends at 2005-04-03 03:00 local time, 2005-04-02 16:00 UTC
+        assert_eq!(Some(11 * 3600), posix_offset(rule, 1112457599));
+        assert_eq!(Some(10 * 3600), posix_offset(rule, 1112457600));
+        // and starts again at 2005-10-02 02:00 local time, 2005-10-01 16:00 UTC
+        assert_eq!(Some(10 * 3600), posix_offset(rule, 1128182399));
+        assert_eq!(Some(11 * 3600), posix_offset(rule, 1128182400));
+    }
+
+    #[test]
+    fn test_posix_offset_end_of_dst() {
+        // Daylight saving time ends at 2005-10-30 01:00 UTC, the last Sunday of a month
+        // with five Sundays
+        let rule = "CET-1CEST,M3.5.0,M10.5.0/3";
+        assert_eq!(Some(7200), posix_offset(rule, 1130633999));
+        assert_eq!(Some(3600), posix_offset(rule, 1130634000));
+    }
+
+    #[test]
+    fn test_parse_duration() {
+        assert_eq!(
+            Some((3600, "CEST")),
+            parse_duration("-1CEST").map(|(d, r)| (-d, r))
+        );
+        assert_eq!(
+            Some((5 * 3600 + 30 * 60 + 15, "")),
+            parse_duration("5:30:15")
+        );
+        assert_eq!(Some((167 * 3600, "")), parse_duration("167"));
+        assert_eq!(None, parse_duration("168"));
+        assert_eq!(None, parse_duration("1:2:3:4"));
+        assert_eq!(None, parse_duration("99999999999999999"));
+        assert_eq!(None, parse_duration("3074457345618258:0"));
+        assert_eq!(None, parse_duration("ABC"));
+        assert_eq!(None, posix_offset("UTC99999999999999999", 0));
+    }
+
+    /// Builds a version 2 TZif file with no version 1 data, switching between CET and CEST
+    /// at the given times, then following the CET rule
+    fn tzif(transitions: &[(i64, u8)]) -> Vec<u8> {
+        let header = |time_count: u32, type_count: u32, char_count: u32| {
+            let mut header = b"TZif2".to_vec();
+            header.extend_from_slice(&[0; 15]);
+            for count in [0, 0, 0, time_count, type_count, char_count] {
+                header.extend_from_slice(&u32::to_be_bytes(count));
+            }
+            header
+        };
+
+        let mut data = header(0, 0, 0);
+        data.extend(header(transitions.len() as u32, 2, 10));
+        for (time, _) in transitions.iter() {
+            data.extend_from_slice(&time.to_be_bytes());
+        }
+        data.extend(transitions.iter().map(|(_, index)| index));
+        data.extend_from_slice(&[0, 0, 0x0e, 0x10, 0, 0]);
+        data.extend_from_slice(&[0, 0, 0x1c, 0x20, 1, 4]);
+        data.extend_from_slice(b"CET\0CEST\0");
+        data.extend_from_slice(b"\nCET-1CEST,M3.5.0,M10.5.0/3\n");
+        data
+    }
+
+    #[test]
+    fn test_tzif_offset() {
+        let data = tzif(&[(1111885200, 1), (1130634000, 0)]);
+        // Before the first transition, the first type applies
+        assert_eq!(Some(3600), tzif_offset(&data, 1101859200));
+        assert_eq!(Some(3600), tzif_offset(&data, 1111885199));
+        assert_eq!(Some(7200), tzif_offset(&data, 1111885200));
+        assert_eq!(Some(7200), tzif_offset(&data, 1130633999));
+        // From the last transition, the footer's rule applies
+        assert_eq!(Some(3600), tzif_offset(&data, 1130634000));
+        assert_eq!(Some(7200), tzif_offset(&data, 1152921600));
+        assert_eq!(Some(3600), tzif_offset(&data, 1137283200));
+
+        // With no transitions, only the footer is used
+        assert_eq!(Some(7200), tzif_offset(&tzif(&[]), 1152921600));
+    }
+
+    #[test]
+    fn test_tzif_offset_invalid() {
+        let data = tzif(&[(1111885200, 1), (1130634000, 0)]);
+        assert_eq!(None, tzif_offset(&data[..60], 1111885200));
+        assert_eq!(None, tzif_offset(b"TZif2", 1111885200));
+        assert_eq!(None, tzif_offset(b"not a timezone file", 0));
+
+        // A transition to a local time type that doesn't exist
+        let data = tzif(&[(1111885200, 7)]);
+        assert_eq!(None, tzif_offset(&data, 1111885200));
+    }
+}
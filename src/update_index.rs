@@ -1,6 +1,3 @@
-use std::fs;
-use std::time::{Duration, SystemTime};
-
 use super::hash_object::write_hash_object;
 use super::index_util;
 use super::index_util::IndexFile;
@@ -11,73 +8,51 @@ use super::index_util::IndexFile;
 ///
 /// Ignores new files
 pub fn update_index() -> Result<(), String> {
-    let (_header, items) = index_util::parse_index()?;
+    let (index, items) = index_util::lock_index()?;
 
     let updated_items = update_index_items(items);
 
-    match index_util::write_index(updated_items) {
-        Ok(_) => Ok(()),
-        Err(e) => Err(e.to_string()),
+    index_util::write_index(index, updated_items)
+}
+
+/// Rewrites the index in the given index version
+///
+/// Versions 2 and 3 are chosen between automatically when the index is written,
+/// so asking for either gives version 3 only if an entry needs extended flags
+pub fn set_index_version(version: &str) -> Result<(), String> {
+    let version: u32 = version
+        .parse()
+        .map_err(|_| "error: option `index-version' expects a numerical value".to_string())?;
+    if !index_util::INDEX_VERSIONS.contains(&version) {
+        return Err(format!(
+            "fatal: index-version {} not in range: {}..{}",
+            version,
+            index_util::INDEX_VERSIONS.start(),
+            index_util::INDEX_VERSIONS.end()
+        ));
     }
+
+    let (index, items) = index_util::lock_index()?;
+    index_util::write_index_version(index, items, version)
 }
 
 /// Add specified file to the index and update existing items
 ///
-/// Will fail for duplicate items
+/// An entry already in the index for the file is replaced, along with any conflict stages
 pub fn add_to_index(filepath: &str) -> Result<(), String> {
-    let (_header, mut items) = index_util::parse_index()?;
+    let (index, mut items) = index_util::lock_index()?;
 
     // filepath should be normalized to avoid false negatives
-    for item in items.iter() {
-        if filepath == item.filename {
-            return Err("This file already exists in the index".to_string());
-        }
-    }
-
+    items.retain(|item| item.filename != filepath);
     items = update_index_items(items);
 
     let object_hash = write_hash_object(filepath);
-
-    let mut ctime = 0u32;
-    let mut mtime = 0u32;
-    let mut size = 0u32;
-
-    if let Ok(metadata) = fs::metadata(filepath) {
-        ctime = metadata
-            .created()
-            .unwrap_or_else(|_| SystemTime::now())
-            .duration_since(SystemTime::UNIX_EPOCH)
-            .unwrap_or_else(|_| Duration::new(0, 0))
-            .as_secs() as u32;
-        mtime = metadata
-            .modified()
-            .unwrap_or_else(|_| SystemTime::now())
-            .duration_since(SystemTime::UNIX_EPOCH)
-            .unwrap_or_else(|_| Duration::new(0, 0))
-            .as_secs() as u32;
-        size = metadata.len() as u32;
-    }
-
-    let new_item = IndexFile {
-        ctime,
-        ctime_fractions: 0,
-        mtime,
-        mtime_fractions: 0,
-        dev: None,
-        ino: None,
-        permissions: "644".to_string(),
-        uid: None,
-        gid: None,
-        size,
-        object_hash,
-        filename: filepath.to_string(),
-    };
+    let new_item = index_util::refresh_stat(&IndexFile::new(filepath, 0o100644, &object_hash, 0));
     items.push(new_item);
+    // Like add, entries are kept sorted by path and stage, git rejects an index that isn't
+    items.sort_by(|a, b| (a.filename.as_bytes(), a.stage).cmp(&(b.filename.as_bytes(), b.stage)));
 
-    match index_util::write_index(items) {
-        Ok(_) => Ok(()),
-        Err(e) => Err(e.to_string()),
-    }
+    index_util::write_index(index, items)
 }
 
 /// Remove specified file from the index
@@ -88,33 +63,20 @@ pub fn remove_from_index(force: bool) -> Result<(), String> {
 }
 
 /// Update [`IndexFile`]'s hash and file properties
+///
+/// Skip-worktree entries are kept as they are, their files may not be in the working tree
 fn update_index_items(items: Vec<IndexFile>) -> Vec<IndexFile> {
     let mut updated_items: Vec<IndexFile> = Vec::new();
     for item in items.iter() {
-        let object_hash = write_hash_object(&item.filename);
-
-        let mut mtime = 0u32;
-        let mut size = 0u32;
-
-        // metadata also has dev, ino, uid, and gid fields but since windows doesn't use them
-        // I'm ignoring it for now
-        if let Ok(metadata) = fs::metadata(&item.filename) {
-            mtime = metadata
-                .modified()
-                .unwrap_or_else(|_| SystemTime::now())
-                .duration_since(SystemTime::UNIX_EPOCH)
-                .unwrap_or_else(|_| Duration::new(0, 0))
-                .as_secs() as u32;
-
-            size = metadata.len() as u32;
+        if item.skip_worktree {
+            updated_items.push(item.clone());
+            continue;
         }
-
-        let updated = IndexFile {
-            mtime,
-            size,
+        let object_hash = write_hash_object(&item.filename);
+        let updated = index_util::refresh_stat(&IndexFile {
             object_hash,
             ..item.clone()
-        };
+        });
         updated_items.push(updated);
     }
     updated_items
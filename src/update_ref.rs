@@ -0,0 +1,106 @@
+use super::ref_util;
+use super::rev_parse;
+
+/// Arguments for `update_ref`
+#[derive(Debug, Default, PartialEq)]
+pub struct UpdateRefOptions {
+    /// The ref to update, e.g. `refs/heads/master` or `HEAD`
+    pub name: String,
+    /// The object to point the ref at, or `None` to delete the ref
+    pub new: Option<String>,
+    /// The value the ref must currently have. An empty string or
+    /// the null hash means the ref must not exist yet
+    pub old: Option<String>,
+    /// The reason for the update, recorded in the reflog
+    pub message: String,
+}
+
+/// Updates or deletes a ref, optionally checking its current value first
+pub fn update_ref(options: &UpdateRefOptions) -> Result<(), String> {
+    let old = match options.old.as_deref() {
+        Some("") | Some(ref_util::NULL_HASH) => Some(ref_util::NULL_HASH.to_string()),
+        Some(o) => Some(rev_parse::rev_parse(o)?),
+        None => None,
+    };
+
+    match &options.new {
+        Some(n) => {
+            let new = rev_parse::rev_parse(n)?;
+            ref_util::update_ref(&options.name, &new, old.as_deref(), &options.message)
+        }
+        None => ref_util::delete_ref(&options.name, old.as_deref()),
+    }
+}
+
+/// Parse arguments for `update_ref`
+pub fn parse_args(args: &[String]) -> Result<UpdateRefOptions, String> {
+    let usage =
+        || "usage: update-ref [-m <reason>] [-d] <refname> [<new-val>] [<old-val>]".to_string();
+    let mut message = String::new();
+    let mut delete = false;
+    let mut rest = Vec::new();
+
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "-d" => delete = true,
+            "-m" => message = iter.next().ok_or_else(usage)?.to_owned(),
+            _ => rest.push(arg.to_owned()),
+        }
+    }
+
+    let (name, new, old) = match (delete, &rest[..]) {
+        (true, [name]) => (name, None, None),
+        (true, [name, old]) => (name, None, Some(old)),
+        (false, [name, new]) => (name, Some(new), None),
+        (false, [name, new, old]) => (name, Some(new), Some(old)),
+        _ => return Err(usage()),
+    };
+    Ok(UpdateRefOptions {
+        name: name.to_owned(),
+        new: new.cloned(),
+        old: old.cloned(),
+        message,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn to_args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|a| a.to_string()).collect()
+    }
+
+    #[test]
+    fn test_parse_args_delete() {
+        let args = to_args(&["-d", "refs/heads/topic"]);
+        assert_eq!(
+            Ok(UpdateRefOptions {
+                name: "refs/heads/topic".to_string(),
+                ..Default::default()
+            }),
+            parse_args(&args)
+        );
+    }
+
+    #[test]
+    fn test_parse_args_with_old_value() {
+        let args = to_args(&["-m", "reset", "refs/heads/topic", "new", "old"]);
+        assert_eq!(
+            Ok(UpdateRefOptions {
+                name: "refs/heads/topic".to_string(),
+                new: Some("new".to_string()),
+                old: Some("old".to_string()),
+                message: "reset".to_string(),
+            }),
+            parse_args(&args)
+        );
+    }
+
+    #[test]
+    fn test_parse_args_missing_value() {
+        let args = to_args(&["refs/heads/topic"]);
+        assert!(parse_args(&args).is_err());
+    }
+}
@@ -0,0 +1,204 @@
+use super::object_util;
+use super::pack_util;
+use super::pack_util::{DeltaBase, PackIndex};
+use flate2::Crc;
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+
+// https://git-scm.com/docs/git-verify-pack
+
+/// Options for `verify-pack`
+#[derive(Debug, Default, PartialEq)]
+pub struct VerifyPackOptions {
+    /// Lists every object, then how long the delta chains are
+    pub verbose: bool,
+    /// The `.idx` files to check, or the `.pack` files next to them
+    pub paths: Vec<String>,
+}
+
+/// Where an object is in a pack and how it is stored
+struct PackedObject {
+    hash: String,
+    offset: u64,
+    crc: u32,
+    /// The size of the entry's data, the delta itself for a delta
+    size: usize,
+    /// The offset of the delta base, if the object is a delta
+    base_offset: Option<u64>,
+}
+
+/// Checks that packs and their indexes match and that every object in them can be read
+///
+/// With `verbose`, lists each object as
+/// `<hash> <type> <size> <size-in-pack> <offset> [<depth> <base-hash>]`
+///
+/// Returns the output to show
+pub fn verify_pack(options: &VerifyPackOptions) -> Result<String, String> {
+    let mut output = String::new();
+    for path in options.paths.iter() {
+        let idx_path = format!(
+            "{}.idx",
+            path.trim_end_matches(".idx").trim_end_matches(".pack")
+        );
+        let pack_path = format!("{}.pack", idx_path.trim_end_matches(".idx"));
+
+        match verify_one(&idx_path, &pack_path, options.verbose) {
+            Ok(listing) => {
+                output += &listing;
+                if options.verbose {
+                    output += &format!("{}: ok\n", pack_path);
+                }
+            }
+            Err(e) => return Err(format!("{}{}\n{}: bad", output, e, pack_path)),
+        }
+    }
+    Ok(output)
+}
+
+/// Parse arguments for `verify-pack`
+pub fn parse_args(args: &[String]) -> Result<VerifyPackOptions, String> {
+    let mut options = VerifyPackOptions::default();
+    for arg in args.iter() {
+        match arg.as_str() {
+            "-v" | "--verbose" => options.verbose = true,
+            _ if arg.starts_with('-') => {
+                return Err(format!("{} is not recognized as a valid option", arg))
+            }
+            _ => options.paths.push(arg.to_owned()),
+        }
+    }
+
+    if options.paths.is_empty() {
+        return Err("usage: verify-pack [-v | --verbose] <pack>...".to_string());
+    }
+    Ok(options)
+}
+
+/// Checks one pack against its index
+fn verify_one(idx_path: &str, pack_path: &str, verbose: bool) -> Result<String, String> {
+    pack_util::verify_checksum(idx_path)?;
+    let pack_checksum = pack_util::verify_checksum(pack_path)?;
+    let idx = PackIndex::read(idx_path)?;
+    if idx.pack_checksum != pack_checksum {
+        return Err(format!(
+            "error: packfile {} does not match index",
+            pack_path
+        ));
+    }
+
+    let mut objects: Vec<PackedObject> = Vec::with_capacity(idx.len());
+    for n in 0..idx.len() {
+        let entry = pack_util::read_entry(pack_path, idx.offset(n))?;
+        let base_offset = match entry.base {
+            Some(DeltaBase::Offset(offset)) => Some(offset),
+            Some(DeltaBase::Hash(hash)) => object_util::from_hex_string(&hash)
+                .and_then(|h| idx.find(&h))
+                .map(|b| idx.offset(b)),
+            None => None,
+        };
+        objects.push(PackedObject {
+            hash: idx.hash(n),
+            offset: idx.offset(n),
+            crc: idx.crc(n),
+            size: entry.data.len(),
+            base_offset,
+        });
+    }
+    objects.sort_by_key(|o| o.offset);
+
+    let pack_len = fs::metadata(pack_path).map_err(|e| e.to_string())?.len();
+    let hashes: HashMap<u64, &str> = objects
+        .iter()
+        .map(|o| (o.offset, o.hash.as_str()))
+        .collect();
+    let bases: HashMap<u64, Option<u64>> =
+        objects.iter().map(|o| (o.offset, o.base_offset)).collect();
+    let mut file = File::open(pack_path).map_err(|e| e.to_string())?;
+
+    let mut output = String::new();
+    let mut chain_lengths: BTreeMap<usize, usize> = BTreeMap::new();
+    for (i, object) in objects.iter().enumerate() {
+        // Entries are back to back, the last one ends at the pack checksum
+        let end = objects
+            .get(i + 1)
+            .map(|o| o.offset)
+            .unwrap_or(pack_len - 20);
+        let mut raw = vec![0u8; (end - object.offset) as usize];
+        file.seek(SeekFrom::Start(object.offset))
+            .and_then(|_| file.read_exact(&mut raw))
+            .map_err(|e| e.to_string())?;
+        let mut crc = Crc::new();
+        crc.update(&raw);
+        if crc.sum() != object.crc {
+            return Err(format!(
+                "error: index CRC mismatch for object {}",
+                object.hash
+            ));
+        }
+
+        let (object_type, content) = pack_util::read_pack_object(&idx, object.offset)?;
+        if object_util::hash_object(object_type, &content) != object.hash {
+            return Err(format!(
+                "error: packed {} from {} is corrupt",
+                object.hash, pack_path
+            ));
+        }
+
+        let mut depth = 0;
+        let mut next = object.base_offset;
+        while let Some(offset) = next {
+            depth += 1;
+            next = bases.get(&offset).copied().flatten();
+        }
+        *chain_lengths.entry(depth).or_default() += 1;
+
+        if verbose {
+            output += &format!(
+                "{} {:<6} {} {} {}",
+                object.hash,
+                object_type.to_string(),
+                object.size,
+                raw.len(),
+                object.offset
+            );
+            if let Some(base) = object.base_offset {
+                output += &format!(" {} {}", depth, hashes.get(&base).unwrap_or(&""));
+            }
+            output += "\n";
+        }
+    }
+
+    if verbose {
+        for (depth, count) in chain_lengths.iter() {
+            let objects = if *count == 1 { "object" } else { "objects" };
+            match depth {
+                0 => output += &format!("non delta: {} {}\n", count, objects),
+                _ => output += &format!("chain length = {}: {} {}\n", depth, count, objects),
+            }
+        }
+    }
+    Ok(output)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn to_args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|a| a.to_string()).collect()
+    }
+
+    #[test]
+    fn test_parse_args() {
+        assert_eq!(
+            Ok(VerifyPackOptions {
+                verbose: true,
+                paths: vec!["a.idx".to_string(), "b.pack".to_string()],
+            }),
+            parse_args(&to_args(&["-v", "a.idx", "b.pack"]))
+        );
+        assert!(parse_args(&to_args(&["-v"])).is_err());
+    }
+}
@@ -0,0 +1,128 @@
+use flate2::write::ZlibEncoder;
+use sha1::Sha1;
+use std::env;
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::{self, Command};
+
+/// Creates an empty repository in a new temporary directory
+fn init_repo(name: &str) -> PathBuf {
+    let dir = env::temp_dir().join(format!("gitrs-test-{}-{}", name, process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(dir.join("initFiles")).unwrap();
+    for file in ["config", "exclude"].iter() {
+        fs::copy(
+            Path::new(env!("CARGO_MANIFEST_DIR"))
+                .join("initFiles")
+                .join(file),
+            dir.join("initFiles").join(file),
+        )
+        .unwrap();
+    }
+    gitrs(&dir, &["init"]);
+    dir
+}
+
+/// Runs gitrs in a directory and returns what it printed
+fn gitrs(dir: &Path, args: &[&str]) -> String {
+    let output = Command::new(env!("CARGO_BIN_EXE_gitrs"))
+        .args(args)
+        .current_dir(dir)
+        .env("GIT_AUTHOR_NAME", "A U Thor")
+        .env("GIT_AUTHOR_EMAIL", "author@example.com")
+        .env("GIT_COMMITTER_NAME", "A U Thor")
+        .env("GIT_COMMITTER_EMAIL", "author@example.com")
+        .output()
+        .unwrap();
+    String::from_utf8_lossy(&output.stdout).into_owned()
+}
+
+/// Writes a loose object without any checks, returning its raw hash
+fn write_object(dir: &Path, object_type: &str, content: &[u8]) -> [u8; 20] {
+    let mut store = format!("{} {}\0", object_type, content.len()).into_bytes();
+    store.extend_from_slice(content);
+    let hash = Sha1::from(&store).digest().bytes();
+    let hex = Sha1::from(&store).hexdigest();
+
+    let object_dir = dir.join("gitrs/objects").join(&hex[..2]);
+    fs::create_dir_all(&object_dir).unwrap();
+    let mut encoder = ZlibEncoder::new(Vec::new(), flate2::Compression::default());
+    encoder.write_all(&store).unwrap();
+    fs::write(object_dir.join(&hex[2..]), encoder.finish().unwrap()).unwrap();
+    hash
+}
+
+/// Writes a tree holding one entry
+fn write_tree(dir: &Path, mode: &str, name: &str, hash: &[u8; 20]) -> [u8; 20] {
+    let mut content = format!("{} {}\0", mode, name).into_bytes();
+    content.extend_from_slice(hash);
+    write_object(dir, "tree", &content)
+}
+
+fn to_hex(hash: &[u8; 20]) -> String {
+    hash.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+#[test]
+fn test_checkout_rejects_unsafe_paths() {
+    let dir = init_repo("unsafe-paths");
+    let blob = write_object(&dir, "blob", b"overwritten\n");
+    let escaped = format!("gitrs-test-escaped-{}", process::id());
+
+    // Each tree puts a file in a directory that must not be written to
+    let cases = [
+        ("..", escaped.as_str()),
+        ("GITRS", "config"),
+        (".git", "config"),
+    ];
+    for (name, file) in cases.iter() {
+        let subtree = write_tree(&dir, "100644", file, &blob);
+        let tree = write_tree(&dir, "40000", name, &subtree);
+        let commit = gitrs(&dir, &["commit-tree", &to_hex(&tree), "-m", "unsafe"]);
+        let output = gitrs(&dir, &["checkout", "--detach", commit.trim()]);
+        assert!(
+            output.starts_with("error: invalid path"),
+            "{}: {}",
+            name,
+            output
+        );
+    }
+
+    assert!(!dir.join("..").join(&escaped).exists());
+    assert!(!dir.join(".git").exists());
+    assert_ne!(
+        "overwritten\n",
+        fs::read_to_string(dir.join("gitrs/config")).unwrap()
+    );
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_checkout_gitlink() {
+    let dir = init_repo("gitlink");
+    let blob = write_object(&dir, "blob", b"file\n");
+    let mut content = b"100644 file\0".to_vec();
+    content.extend_from_slice(&blob);
+    let without = write_object(&dir, "tree", &content);
+    // The submodule's commit is only in its own repository
+    content.extend_from_slice(b"160000 sub\0");
+    content.extend_from_slice(&[0x11; 20]);
+    let with = write_object(&dir, "tree", &content);
+
+    let commit = gitrs(&dir, &["commit-tree", &to_hex(&with), "-m", "add sub"]);
+    gitrs(&dir, &["checkout", "--detach", commit.trim()]);
+    assert!(dir.join("sub").is_dir());
+    assert_eq!(0, fs::read_dir(dir.join("sub")).unwrap().count());
+    assert_eq!(to_hex(&with), gitrs(&dir, &["write-tree"]).trim());
+    assert!(!gitrs(&dir, &["status"]).contains("sub"));
+
+    let commit = gitrs(
+        &dir,
+        &["commit-tree", &to_hex(&without), "-m", "remove sub"],
+    );
+    gitrs(&dir, &["checkout", "--detach", commit.trim()]);
+    assert!(!dir.join("sub").exists());
+    assert_eq!("file\n", fs::read_to_string(dir.join("file")).unwrap());
+    fs::remove_dir_all(&dir).unwrap();
+}